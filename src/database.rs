@@ -1,9 +1,10 @@
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{self, Read, Write};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use serde::de::DeserializeOwned;
 use serde::Serialize;
-use serde_json;
 
 use modules::account::Account;
 use modules::blockchain::Block;
@@ -14,10 +15,17 @@ use crate::modules::transactions::Transaction;
 
 const BASEDBPATH: &str = "data";
 const NODEFILE: &str = "nodes.json";
+const BANFILE: &str = "bans.json";
 const TXFILE: &str = "txn.json";
 const UNTXFILE: &str = "untxn.json";
 const ACCOUNTDB: &str = "accounts.json";
 const BLOCKCHAINDB: &str = "blockchain.json";
+const BLOCKINDEXDB: &str = "blockindex.json";
+const UTXOFILE: &str = "utxo.json";
+const UNDOFILE: &str = "undo.json";
+
+// number of database writes started by this process, giving every write its own temporary file
+static WRITES: AtomicU64 = AtomicU64::new(0);
 
 pub trait BaseDB {
     // get current path to local database
@@ -25,46 +33,28 @@ pub trait BaseDB {
 
     // read the database || return an object that is deserializable
     fn read<T: DeserializeOwned>(&self) -> Vec<T> {
+        // return the deserialized data or a new vector as form of error handling
+        self.read_value().unwrap_or_default()
+    }
+
+    // read the whole database as a single value, or `None` if it is missing or malformed
+    fn read_value<T: DeserializeOwned>(&self) -> Option<T> {
         let file_path: String = self.get_path();
         // create an empty string to save data read from file
         let mut raw: String = String::new();
 
         // check if the file exists or return the file for reading
-        let mut file = match File::open(file_path) {
-            Ok(file) => file,
-            // handle file open error, by returning an empty vector
-            Err(_) => {
-                return Vec::new();
-            }
-        };
+        let mut file = File::open(file_path).ok()?;
 
         // handle errors when reading the file
-        if file.read_to_string(&mut raw).is_err() {
-            // return an empty vector
-            return Vec::new();
-        }
-
-        // deserialize from string to Vec<Node>
-        let data: Result<Vec<T>, serde_json::Error> = serde_json::from_str(&raw);
+        file.read_to_string(&mut raw).ok()?;
 
-        // check for deserialization errors
-        match data {
-            Ok(data) => {
-                // return the deserialized data
-                data
-            }
-            Err(_) => {
-                // return a new vector as form of error handling
-                Vec::new()
-            }
-        }
+        // deserialize from string to the requested type
+        serde_json::from_str(&raw).ok()
     }
 
     // write an item to the database | accepting parameters that can be serialized or deserialized
     fn write<T: Serialize + DeserializeOwned>(&self, item: T) -> io::Result<()> {
-        // get current path to local database
-        let file_path: String = self.get_path();
-
         // read the entire database to vector buffer (there should be a better to do this)
         let mut data: Vec<T> = self.read();
         // println!("{data:?}");
@@ -72,14 +62,35 @@ pub trait BaseDB {
         // push item to the buffer
         data.push(item);
 
-        let json_data = serde_json::to_string(&data)?; // serialize buffer vector to string
-        let mut file = File::create(file_path)?; // overwrite existing database
+        self.write_all(data)
+    }
+
+    // overwrite the database with the given items
+    fn write_all<T: Serialize>(&self, items: Vec<T>) -> io::Result<()> {
+        self.write_value(&items)
+    }
+
+    // overwrite the database with a single value
+    fn write_value<T: Serialize + ?Sized>(&self, value: &T) -> io::Result<()> {
+        // get current path to local database
+        let file_path: String = self.get_path();
+
+        // write to a temporary file first, so a failed write never leaves a half-written database.
+        // every write gets its own file, so concurrent writers can't truncate each other's data
+        let write: u64 = WRITES.fetch_add(1, Ordering::Relaxed);
+        let tmp_path: String = format!("{file_path}.{}.{write}.tmp", process::id());
+
+        let json_data = serde_json::to_string(value)?; // serialize the value to string
+        let mut file = File::create(&tmp_path)?; // create the temporary database
         file.write_all(json_data.as_bytes())?; // write serialized string to the database
+        file.sync_all()?;
 
-        Ok(())
+        // atomically replace the existing database
+        fs::rename(tmp_path, file_path)
     }
 
     // erase the database
+    #[allow(dead_code)]
     fn clear(&self) -> io::Result<()> {
         let file_path = self.get_path();
         // truncate the database file
@@ -94,6 +105,7 @@ pub trait BaseDB {
     }
 
     // write an item to the local database
+    #[allow(dead_code)]
     fn insert<T: Serialize + DeserializeOwned>(&self, item: T) -> io::Result<()> {
         self.write(item)
     }
@@ -103,6 +115,14 @@ pub trait BaseDB {
     where
         T: Serialize + DeserializeOwned + HasHashField,
     {
+        // refuse items whose hash doesn't cover their contents
+        if !item.has_valid_hash() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Hash {} does not match its contents", item.hash()),
+            ));
+        }
+
         // flag for checking if a hash already exists
         let mut exists = false;
 
@@ -134,6 +154,11 @@ pub struct NodeDB {
     file_path: String, // database location
 }
 
+// Banned nodes
+pub struct BanDB {
+    file_path: String, // database location
+}
+
 // Local user accounts
 pub struct AccountDB {
     file_path: String, // database location
@@ -144,6 +169,11 @@ pub struct BlockchainDB {
     file_path: String, // database location
 }
 
+// Every known block, including blocks on competing branches
+pub struct BlockIndexDB {
+    file_path: String, // database location
+}
+
 // Transactions in the database
 pub struct TransactionDB {
     file_path: String, // database location
@@ -154,6 +184,16 @@ pub struct UnTransactionDB {
     file_path: String, // database location
 }
 
+// Unspent transaction outputs
+pub struct UtxoDB {
+    file_path: String, // database location
+}
+
+// Outputs spent by each block, for disconnecting blocks
+pub struct UndoDB {
+    file_path: String, // database location
+}
+
 // Native methods for the Nodes database
 impl NodeDB {
     // create an instance of the Nodes database
@@ -166,6 +206,17 @@ impl NodeDB {
     }
 }
 
+// Native methods for the Bans database
+impl BanDB {
+    // create an instance of the Bans database
+    pub fn new() -> BanDB {
+        // perform initialization with the database location, next to the Nodes database
+        BanDB {
+            file_path: format!("{BASEDBPATH}/{BANFILE}"),
+        }
+    }
+}
+
 // Native methods for the Blockchain database
 impl BlockchainDB {
     // create an instance of the Blockchain database
@@ -177,27 +228,54 @@ impl BlockchainDB {
         }
     }
 
-    fn find(&self, hash: String) -> Block {
-        // initialize a default `Block` with empty values
-        let mut default: Block = Block::default();
-
+    // find a block by its hash
+    #[allow(dead_code)]
+    pub fn find(&self, hash: &str) -> Option<Block> {
         // iterate over all objects for type `Block` from the local database
-        for item in self.find_all::<Block>() {
-            // check if hash of the current item matches the provided hash
-            if item.hash == hash {
-                // if a match is found, update the current item to the default item.
-                default = item;
-            }
+        // and return the first one whose hash matches the provided hash
+        self.find_all::<Block>()
+            .into_iter()
+            .find(|item| item.hash == hash)
+    }
+
+    // find the block that includes the transaction with the given hash
+    pub fn find_by_transaction(&self, txid: &str) -> Option<Block> {
+        self.find_all::<Block>()
+            .into_iter()
+            .find(|block| block.tx.iter().any(|hash| hash == txid))
+    }
+
+    // get the most recent block on the blockchain
+    pub fn tip(&self) -> Option<Block> {
+        self.find_all::<Block>().pop()
+    }
+}
+
+// Native methods for the Block index database
+impl BlockIndexDB {
+    // create an instance of the Block index database
+    pub fn new() -> BlockIndexDB {
+        // perform initialization with the database location
+        BlockIndexDB {
+            file_path: format!("{BASEDBPATH}/{BLOCKINDEXDB}"),
         }
-        // return the final value of the `default` variable
-        default
     }
 
-    // insert a record to the blockchain
-    fn insert(&self, item: Block) -> io::Result<()> {
-        // insert item by hash or do nothing if hash already exists
+    // insert a block by its hash
+    pub fn insert(&self, item: Block) -> io::Result<()> {
         self.hash_insert(item)
     }
+
+    // remove the blocks with the given hashes from the database
+    pub fn remove(&self, hashes: &[String]) -> io::Result<()> {
+        let remaining: Vec<Block> = self
+            .find_all::<Block>()
+            .into_iter()
+            .filter(|item| !hashes.contains(&item.hash))
+            .collect();
+
+        self.write_all(remaining)
+    }
 }
 
 // Native methods for the accounts database
@@ -211,13 +289,18 @@ impl AccountDB {
         }
     }
 
-    // get the last account from the database
-    fn find_one(&self) -> Option<Account> {
+    // get the last account from the database, which is the active account
+    pub fn find_one(&self) -> Option<Account> {
         // read for all accounts from the local database
         let accounts: Vec<Account> = self.read();
 
         // get a copied value of the last account
-        accounts.get(0).cloned()
+        accounts.last().cloned()
+    }
+
+    // store a new account, making it the active account
+    pub fn insert(&self, account: Account) -> io::Result<()> {
+        self.write(account)
     }
 }
 
@@ -231,21 +314,13 @@ impl TransactionDB {
         }
     }
 
-    fn find(&self, hash: String) -> Transaction {
-        // initialize a default `Transaction` with empty values
-        let mut default: Transaction = Transaction::default();
-
+    // find a transaction by its hash
+    pub fn find(&self, hash: &str) -> Option<Transaction> {
         // iterate over all objects for type `Transaction` from the local database
-        for item in self.find_all::<Transaction>() {
-            // check if hash of the current item matches the provided hash
-            if item.hash == hash {
-                // if a match is found, update the current item to the default item.
-                default = item;
-            }
-        }
-
-        // return the final value of the `default` variable
-        default
+        // and return the first one whose hash matches the provided hash
+        self.find_all::<Transaction>()
+            .into_iter()
+            .find(|item| item.hash == hash)
     }
 
     // Insert a single transaction (implementing it as an iterator) or multiple transactions
@@ -275,6 +350,7 @@ impl UnTransactionDB {
         }
     }
 
+    #[allow(dead_code)]
     fn all_hashes(&self) -> Vec<String> {
         let mut hashes: Vec<String> = Vec::new();
 
@@ -286,6 +362,28 @@ impl UnTransactionDB {
     }
 }
 
+// Native methods for the UTXO database
+impl UtxoDB {
+    // create an instance of the UTXO database
+    pub fn new() -> UtxoDB {
+        // perform initialization with the database location
+        UtxoDB {
+            file_path: format!("{BASEDBPATH}/{UTXOFILE}"),
+        }
+    }
+}
+
+// Native methods for the Undo database
+impl UndoDB {
+    // create an instance of the Undo database
+    pub fn new() -> UndoDB {
+        // perform initialization with the database location
+        UndoDB {
+            file_path: format!("{BASEDBPATH}/{UNDOFILE}"),
+        }
+    }
+}
+
 // Inherited methods from BaseDB trait
 impl BaseDB for NodeDB {
     // get current path to local database
@@ -294,6 +392,13 @@ impl BaseDB for NodeDB {
     }
 }
 
+impl BaseDB for BanDB {
+    // get current path to local database
+    fn get_path(&self) -> String {
+        self.file_path.to_string()
+    }
+}
+
 impl BaseDB for AccountDB {
     // get current path to local database
     fn get_path(&self) -> String {
@@ -320,3 +425,21 @@ impl BaseDB for UnTransactionDB {
         self.file_path.to_string()
     }
 }
+
+impl BaseDB for UtxoDB {
+    fn get_path(&self) -> String {
+        self.file_path.to_string()
+    }
+}
+
+impl BaseDB for UndoDB {
+    fn get_path(&self) -> String {
+        self.file_path.to_string()
+    }
+}
+
+impl BaseDB for BlockIndexDB {
+    fn get_path(&self) -> String {
+        self.file_path.to_string()
+    }
+}
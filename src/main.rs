@@ -8,11 +8,25 @@ use routes::{AccountRoute, BlockchainRoute, MinerRoute, NodeRoute, TransactionRo
 
 mod modules {
     pub mod account;
+    pub mod amount;
+    pub mod ban;
     pub mod blockchain;
+    pub mod chain;
+    pub mod difficulty;
     pub mod generics;
+    pub mod gossip;
+    pub mod hashing;
+    pub mod mempool;
+    pub mod merkle;
+    pub mod migration;
     pub mod miner;
     pub mod node;
+    pub mod script;
+    pub mod subsidy;
+    pub mod sync;
     pub mod transactions;
+    pub mod utxo;
+    pub mod validation;
 }
 
 mod database;
@@ -108,6 +122,9 @@ async fn main() {
                     // list all blocks on the blockchain
                     "list" => BlockchainRoute::list(),
 
+                    // prove that a transaction is included in a block
+                    "proof" => BlockchainRoute::proof(argv),
+
                     // handle for invalid method
                     _ => eprintln!("Blockchain: \"{method}\" is not a {module} module"),
                 }
@@ -127,7 +144,7 @@ async fn main() {
             if let Some(method) = argv.get(2) {
                 match &method[..] {
                     // start a miner on the network
-                    "start" => MinerRoute::start(argv),
+                    "start" => MinerRoute::start(argv).await,
 
                     // handle for invalid method
                     _ => eprintln!("Miner: \"{method}\" is not a {module} module"),
@@ -147,14 +164,23 @@ async fn main() {
             if let Some(method) = argv.get(2) {
                 match &method[..] {
                     // register a node locally and on the network
-                    "add" => NodeRoute::add(argv),
+                    "add" => NodeRoute::add(argv).await,
 
                     // list all the nodes on the network
-                    "list" => NodeRoute::list(),
+                    "list" => NodeRoute::list().await,
 
                     // start a node locally
                     "start" => NodeRoute::start(argv).await,
 
+                    // list the banned nodes
+                    "bans" => NodeRoute::bans(),
+
+                    // ban a node for a number of seconds
+                    "ban" => NodeRoute::ban(argv),
+
+                    // lift the ban of a node
+                    "unban" => NodeRoute::unban(argv),
+
                     // handle for invalid method
                     _ => eprintln!("Node: \"{method}\" is not a {module} module"),
                 }
@@ -1,7 +1,68 @@
+use secp256k1::rand::rngs::OsRng;
+use secp256k1::{PublicKey, Secp256k1, SecretKey};
 use serde::{Deserialize, Serialize};
 
+use super::hashing::hash160;
+
+/// Version byte of Pay-to-PubKey-Hash addresses on the main network
+pub const P2PKH_VERSION: u8 = 0x00;
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Account {
+    pub privkey: String,
     pub pubkey: String,
     pub address: String,
 }
+
+impl Account {
+    /// Generate an account from a new random secp256k1 keypair
+    pub fn generate() -> Account {
+        let secp = Secp256k1::new();
+        let (secret_key, public_key) = secp.generate_keypair(&mut OsRng);
+
+        Account::from_keys(&secret_key, &public_key)
+    }
+
+    /// Decode the private key of the account
+    pub fn secret_key(&self) -> Result<SecretKey, secp256k1::Error> {
+        let bytes: Vec<u8> =
+            hex::decode(&self.privkey).map_err(|_| secp256k1::Error::InvalidSecretKey)?;
+
+        SecretKey::from_slice(&bytes)
+    }
+
+    /// Construct an account from an existing keypair
+    pub fn from_keys(secret_key: &SecretKey, public_key: &PublicKey) -> Account {
+        Account {
+            privkey: hex::encode(secret_key.secret_bytes()),
+            pubkey: hex::encode(public_key.serialize()),
+            address: pubkey_to_address(public_key),
+        }
+    }
+}
+
+/// Derive the Base58Check encoded P2PKH address of a public key
+pub fn pubkey_to_address(public_key: &PublicKey) -> String {
+    // the address commits to the hash of the compressed public key
+    pubkey_hash_to_address(&hash160(&public_key.serialize()))
+}
+
+/// Encode the HASH160 of a public key as a Base58Check P2PKH address
+pub fn pubkey_hash_to_address(pubkey_hash: &[u8; 20]) -> String {
+    let mut payload: Vec<u8> = vec![P2PKH_VERSION];
+    payload.extend_from_slice(pubkey_hash);
+
+    bs58::encode(payload).with_check().into_string()
+}
+
+/// Decode a Base58Check P2PKH address into the HASH160 of its public key.
+/// Returns `None` if the address is malformed, has a bad checksum or another version.
+pub fn address_to_pubkey_hash(address: &str) -> Option<[u8; 20]> {
+    let payload: Vec<u8> = bs58::decode(address)
+        .with_check(Some(P2PKH_VERSION))
+        .into_vec()
+        .ok()?;
+
+    // the payload starts with the version byte
+    payload.get(1..)?.try_into().ok()
+}
@@ -0,0 +1,284 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+/// Satoshis in one bitcoin
+pub const COIN: u64 = 100_000_000;
+
+/// Most coins that can ever exist. No amount, and no sum of amounts, may exceed it.
+pub const MAX_MONEY: Amount = Amount(21_000_000 * COIN);
+
+// decimal places of an amount in bitcoins
+const BTC_DECIMALS: usize = 8;
+
+/// An amount of coins, counted in satoshis. Stored and sent as a plain number of satoshis.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct Amount(u64);
+
+/// Reasons an amount can't be parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseAmountError {
+    // the amount is empty
+    Empty,
+    // the amount is not a number, optionally followed by `BTC` or `sat`
+    InvalidNumber(String),
+    // the amount has more decimal places than a satoshi
+    TooPrecise(String),
+    // the amount is more than `MAX_MONEY`
+    TooLarge(String),
+}
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+
+    pub const fn from_sat(sat: u64) -> Amount {
+        Amount(sat)
+    }
+
+    pub const fn to_sat(self) -> u64 {
+        self.0
+    }
+
+    /// Check if the amount is no more than `MAX_MONEY`
+    pub fn is_valid(self) -> bool {
+        self <= MAX_MONEY
+    }
+
+    /// Add two amounts, returning `None` if the sum is more than `MAX_MONEY`
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0
+            .checked_add(other.0)
+            .map(Amount)
+            .filter(|sum| sum.is_valid())
+    }
+
+    /// Subtract `other`, returning `None` if it is larger
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+    /// Add up `amounts`, returning `None` if any of them or their sum is more than `MAX_MONEY`
+    pub fn checked_sum<I: IntoIterator<Item = Amount>>(amounts: I) -> Option<Amount> {
+        amounts
+            .into_iter()
+            .try_fold(Amount::ZERO, Amount::checked_add)
+    }
+
+    /// Canonical serialization of the amount: the satoshis as u64 LE
+    pub fn to_le_bytes(self) -> [u8; 8] {
+        self.0.to_le_bytes()
+    }
+
+    /// Parse a number of bitcoins with up to 8 decimal places, such as `0.5` or `21`
+    pub fn from_btc(btc: &str) -> Result<Amount, ParseAmountError> {
+        let invalid = || ParseAmountError::InvalidNumber(btc.to_string());
+
+        let (whole, fraction) = btc.split_once('.').unwrap_or((btc, ""));
+        if whole.is_empty() && fraction.is_empty() {
+            return Err(invalid());
+        }
+        if !whole
+            .chars()
+            .chain(fraction.chars())
+            .all(|c| c.is_ascii_digit())
+        {
+            return Err(invalid());
+        }
+        if fraction.len() > BTC_DECIMALS {
+            return Err(ParseAmountError::TooPrecise(btc.to_string()));
+        }
+
+        // the fraction counts satoshis once padded to 8 digits
+        let too_large = || ParseAmountError::TooLarge(btc.to_string());
+        let whole: u64 = match whole {
+            "" => 0,
+            whole => whole.parse().map_err(|_| too_large())?,
+        };
+        let fraction: u64 = format!("{fraction:0<BTC_DECIMALS$}")
+            .parse()
+            .map_err(|_| invalid())?;
+
+        whole
+            .checked_mul(COIN)
+            .and_then(|sat| sat.checked_add(fraction))
+            .map(Amount)
+            .filter(|amount| amount.is_valid())
+            .ok_or_else(too_large)
+    }
+}
+
+/// Parse an amount given in bitcoins with a `BTC` suffix, such as `0.5 BTC`, or in satoshis,
+/// with or without a `sat` suffix, such as `5000 sat` or `5000`
+impl FromStr for Amount {
+    type Err = ParseAmountError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s: &str = s.trim();
+        if s.is_empty() {
+            return Err(ParseAmountError::Empty);
+        }
+
+        let lower: String = s.to_ascii_lowercase();
+        if let Some(btc) = lower.strip_suffix("btc") {
+            return Amount::from_btc(btc.trim_end());
+        }
+
+        let sat: &str = lower
+            .strip_suffix("sats")
+            .or_else(|| lower.strip_suffix("sat"))
+            .unwrap_or(&lower)
+            .trim_end();
+        if sat.is_empty() || !sat.chars().all(|c| c.is_ascii_digit()) {
+            return Err(ParseAmountError::InvalidNumber(s.to_string()));
+        }
+
+        sat.parse::<u64>()
+            .ok()
+            .map(Amount)
+            .filter(|amount| amount.is_valid())
+            .ok_or_else(|| ParseAmountError::TooLarge(s.to_string()))
+    }
+}
+
+/// Display the amount in bitcoins with all 8 decimal places, such as `0.00001000 BTC`
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{:0BTC_DECIMALS$} BTC", self.0 / COIN, self.0 % COIN)
+    }
+}
+
+impl fmt::Display for ParseAmountError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseAmountError::Empty => write!(f, "amount is empty"),
+            ParseAmountError::InvalidNumber(amount) => write!(f, "\"{amount}\" is not a number"),
+            ParseAmountError::TooPrecise(amount) => {
+                write!(
+                    f,
+                    "\"{amount}\" has more than {BTC_DECIMALS} decimal places"
+                )
+            }
+            ParseAmountError::TooLarge(amount) => {
+                write!(
+                    f,
+                    "\"{amount}\" is more than the {MAX_MONEY} that can exist"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseAmountError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_bitcoins() {
+        assert_eq!(Amount::from_btc("1"), Ok(Amount(COIN)));
+        assert_eq!(Amount::from_btc("0.5"), Ok(Amount(COIN / 2)));
+        assert_eq!(Amount::from_btc(".5"), Ok(Amount(COIN / 2)));
+        assert_eq!(Amount::from_btc("2."), Ok(Amount(2 * COIN)));
+        assert_eq!(Amount::from_btc("0.00000001"), Ok(Amount(1)));
+        assert_eq!(Amount::from_btc("21000000"), Ok(MAX_MONEY));
+    }
+
+    #[test]
+    fn rejects_malformed_bitcoins() {
+        for btc in ["", ".", "-1", "+1", "1.2.3", "1e8", "0x10", " 1", "1,5"] {
+            assert_eq!(
+                Amount::from_btc(btc),
+                Err(ParseAmountError::InvalidNumber(btc.to_string())),
+                "{btc}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_fractions_of_a_satoshi() {
+        assert_eq!(
+            Amount::from_btc("0.000000001"),
+            Err(ParseAmountError::TooPrecise("0.000000001".to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_bitcoins_past_max_money() {
+        for btc in ["21000000.00000001", "99999999999", "184467440737.09551616"] {
+            assert_eq!(
+                Amount::from_btc(btc),
+                Err(ParseAmountError::TooLarge(btc.to_string())),
+                "{btc}"
+            );
+        }
+
+        // more whole bitcoins than fit in 64 bits
+        assert_eq!(
+            Amount::from_btc("99999999999999999999"),
+            Err(ParseAmountError::TooLarge(
+                "99999999999999999999".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn parses_units() {
+        assert_eq!("0.5BTC".parse(), Ok(Amount(COIN / 2)));
+        assert_eq!("0.5 btc".parse(), Ok(Amount(COIN / 2)));
+        assert_eq!("5000sat".parse(), Ok(Amount(5000)));
+        assert_eq!("5000 sats".parse(), Ok(Amount(5000)));
+        assert_eq!("5000".parse(), Ok(Amount(5000)));
+        assert_eq!(" 0 ".parse(), Ok(Amount::ZERO));
+        assert_eq!("2100000000000000".parse(), Ok(MAX_MONEY));
+    }
+
+    #[test]
+    fn rejects_malformed_units() {
+        assert_eq!("".parse::<Amount>(), Err(ParseAmountError::Empty));
+        assert_eq!("  ".parse::<Amount>(), Err(ParseAmountError::Empty));
+
+        for amount in ["sat", "BTC", "0.5", "0.5sat", "-5", "5 000", "5 bitcoins"] {
+            assert!(
+                matches!(
+                    amount.parse::<Amount>(),
+                    Err(ParseAmountError::InvalidNumber(_))
+                ),
+                "{amount}"
+            );
+        }
+
+        assert_eq!(
+            "2100000000000001".parse::<Amount>(),
+            Err(ParseAmountError::TooLarge("2100000000000001".to_string()))
+        );
+        assert_eq!(
+            "18446744073709551616".parse::<Amount>(),
+            Err(ParseAmountError::TooLarge(
+                "18446744073709551616".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn checked_arithmetic_stays_within_max_money() {
+        assert_eq!(MAX_MONEY.checked_add(Amount(1)), None);
+        assert_eq!(Amount(u64::MAX).checked_add(Amount(1)), None);
+        assert_eq!(Amount(1).checked_sub(Amount(2)), None);
+        assert_eq!(
+            Amount::checked_sum([MAX_MONEY, Amount::ZERO]),
+            Some(MAX_MONEY)
+        );
+        assert_eq!(Amount::checked_sum([MAX_MONEY, Amount(1)]), None);
+    }
+
+    #[test]
+    fn displays_bitcoins() {
+        assert_eq!(Amount(1000).to_string(), "0.00001000 BTC");
+        assert_eq!(MAX_MONEY.to_string(), "21000000.00000000 BTC");
+    }
+}
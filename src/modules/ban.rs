@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{LazyLock, Mutex, OnceLock};
+
+use serde::{Deserialize, Serialize};
+
+use crate::database::{BanDB, BaseDB};
+use crate::modules::miner::current_timestamp;
+use crate::p2p::node_ip;
+
+/// Misbehavior score at which a peer is banned
+pub const BAN_THRESHOLD: u32 = 100;
+
+/// Time in seconds a misbehaving peer is banned for, unless configured otherwise
+pub const DEFAULT_BAN_TIME: u64 = 24 * 60 * 60;
+
+/// Penalty for a call with malformed params
+pub const MALFORMED_PENALTY: u32 = 10;
+
+/// Time in seconds the IP address a node address resolves to is remembered for
+pub const RESOLVE_TIME: u64 = 10 * 60;
+
+// time in seconds misbehaving peers are banned for, configured when the node starts
+static BAN_TIME: OnceLock<u64> = OnceLock::new();
+
+// misbehavior score of every peer IP address since it was last banned
+static SCORES: LazyLock<Mutex<HashMap<IpAddr, u32>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+// IP address every node address resolved to
+static RESOLVED: LazyLock<Mutex<HashMap<String, Resolved>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+// the outcome of resolving a node address
+#[derive(Clone, Copy)]
+struct Resolved {
+    // IP address of the node, if the address resolved
+    ip: Option<IpAddr>,
+    // UNIX time in seconds the address was resolved at
+    at: u64,
+}
+
+/// An IP address whose calls and connections are refused until the ban ends
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ban {
+    // IP address of the banned peer
+    pub ip: IpAddr,
+    // UNIX time in seconds the ban ends at
+    pub until: u64,
+    // why the node was banned
+    pub reason: String,
+}
+
+/// Set the time in seconds misbehaving peers are banned for. Only the first call has an effect.
+pub fn set_ban_time(seconds: u64) {
+    let _ = BAN_TIME.set(seconds);
+}
+
+/// Get the time in seconds misbehaving peers are banned for
+pub fn ban_time() -> u64 {
+    BAN_TIME.get().copied().unwrap_or(DEFAULT_BAN_TIME)
+}
+
+/// Get the bans that haven't ended yet from the local database
+pub fn get_bans() -> Vec<Ban> {
+    let now: u64 = current_timestamp();
+
+    BanDB::new()
+        .find_all::<Ban>()
+        .into_iter()
+        .filter(|ban| ban.until > now)
+        .collect()
+}
+
+/// Check if the peer at `ip` is banned
+pub fn is_banned(ip: IpAddr) -> bool {
+    get_bans().iter().any(|ban| ban.ip == ip)
+}
+
+/// Resolve the IP address of the node at `address`. Results are remembered for
+/// `RESOLVE_TIME` seconds, so checking the bans of known nodes doesn't look up their
+/// hostnames every time.
+fn resolve(address: &str) -> Option<IpAddr> {
+    let now: u64 = current_timestamp();
+
+    if let Some(resolved) = RESOLVED
+        .lock()
+        .expect("Resolved addresses lock poisoned")
+        .get(address)
+    {
+        if now < resolved.at.saturating_add(RESOLVE_TIME) {
+            return resolved.ip;
+        }
+    }
+
+    // resolve without holding the lock, lookups can take a while
+    let ip: Option<IpAddr> = node_ip(address).ok();
+    RESOLVED
+        .lock()
+        .expect("Resolved addresses lock poisoned")
+        .insert(address.to_string(), Resolved { ip, at: now });
+
+    ip
+}
+
+/// Check if the node at `address` is banned. Addresses that don't resolve aren't banned.
+pub fn is_node_banned(address: &str) -> bool {
+    resolve(address).is_some_and(is_banned)
+}
+
+/// Ban the peer at `ip` for `duration` seconds, replacing an earlier ban of the peer.
+/// Bans that have ended are dropped from the local database.
+pub fn ban(ip: IpAddr, duration: u64, reason: &str) -> Ban {
+    let mut bans: Vec<Ban> = get_bans();
+    bans.retain(|ban| ban.ip != ip);
+
+    let ban: Ban = Ban {
+        ip,
+        until: current_timestamp().saturating_add(duration),
+        reason: reason.to_string(),
+    };
+    bans.push(ban.clone());
+
+    BanDB::new()
+        .write_all(bans)
+        .expect("Couldn't write to Ban database");
+
+    // the peer starts over once the ban ends
+    SCORES
+        .lock()
+        .expect("Misbehavior scores lock poisoned")
+        .remove(&ban.ip);
+
+    ban
+}
+
+/// Lift the ban of the peer at `ip`. Returns whether the peer was banned.
+pub fn unban(ip: IpAddr) -> bool {
+    let mut bans: Vec<Ban> = get_bans();
+    let count: usize = bans.len();
+    bans.retain(|ban| ban.ip != ip);
+
+    if bans.len() == count {
+        return false;
+    }
+
+    BanDB::new()
+        .write_all(bans)
+        .expect("Couldn't write to Ban database");
+
+    true
+}
+
+/// Raise the misbehavior score of the peer at `ip` by `penalty`, banning the peer for the
+/// configured time once its score reaches `BAN_THRESHOLD`. Peers on the loopback interface
+/// aren't scored: they all share one address, so banning one would ban every local node.
+pub fn misbehaving(ip: IpAddr, penalty: u32, reason: &str) {
+    if penalty == 0 {
+        return;
+    }
+
+    if ip.is_loopback() {
+        println!("Local peer {ip} misbehaved ({reason})");
+        return;
+    }
+
+    let score: u32 = {
+        let mut scores = SCORES.lock().expect("Misbehavior scores lock poisoned");
+        let score: &mut u32 = scores.entry(ip).or_default();
+        *score = score.saturating_add(penalty);
+        *score
+    };
+
+    println!("Peer {ip} misbehaved ({reason}), score {score}");
+
+    if score >= BAN_THRESHOLD {
+        let ban: Ban = ban(ip, ban_time(), reason);
+        println!("Banned {ip} until {}", ban.until);
+    }
+}
+
+/// Raise the misbehavior score of the node at `address`, which the current node called
+pub fn node_misbehaving(address: &str, penalty: u32, reason: &str) {
+    if let Some(ip) = resolve(address) {
+        misbehaving(ip, penalty, reason);
+    }
+}
@@ -1,24 +1,111 @@
 use serde::{Deserialize, Serialize};
 
+use super::hashing::{decode_hash, sha256d, HASH_SIZE};
+use super::merkle::merkle_root;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Block {
     pub index: u32,
     pub timestamp: u64,
     pub tx: Vec<String>,
     pub previous_block: String,
+    pub merkle_root: String,
+    // difficulty target in compact form
+    pub bits: u32,
+    pub nonce: u32,
+    pub hash: String,
+}
+
+/// The part of a block covered by its hash, without the list of transactions
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockHeader {
+    pub index: u32,
+    pub timestamp: u64,
+    pub previous_block: String,
+    pub merkle_root: String,
+    pub bits: u32,
     pub nonce: u32,
     pub hash: String,
 }
 
 impl Block {
+    #[allow(dead_code)]
     pub fn default() -> Block {
         Block {
             index: 0,
             timestamp: 0,
             tx: vec![],
             previous_block: "".to_string(),
+            merkle_root: "".to_string(),
+            bits: 0,
+            nonce: 0,
+            hash: "".to_string(),
+        }
+    }
+
+    /// Construct an unmined block on top of `previous_block`
+    pub fn new(
+        index: u32,
+        timestamp: u64,
+        tx: Vec<String>,
+        previous_block: String,
+        bits: u32,
+    ) -> Block {
+        Block {
+            index,
+            timestamp,
+            merkle_root: merkle_root(&tx),
+            tx,
+            previous_block,
+            bits,
             nonce: 0,
             hash: "".to_string(),
         }
     }
+
+    /// Get the header of the block
+    pub fn to_header(&self) -> BlockHeader {
+        BlockHeader {
+            index: self.index,
+            timestamp: self.timestamp,
+            previous_block: self.previous_block.clone(),
+            merkle_root: self.merkle_root.clone(),
+            bits: self.bits,
+            nonce: self.nonce,
+            hash: self.hash.clone(),
+        }
+    }
+
+    /// Calculate the double SHA-256 hash of the block header, hex encoded
+    pub fn calculate_hash(&self) -> String {
+        self.to_header().calculate_hash()
+    }
+
+    /// Check that the stored hash and merkle root match the contents of the block
+    pub fn has_valid_hash(&self) -> bool {
+        self.merkle_root == merkle_root(&self.tx) && self.hash == self.calculate_hash()
+    }
+}
+
+impl BlockHeader {
+    /// Serialize the header in its canonical byte layout:
+    /// index (u32 LE), timestamp (u64 LE), previous block, merkle root, bits (u32 LE)
+    /// and nonce (u32 LE)
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut header: Vec<u8> = Vec::with_capacity(4 + 8 + HASH_SIZE + HASH_SIZE + 4 + 4);
+
+        header.extend_from_slice(&self.index.to_le_bytes());
+        header.extend_from_slice(&self.timestamp.to_le_bytes());
+        header.extend_from_slice(&decode_hash(&self.previous_block));
+        header.extend_from_slice(&decode_hash(&self.merkle_root));
+        header.extend_from_slice(&self.bits.to_le_bytes());
+        header.extend_from_slice(&self.nonce.to_le_bytes());
+
+        header
+    }
+
+    /// Calculate the double SHA-256 hash of the header, hex encoded
+    pub fn calculate_hash(&self) -> String {
+        hex::encode(sha256d(&self.serialize()))
+    }
 }
@@ -0,0 +1,485 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+
+use primitive_types::U256;
+
+use crate::database::{BaseDB, BlockIndexDB, BlockchainDB, TransactionDB};
+use crate::modules::blockchain::{Block, BlockHeader};
+use crate::modules::difficulty::bits_to_target;
+use crate::modules::mempool::{mempool, Mempool};
+use crate::modules::miner::GENESIS_PREVIOUS_BLOCK;
+use crate::modules::transactions::Transaction;
+use crate::modules::utxo::{find_undo, save_undo, BlockUndo, UtxoSet};
+use crate::modules::validation::{find_transaction, validate_block, BlockError};
+
+// serializes updates of the active chain, block index, UTXO set and undo data
+static CHAIN: Mutex<()> = Mutex::new(());
+
+/// Lock the chain of the node. Every update of the chain happens under the lock, so code that
+/// reads the chain and builds on it, like the miner, holds it to see a consistent chain. The
+/// lock must not be held while calling `accept_block`, which locks the chain itself.
+pub fn chain_lock() -> MutexGuard<'static, ()> {
+    CHAIN.lock().expect("Chain lock poisoned")
+}
+
+/// Expected number of hashes needed to meet the target encoded in `bits`
+pub fn block_work(bits: u32) -> U256 {
+    let target: U256 = bits_to_target(bits);
+
+    if target.is_zero() {
+        return U256::zero();
+    }
+
+    // 2^256 / (target + 1), computed without overflowing 256 bits
+    (!target / (target + 1)) + 1
+}
+
+/// Every known block, including blocks on competing branches
+#[derive(Default)]
+pub struct BlockTree {
+    blocks: HashMap<String, Block>,
+    // hashes of the blocks built on top of each block
+    children: HashMap<String, Vec<String>>,
+}
+
+impl BlockTree {
+    /// Load all known blocks from the local databases
+    pub fn load() -> BlockTree {
+        let mut tree: BlockTree = BlockTree::default();
+
+        // blocks of the active chain are known even if they predate the block index
+        let known: Vec<Block> = BlockIndexDB::new().find_all();
+        let active: Vec<Block> = BlockchainDB::new().find_all();
+
+        for block in known.into_iter().chain(active) {
+            tree.insert(block);
+        }
+
+        tree
+    }
+
+    /// Get a known block by its hash
+    pub fn get(&self, hash: &str) -> Option<&Block> {
+        self.blocks.get(hash)
+    }
+
+    /// Add a block to the tree
+    pub fn insert(&mut self, block: Block) {
+        if self.blocks.contains_key(&block.hash) {
+            return;
+        }
+
+        self.children
+            .entry(block.previous_block.clone())
+            .or_default()
+            .push(block.hash.clone());
+        self.blocks.insert(block.hash.clone(), block);
+    }
+
+    /// Get the chain of blocks ending at `hash`, ordered from the first block
+    pub fn branch(&self, hash: &str) -> Vec<Block> {
+        let mut branch: Vec<Block> = Vec::new();
+        let mut current: Option<&Block> = self.get(hash);
+
+        // walk back through the parents until the first block
+        while let Some(block) = current {
+            branch.push(block.clone());
+
+            if block.previous_block == GENESIS_PREVIOUS_BLOCK {
+                break;
+            }
+            current = self.get(&block.previous_block);
+        }
+
+        branch.reverse();
+        branch
+    }
+
+    /// Total work of the chain ending at `hash`
+    pub fn chain_work(&self, hash: &str) -> U256 {
+        self.branch(hash).iter().fold(U256::zero(), |work, block| {
+            work.saturating_add(block_work(block.bits))
+        })
+    }
+
+    /// Get the hashes of `hash` and every block built on top of it
+    pub fn descendants(&self, hash: &str) -> Vec<String> {
+        if self.get(hash).is_none() {
+            return Vec::new();
+        }
+
+        let mut found: Vec<String> = vec![hash.to_string()];
+        let mut next: usize = 0;
+
+        // add the children of every block found, until no block has more
+        while next < found.len() {
+            if let Some(children) = self.children.get(&found[next]) {
+                found.extend(children.iter().cloned());
+            }
+            next += 1;
+        }
+
+        found
+    }
+}
+
+/// Hashes of blocks of `chain` for a peer to find the last block both have in common:
+/// the last ten blocks, then blocks exponentially further apart, ending with the first block
+pub fn block_locator(chain: &[Block]) -> Vec<String> {
+    let mut locator: Vec<String> = Vec::new();
+    let mut step: usize = 1;
+    let mut next: Option<usize> = chain.len().checked_sub(1);
+
+    while let Some(position) = next {
+        locator.push(chain[position].hash.clone());
+
+        if locator.len() >= 10 {
+            step *= 2;
+        }
+        next = match position {
+            0 => None,
+            _ => Some(position.saturating_sub(step)),
+        };
+    }
+
+    locator
+}
+
+/// Headers of the blocks of `chain` following the first block of `locator` found on it, or
+/// following nothing if none is found. Returns at most `max` headers.
+pub fn headers_after(chain: &[Block], locator: &[String], max: usize) -> Vec<BlockHeader> {
+    let start: usize = locator
+        .iter()
+        .find_map(|hash| chain.iter().position(|block| &block.hash == hash))
+        .map_or(0, |position| position + 1);
+
+    chain[start..]
+        .iter()
+        .take(max)
+        .map(Block::to_header)
+        .collect()
+}
+
+/// Height of the next block of the active chain
+pub fn next_height() -> u32 {
+    BlockchainDB::new()
+        .find_all::<Block>()
+        .last()
+        .map_or(0, |tip| tip.index + 1)
+}
+
+/// Collect the transactions of a block from the local database
+pub fn block_transactions(block: &Block) -> Vec<Transaction> {
+    let txn_db: TransactionDB = TransactionDB::new();
+
+    block
+        .tx
+        .iter()
+        .filter_map(|hash| txn_db.find(hash))
+        .collect()
+}
+
+/// Validate `block` and add it to the block tree. If its branch then has the most work,
+/// it becomes the active chain.
+pub fn accept_block(block: Block) -> Result<(), BlockError> {
+    let _chain = chain_lock();
+
+    let mut tree: BlockTree = BlockTree::load();
+
+    if tree.get(&block.hash).is_some() {
+        return Err(BlockError::AlreadyKnown);
+    }
+
+    // the parent must be known, unless the block starts a new chain
+    let branch: Vec<Block> = if block.previous_block == GENESIS_PREVIOUS_BLOCK {
+        Vec::new()
+    } else if tree.get(&block.previous_block).is_some() {
+        tree.branch(&block.previous_block)
+    } else {
+        return Err(BlockError::UnknownParent(block.previous_block.clone()));
+    };
+
+    validate_block(&block, &branch)?;
+
+    // keep the transactions of the block, even if it ends up on a side branch
+    let txn_db: TransactionDB = TransactionDB::new();
+    for hash in &block.tx {
+        if let Some(txn) = find_transaction(hash) {
+            txn_db
+                .insert(txn)
+                .expect("Couldn't write to Transaction database");
+        }
+    }
+    mempool().unstage(&block.tx);
+
+    BlockIndexDB::new()
+        .insert(block.clone())
+        .expect("Couldn't write to Block index database");
+    tree.insert(block.clone());
+
+    // switch to the branch of the block if it has more work than the active chain
+    let active: Vec<Block> = BlockchainDB::new().find_all();
+    let active_work: U256 = active
+        .last()
+        .map_or(U256::zero(), |tip| tree.chain_work(&tip.hash));
+
+    if tree.chain_work(&block.hash) > active_work {
+        reorganize(&tree, &active, &block.hash)?;
+    }
+
+    Ok(())
+}
+
+/// Make the branch ending at `tip` the active chain, disconnecting the blocks of `active`
+/// that are not on it and connecting the new ones. The active chain is left untouched unless
+/// every block of the new branch connects.
+fn reorganize(tree: &BlockTree, active: &[Block], tip: &str) -> Result<(), BlockError> {
+    let branch: Vec<Block> = tree.branch(tip);
+
+    // number of blocks both chains share
+    let fork: usize = active
+        .iter()
+        .zip(branch.iter())
+        .take_while(|(old, new)| old.hash == new.hash)
+        .count();
+
+    if fork < active.len() {
+        println!(
+            "Reorganizing: disconnecting {} block(s), connecting {} block(s)",
+            active.len() - fork,
+            branch.len() - fork
+        );
+    }
+
+    let mut reorg: Reorg = Reorg::new(UtxoSet::load());
+
+    // disconnect the blocks of the old branch, from the tip down
+    for block in active[fork..].iter().rev() {
+        let undo: BlockUndo =
+            find_undo(&block.hash).ok_or_else(|| BlockError::MissingUndo(block.hash.clone()))?;
+
+        reorg.disconnect(block, block_transactions(block), &undo);
+    }
+
+    // connect the blocks of the new branch, from the fork up
+    for block in &branch[fork..] {
+        if let Err(error) = reorg.connect(block, block_transactions(block)) {
+            // the block is invalid, forget it and everything built on it
+            BlockIndexDB::new()
+                .remove(&tree.descendants(&block.hash))
+                .expect("Couldn't write to Block index database");
+            return Err(error);
+        }
+    }
+
+    // every block connected, commit the new active chain. The chain is written last: if the
+    // node stops before, the UTXO set no longer matches the tip and is rebuilt on load.
+    save_undo(std::mem::take(&mut reorg.undos));
+    reorg.utxos.save();
+    BlockchainDB::new()
+        .write_all(branch.clone())
+        .expect("Couldn't write to Blockchain database");
+
+    reorg.update_mempool(&mut mempool(), branch.len() as u32);
+
+    Ok(())
+}
+
+/// Changes of a switch between branches, staged in memory until every block connects
+struct Reorg {
+    utxos: UtxoSet,
+    // undo data of the connected blocks
+    undos: Vec<BlockUndo>,
+    connected: Vec<Transaction>,
+    disconnected: Vec<Transaction>,
+}
+
+impl Reorg {
+    fn new(utxos: UtxoSet) -> Reorg {
+        Reorg {
+            utxos,
+            undos: Vec::new(),
+            connected: Vec::new(),
+            disconnected: Vec::new(),
+        }
+    }
+
+    /// Disconnect a block of the old branch. Blocks are disconnected from the tip down.
+    fn disconnect(&mut self, block: &Block, transactions: Vec<Transaction>, undo: &BlockUndo) {
+        self.utxos.disconnect_block(block, &transactions, undo);
+        self.disconnected.extend(transactions);
+    }
+
+    /// Connect a block of the new branch. Blocks are connected from the fork up.
+    fn connect(&mut self, block: &Block, transactions: Vec<Transaction>) -> Result<(), BlockError> {
+        let undo: BlockUndo = self.utxos.connect_block(block, &transactions)?;
+
+        self.undos.push(undo);
+        self.connected.extend(transactions);
+        Ok(())
+    }
+
+    /// Drop the pending transactions the new blocks confirm or conflict with, and return the
+    /// transactions of disconnected blocks that are still valid at `height` to the pool
+    fn update_mempool(self, pool: &mut Mempool, height: u32) {
+        pool.remove_confirmed(&self.connected);
+
+        if !self.disconnected.is_empty() {
+            // coinbase transactions are only valid in their own block
+            let connected: Vec<Transaction> = self.connected;
+            let mut disconnected: Vec<Transaction> = self.disconnected;
+            disconnected.retain(|txn| {
+                !txn.is_coinbase() && !connected.iter().any(|confirmed| confirmed.hash == txn.hash)
+            });
+            pool.restore(disconnected, &self.utxos, height);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::amount::Amount;
+    use crate::modules::difficulty::POW_LIMIT_BITS;
+    use crate::modules::script::{Script, OP_1};
+    use crate::modules::subsidy::{block_subsidy, halving_interval};
+    use crate::modules::transactions::{OutPoint, Vin, Vout};
+
+    // an output anyone can spend with an empty unlocking script
+    fn anyone_can_spend(amount: Amount) -> Vout {
+        Vout::new(Script::new().push_opcode(OP_1), amount)
+    }
+
+    // spend output `vout` of `parent` to a single output, paying `fee`
+    fn spend(parent: &Transaction, vout: u32, fee: Amount) -> Transaction {
+        let amount: Amount = parent.vout[vout as usize].amount;
+        let vin: Vin = Vin::new(parent.hash.clone(), vout, amount);
+
+        Transaction::new(
+            0,
+            vec![vin],
+            vec![anyone_can_spend(amount.checked_sub(fee).unwrap())],
+        )
+    }
+
+    // the coinbase transaction of the block at `height`, claiming the subsidy and `fees`
+    fn coinbase(height: u32, fees: Amount) -> Transaction {
+        let amount: Amount = block_subsidy(height, halving_interval())
+            .checked_add(fees)
+            .unwrap();
+        Transaction::coinbase(height, 0, Script::new().push_opcode(OP_1), amount)
+    }
+
+    // the block at `height` on top of `previous` holding `transactions`
+    fn block(height: u32, previous: &str, transactions: &[Transaction]) -> Block {
+        let mut block: Block = Block::new(
+            height,
+            0,
+            transactions.iter().map(|txn| txn.hash.clone()).collect(),
+            previous.to_string(),
+            POW_LIMIT_BITS,
+        );
+        block.hash = block.calculate_hash();
+        block
+    }
+
+    fn output(txn: &Transaction) -> OutPoint {
+        OutPoint {
+            txid: txn.hash.clone(),
+            vout: 0,
+        }
+    }
+
+    #[test]
+    fn descendants_follow_every_branch() {
+        let first: Block = block(0, GENESIS_PREVIOUS_BLOCK, &[coinbase(0, Amount::ZERO)]);
+        let left: Block = block(1, &first.hash, &[coinbase(1, Amount::ZERO)]);
+        let right: Block = block(1, &first.hash, &[coinbase(1, Amount::from_sat(1))]);
+        let tip: Block = block(2, &right.hash, &[coinbase(2, Amount::ZERO)]);
+
+        let mut tree: BlockTree = BlockTree::default();
+        for block in [&tip, &left, &right, &first] {
+            tree.insert(block.clone());
+        }
+
+        let mut found: Vec<String> = tree.descendants(&first.hash);
+        found.sort();
+        let mut expected: Vec<String> =
+            vec![first.hash, left.hash, right.hash.clone(), tip.hash.clone()];
+        expected.sort();
+        assert_eq!(found, expected);
+
+        assert_eq!(tree.descendants(&right.hash), vec![right.hash, tip.hash]);
+        assert!(tree.descendants(&"11".repeat(32)).is_empty());
+    }
+
+    #[test]
+    fn reorganizing_switches_to_the_branch_with_more_work() {
+        // a confirmed output both branches build on
+        let vin: Vin = Vin::new("11".repeat(32), 0, Amount::from_sat(100_000));
+        let funding: Transaction = Transaction::new(
+            0,
+            vec![vin],
+            vec![anyone_can_spend(Amount::from_sat(100_000))],
+        );
+        let mut base: UtxoSet = UtxoSet::default();
+        base.add_outputs(&funding, 0);
+
+        // the active chain confirms a spend of it, which a pending transaction spends in turn
+        let fee: Amount = Amount::from_sat(1_000);
+        let confirmed: Transaction = spend(&funding, 0, fee);
+        let pending: Transaction = spend(&confirmed, 0, fee);
+        let old: Vec<Transaction> = vec![coinbase(1, fee), confirmed.clone()];
+        let old_tip: Block = block(1, GENESIS_PREVIOUS_BLOCK, &old);
+
+        let mut active: UtxoSet = base.clone();
+        let undo: BlockUndo = active.connect_block(&old_tip, &old).unwrap();
+        let mut pool: Mempool = Mempool::default();
+        pool.add(pending.clone(), &active, 2).unwrap();
+
+        // a competing branch of two empty blocks has more work
+        let first: Vec<Transaction> = vec![coinbase(1, Amount::ZERO)];
+        let second: Vec<Transaction> = vec![coinbase(2, Amount::ZERO)];
+        let new_first: Block = block(1, GENESIS_PREVIOUS_BLOCK, &first);
+        let new_tip: Block = block(2, &new_first.hash, &second);
+
+        let mut tree: BlockTree = BlockTree::default();
+        for block in [&old_tip, &new_first, &new_tip] {
+            tree.insert(block.clone());
+        }
+        assert!(tree.chain_work(&new_tip.hash) > tree.chain_work(&old_tip.hash));
+
+        let branch: Vec<String> = tree
+            .branch(&new_tip.hash)
+            .into_iter()
+            .map(|block| block.hash)
+            .collect();
+        assert_eq!(branch, vec![new_first.hash.clone(), new_tip.hash.clone()]);
+
+        let mut reorg: Reorg = Reorg::new(active);
+        reorg.disconnect(&old_tip, old.clone(), &undo);
+        reorg.connect(&new_first, first.clone()).unwrap();
+        reorg.connect(&new_tip, second.clone()).unwrap();
+
+        // the spend of the old branch is undone and the new coinbase outputs are unspent
+        assert!(reorg.utxos.get(&output(&funding)).is_some());
+        assert!(reorg.utxos.get(&output(&confirmed)).is_none());
+        assert!(reorg.utxos.get(&output(&old[0])).is_none());
+        assert!(reorg.utxos.get(&output(&first[0])).is_some());
+        assert!(reorg.utxos.get(&output(&second[0])).is_some());
+        assert_eq!(reorg.undos.len(), 2);
+
+        // the disconnected spend returns to the pool ahead of the transaction spending it
+        let utxos: UtxoSet = reorg.utxos.clone();
+        reorg.update_mempool(&mut pool, 3);
+        let restored: Vec<String> = pool
+            .transactions()
+            .into_iter()
+            .map(|txn| txn.hash)
+            .collect();
+        assert_eq!(restored, vec![confirmed.hash, pending.hash]);
+
+        // the restored transactions still spend outputs of the new chain
+        assert!(utxos.validate_spend(&pool.transactions()[0], 3).is_ok());
+    }
+}
@@ -0,0 +1,202 @@
+use primitive_types::U256;
+
+use super::blockchain::Block;
+use super::hashing::decode_hash;
+
+/// Easiest allowed target in compact form: hashes need four leading zeroes
+pub const POW_LIMIT_BITS: u32 = 0x1f00ffff;
+
+/// Number of blocks between difficulty adjustments
+pub const RETARGET_INTERVAL: u32 = 10;
+
+/// Desired time between blocks in seconds
+pub const TARGET_SPACING: u64 = 60;
+
+/// Desired time between difficulty adjustments in seconds
+pub const TARGET_TIMESPAN: u64 = RETARGET_INTERVAL as u64 * TARGET_SPACING;
+
+/// Expand a compact `bits` value to the full 256-bit target.
+/// Negative or overflowing encodings expand to a target of zero, which no hash can meet.
+pub fn bits_to_target(bits: u32) -> U256 {
+    let exponent: u32 = bits >> 24;
+    let mantissa: u32 = bits & 0x007fffff;
+
+    // the sign bit is set, targets can't be negative
+    if bits & 0x00800000 != 0 && mantissa != 0 {
+        return U256::zero();
+    }
+
+    if exponent <= 3 {
+        return U256::from(mantissa >> (8 * (3 - exponent)));
+    }
+
+    // the mantissa would be shifted out of 256 bits
+    let shift: u32 = 8 * (exponent - 3);
+    if mantissa != 0 && U256::from(mantissa).bits() as u32 + shift > 256 {
+        return U256::zero();
+    }
+
+    U256::from(mantissa) << shift
+}
+
+/// Compress a 256-bit target into its compact `bits` form
+pub fn target_to_bits(target: U256) -> u32 {
+    let mut size: u32 = target.bits().div_ceil(8) as u32;
+
+    let mut mantissa: u32 = if size <= 3 {
+        (target.low_u64() << (8 * (3 - size))) as u32
+    } else {
+        (target >> (8 * (size - 3))).low_u32()
+    };
+
+    // keep the sign bit clear by moving one byte into the exponent
+    if mantissa & 0x00800000 != 0 {
+        mantissa >>= 8;
+        size += 1;
+    }
+
+    (size << 24) | mantissa
+}
+
+/// Interpret a hex encoded hash as a 256-bit number
+pub fn hash_to_u256(hash: &str) -> U256 {
+    U256::from_big_endian(&decode_hash(hash))
+}
+
+/// Check if a hash satisfies the target encoded in `bits`
+pub fn meets_target(hash: &str, bits: u32) -> bool {
+    let target: U256 = bits_to_target(bits);
+
+    !target.is_zero() && target <= bits_to_target(POW_LIMIT_BITS) && hash_to_u256(hash) <= target
+}
+
+/// Compute the `bits` of the block following the last block of `chain`.
+/// `chain` holds the ancestors of the new block, ordered from the first block.
+pub fn next_bits(chain: &[Block]) -> u32 {
+    let Some(parent) = chain.last() else {
+        // the first block uses the easiest target
+        return POW_LIMIT_BITS;
+    };
+
+    let height: u32 = parent.index + 1;
+
+    // keep the target of the parent between adjustments
+    if !height.is_multiple_of(RETARGET_INTERVAL) {
+        return parent.bits;
+    }
+
+    // the first block of the period that just ended
+    let Some(first) = chain
+        .len()
+        .checked_sub(RETARGET_INTERVAL as usize)
+        .map(|position| &chain[position])
+    else {
+        return parent.bits;
+    };
+
+    // limit the adjustment to a factor of four
+    let actual_timespan: u64 = parent
+        .timestamp
+        .saturating_sub(first.timestamp)
+        .clamp(TARGET_TIMESPAN / 4, TARGET_TIMESPAN * 4);
+
+    // scale the target by how much faster or slower the period was than desired
+    let pow_limit: U256 = bits_to_target(POW_LIMIT_BITS);
+    let target: U256 = bits_to_target(parent.bits).saturating_mul(U256::from(actual_timespan))
+        / U256::from(TARGET_TIMESPAN);
+
+    target_to_bits(target.min(pow_limit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a full retarget period of blocks, the last one `timespan` seconds after the first
+    fn period(bits: u32, timespan: u64) -> Vec<Block> {
+        (0..RETARGET_INTERVAL)
+            .map(|index| Block {
+                index,
+                timestamp: 1_000_000 + timespan * index as u64 / (RETARGET_INTERVAL as u64 - 1),
+                bits,
+                ..Block::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn bits_expand_to_target() {
+        assert_eq!(bits_to_target(0x1d00ffff), U256::from(0xffff) << 208);
+        assert_eq!(bits_to_target(0x03123456), U256::from(0x123456));
+        assert_eq!(bits_to_target(0x02008000), U256::from(0x80));
+    }
+
+    #[test]
+    fn invalid_bits_expand_to_zero() {
+        // negative
+        assert!(bits_to_target(0x04923456).is_zero());
+        // past 256 bits
+        assert!(bits_to_target(0xff123456).is_zero());
+    }
+
+    #[test]
+    fn bits_round_trip() {
+        for bits in [
+            0x1d00ffff, 0x1b0404cb, 0x1f00ffff, 0x1e0fffff, 0x03123456, 0x02008000, 0x2100ffff,
+        ] {
+            assert_eq!(target_to_bits(bits_to_target(bits)), bits, "{bits:#010x}");
+        }
+    }
+
+    #[test]
+    fn compact_form_keeps_sign_bit_clear() {
+        assert_eq!(target_to_bits(U256::from(0x80)), 0x02008000);
+        assert_eq!(target_to_bits(U256::from(0x800000) << 8), 0x05008000);
+    }
+
+    #[test]
+    fn first_block_uses_easiest_target() {
+        assert_eq!(next_bits(&[]), POW_LIMIT_BITS);
+    }
+
+    #[test]
+    fn target_is_kept_between_adjustments() {
+        let chain: Vec<Block> = period(0x1e00ffff, 1);
+
+        assert_eq!(next_bits(&chain[..5]), 0x1e00ffff);
+    }
+
+    #[test]
+    fn target_is_kept_at_desired_timespan() {
+        assert_eq!(next_bits(&period(0x1e00ffff, TARGET_TIMESPAN)), 0x1e00ffff);
+    }
+
+    #[test]
+    fn adjustment_is_clamped_to_a_factor_of_four() {
+        let target: U256 = bits_to_target(0x1e00ffff);
+
+        // no time at all counts as a quarter of the timespan
+        assert_eq!(
+            next_bits(&period(0x1e00ffff, 0)),
+            target_to_bits(target / 4)
+        );
+        assert_eq!(
+            next_bits(&period(0x1e00ffff, 0)),
+            next_bits(&period(0x1e00ffff, TARGET_TIMESPAN / 4))
+        );
+
+        // a very slow period counts as four times the timespan
+        assert_eq!(
+            next_bits(&period(0x1e00ffff, TARGET_TIMESPAN * 100)),
+            target_to_bits(target * 4)
+        );
+    }
+
+    #[test]
+    fn target_never_exceeds_the_limit() {
+        assert_eq!(
+            next_bits(&period(POW_LIMIT_BITS, TARGET_TIMESPAN * 4)),
+            POW_LIMIT_BITS
+        );
+    }
+}
@@ -3,16 +3,27 @@ use super::transactions::Transaction;
 
 pub trait HasHashField {
     fn hash(&self) -> String;
+
+    // check if the stored hash matches the contents it claims to cover
+    fn has_valid_hash(&self) -> bool;
 }
 
 impl HasHashField for Transaction {
     fn hash(&self) -> String {
         self.hash.to_string()
     }
+
+    fn has_valid_hash(&self) -> bool {
+        Transaction::has_valid_hash(self)
+    }
 }
 
 impl HasHashField for Block {
     fn hash(&self) -> String {
         self.hash.to_string()
     }
+
+    fn has_valid_hash(&self) -> bool {
+        Block::has_valid_hash(self)
+    }
 }
@@ -0,0 +1,267 @@
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::{LazyLock, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use crate::modules::ban::misbehaving;
+use crate::modules::blockchain::Block;
+use crate::modules::chain::{accept_block, block_transactions, next_height, BlockTree};
+use crate::modules::mempool::mempool;
+use crate::modules::node::get_nodes;
+use crate::modules::sync::{stage_transactions, BlockData};
+use crate::modules::transactions::Transaction;
+use crate::modules::utxo::UtxoSet;
+use crate::modules::validation::{find_transaction, BlockError, TransactionError};
+use crate::p2p::{node_ip, normalize_address, AddressError, BroadCast, RPCClient};
+
+/// Most items handled from a single `inv` announcement
+pub const MAX_INV: usize = 1000;
+
+// most hashes remembered as requested before the set is cleared
+const MAX_SEEN: usize = 50_000;
+
+// hashes of items that are known or already requested from a peer
+static SEEN: LazyLock<Mutex<HashSet<String>>> = LazyLock::new(|| Mutex::new(HashSet::new()));
+
+/// Kind of an announced item
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InventoryKind {
+    Transaction,
+    Block,
+}
+
+/// Announcement of a transaction or block by its hash
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Inventory {
+    pub kind: InventoryKind,
+    pub hash: String,
+}
+
+/// Items sent in reply to a `get_data` request
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct InventoryData {
+    pub transactions: Vec<Transaction>,
+    pub blocks: Vec<BlockData>,
+}
+
+impl Inventory {
+    pub fn transaction(hash: String) -> Inventory {
+        Inventory {
+            kind: InventoryKind::Transaction,
+            hash,
+        }
+    }
+
+    pub fn block(hash: String) -> Inventory {
+        Inventory {
+            kind: InventoryKind::Block,
+            hash,
+        }
+    }
+
+    /// Check if the item is in the local database or in `tree`, the local block tree
+    fn is_known(&self, tree: &BlockTree) -> bool {
+        match self.kind {
+            InventoryKind::Transaction => find_transaction(&self.hash).is_some(),
+            InventoryKind::Block => tree.get(&self.hash).is_some(),
+        }
+    }
+}
+
+/// Remember that an item has been requested. Returns `false` if it was requested before.
+fn mark_seen(hash: &str) -> bool {
+    let mut seen = SEEN.lock().expect("Seen items lock poisoned");
+
+    // forget old items rather than growing without bound
+    if seen.len() >= MAX_SEEN {
+        seen.clear();
+    }
+
+    seen.insert(hash.to_string())
+}
+
+/// Forget that items have been requested, so they can be requested from another peer
+fn forget_seen(items: &[Inventory]) {
+    let mut seen = SEEN.lock().expect("Seen items lock poisoned");
+
+    for item in items {
+        seen.remove(&item.hash);
+    }
+}
+
+/// Announce a transaction to every peer except `sender`, the peer it came from. Peers request
+/// it from `address`, the advertised address of the current node; without one the transaction
+/// is pushed instead. The announcement is sent in the background on the async runtime.
+pub fn relay_transaction(txn: Transaction, sender: Option<&str>, address: Option<&str>) {
+    match address {
+        Some(address) => relay(vec![Inventory::transaction(txn.hash)], address, sender),
+        None => {
+            tokio::spawn(BroadCast::new_untransaction(txn));
+        }
+    }
+}
+
+/// Announce a block to every peer except `sender`, the peer it came from. Peers request it
+/// from `address`, the advertised address of the current node; without one the block is pushed
+/// instead. The announcement is sent in the background on the async runtime.
+pub fn relay_block(block: Block, sender: Option<&str>, address: Option<&str>) {
+    match address {
+        Some(address) => relay(vec![Inventory::block(block.hash)], address, sender),
+        None => {
+            tokio::spawn(async move {
+                // send the transactions before the block that references them
+                for txn in block_transactions(&block) {
+                    BroadCast::block_transaction(txn).await;
+                }
+                BroadCast::new_block(block).await;
+            });
+        }
+    }
+}
+
+/// Send an `inv` announcement in the background, so a slow peer doesn't hold up the caller
+fn relay(items: Vec<Inventory>, address: &str, sender: Option<&str>) {
+    // nothing we announce needs to be requested from anyone else
+    for item in &items {
+        mark_seen(&item.hash);
+    }
+
+    let except: Option<String> = sender.and_then(|sender| normalize_address(sender).ok());
+    tokio::spawn(BroadCast::inv(items, address.to_string(), except));
+}
+
+/// Validate a transaction received from `sender` and add it to the mempool. A new valid
+/// transaction is relayed to the other peers from `address`, the advertised address of the
+/// current node; a known one isn't relayed again.
+pub fn receive_transaction(
+    txn: Transaction,
+    sender: Option<&str>,
+    address: Option<&str>,
+) -> Result<(), TransactionError> {
+    if find_transaction(&txn.hash).is_some() {
+        return Ok(());
+    }
+
+    // the transaction must be well formed and spend outputs of the local chain or the mempool
+    let utxos: UtxoSet = UtxoSet::load();
+    let height: u32 = next_height();
+    mempool().add(txn.clone(), &utxos, height)?;
+
+    relay_transaction(txn, sender, address);
+    Ok(())
+}
+
+/// Validate a block received from `sender` and add it to the block tree. A new valid block is
+/// relayed to the other peers from `address`, the advertised address of the current node.
+pub fn receive_block(
+    data: BlockData,
+    sender: Option<&str>,
+    address: Option<&str>,
+) -> Result<(), BlockError> {
+    stage_transactions(&data.block, data.transactions);
+    accept_block(data.block.clone())?;
+
+    relay_block(data.block, sender, address);
+    Ok(())
+}
+
+/// Pick the node to request the items the peer at `peer` announced for `sender` from. A peer
+/// can't point at a third party: `sender` is only used if it resolves to the IP address the
+/// peer connects from, and the known node at that address is used otherwise.
+fn announcing_node(sender: &str, peer: IpAddr) -> Option<String> {
+    if node_ip(sender).is_ok_and(|ip| ip == peer) {
+        return Some(sender.to_string());
+    }
+
+    get_nodes()
+        .into_iter()
+        .find(|node| node_ip(node).is_ok_and(|ip| ip == peer))
+}
+
+/// Handle an `inv` announcement the peer at `peer` made for the node at `sender`: request the
+/// items that are neither known nor already requested from another peer, then validate them
+/// and relay them from `address`. Invalid items count against the announcing peer.
+pub fn receive_inventory(
+    items: Vec<Inventory>,
+    sender: String,
+    peer: IpAddr,
+    address: Option<&str>,
+) -> Result<(), AddressError> {
+    let Some(sender) = announcing_node(&sender, peer) else {
+        println!("Ignored items {peer} announced for {sender}, a node at another address");
+        return Ok(());
+    };
+    let client: RPCClient = RPCClient::new(sender.clone())?;
+
+    // the block tree is loaded once for the whole announcement
+    let tree: BlockTree = BlockTree::load();
+    let missing: Vec<Inventory> = items
+        .into_iter()
+        .take(MAX_INV)
+        .filter(|item| !item.is_known(&tree) && mark_seen(&item.hash))
+        .collect();
+
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    let data: InventoryData = match client.get_data(&missing) {
+        Ok(data) => data,
+        Err(error) => {
+            println!("Could not get data from {sender}: {error}");
+            forget_seen(&missing);
+            return Ok(());
+        }
+    };
+
+    // only accept the items that were requested
+    let requested = |hash: &String| missing.iter().any(|item| &item.hash == hash);
+
+    for txn in data.transactions {
+        if requested(&txn.hash) {
+            let hash: String = txn.hash.clone();
+            if let Err(error) = receive_transaction(txn, Some(&sender), address) {
+                println!("Rejected transaction {hash} from {sender}: {error}");
+                misbehaving(peer, error.penalty(), error.reason());
+            }
+        }
+    }
+
+    for block in data.blocks {
+        if requested(&block.block.hash) {
+            let hash: String = block.block.hash.clone();
+            match receive_block(block, Some(&sender), address) {
+                Ok(()) => println!("Received block {hash} from {sender}"),
+                Err(error) => {
+                    println!("Rejected block {hash} from {sender}: {error}");
+                    misbehaving(peer, error.penalty(), error.reason());
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Collect the requested items that are in the local database
+pub fn inventory_data(items: &[Inventory]) -> InventoryData {
+    let tree: BlockTree = BlockTree::load();
+    let mut data: InventoryData = InventoryData::default();
+
+    for item in items.iter().take(MAX_INV) {
+        match item.kind {
+            InventoryKind::Transaction => data.transactions.extend(find_transaction(&item.hash)),
+            InventoryKind::Block => {
+                data.blocks
+                    .extend(tree.get(&item.hash).map(|block| BlockData {
+                        block: block.clone(),
+                        transactions: block_transactions(block),
+                    }))
+            }
+        }
+    }
+
+    data
+}
@@ -0,0 +1,54 @@
+use ripemd::Ripemd160;
+use sha2::{Digest, Sha256};
+
+/// Length in bytes of a SHA-256 digest
+pub const HASH_SIZE: usize = 32;
+
+/// Compute SHA-256(SHA-256(data)), the hash function used for blocks
+pub fn sha256d(data: &[u8]) -> [u8; HASH_SIZE] {
+    let first = Sha256::digest(data);
+    Sha256::digest(first).into()
+}
+
+/// Decode a hex encoded hash to its raw bytes.
+/// Malformed hashes decode to all zeroes, so they can never match a real digest.
+pub fn decode_hash(hash: &str) -> [u8; HASH_SIZE] {
+    let mut bytes: [u8; HASH_SIZE] = [0; HASH_SIZE];
+
+    // only accept hashes of the exact digest length
+    if hex::decode_to_slice(hash, &mut bytes).is_err() {
+        return [0; HASH_SIZE];
+    }
+
+    bytes
+}
+
+/// Append a Bitcoin style variable length integer to `data`
+pub fn write_compact_size(data: &mut Vec<u8>, n: u64) {
+    match n {
+        0..=0xfc => data.push(n as u8),
+        0xfd..=0xffff => {
+            data.push(0xfd);
+            data.extend_from_slice(&(n as u16).to_le_bytes());
+        }
+        0x10000..=0xffff_ffff => {
+            data.push(0xfe);
+            data.extend_from_slice(&(n as u32).to_le_bytes());
+        }
+        _ => {
+            data.push(0xff);
+            data.extend_from_slice(&n.to_le_bytes());
+        }
+    }
+}
+
+/// Append length prefixed bytes to `data`
+pub fn write_var_bytes(data: &mut Vec<u8>, bytes: &[u8]) {
+    write_compact_size(data, bytes.len() as u64);
+    data.extend_from_slice(bytes);
+}
+
+/// Compute RIPEMD-160(SHA-256(data)), the hash used for addresses
+pub fn hash160(data: &[u8]) -> [u8; 20] {
+    Ripemd160::digest(Sha256::digest(data)).into()
+}
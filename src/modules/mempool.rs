@@ -0,0 +1,391 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{LazyLock, Mutex, MutexGuard};
+
+use crate::database::{BaseDB, UnTransactionDB};
+use crate::modules::amount::Amount;
+use crate::modules::chain::next_height;
+use crate::modules::transactions::{OutPoint, Transaction};
+use crate::modules::utxo::UtxoSet;
+use crate::modules::validation::{validate_transaction, TransactionError};
+
+/// Lowest fee rate, in satoshis per 1000 bytes, of transactions accepted into the mempool
+pub const MIN_RELAY_FEE_RATE: u64 = 1000;
+
+/// Most transactions staged for blocks that are still being downloaded or validated
+pub const MAX_STAGED: usize = 10_000;
+
+// height given to the outputs of pending transactions when they are spent by other ones
+const UNCONFIRMED_HEIGHT: u32 = u32::MAX;
+
+// pending transactions of the node, loaded from the local database on first use
+static MEMPOOL: LazyLock<Mutex<Mempool>> = LazyLock::new(|| Mutex::new(Mempool::load()));
+
+/// Valid transactions waiting to be mined
+#[derive(Debug, Default)]
+pub struct Mempool {
+    // pending transactions by their hash
+    transactions: HashMap<String, Transaction>,
+    // hash of the pending transaction spending each output
+    spends: HashMap<OutPoint, String>,
+    // transactions sent along with blocks, kept until their block is validated
+    staged: HashMap<String, Transaction>,
+}
+
+/// Fee a transaction of `size` bytes pays at `fee_rate` satoshis per 1000 bytes, rounded up
+pub fn minimum_fee(size: usize, fee_rate: u64) -> Amount {
+    Amount::from_sat((size as u64).saturating_mul(fee_rate).div_ceil(1000))
+}
+
+/// Lock the mempool of the node. The lock must not be held while calling code that looks up
+/// transactions, which locks the mempool itself.
+pub fn mempool() -> MutexGuard<'static, Mempool> {
+    MEMPOOL.lock().expect("Mempool lock poisoned")
+}
+
+impl Mempool {
+    /// Load the transactions persisted by the last run, keeping those that are still valid
+    fn load() -> Mempool {
+        let mut pool: Mempool = Mempool::default();
+        let transactions: Vec<Transaction> = UnTransactionDB::new().find_all();
+
+        pool.add_all(transactions, &UtxoSet::load(), next_height());
+        pool
+    }
+
+    /// Persist the pending transactions to the local database, parents before children
+    pub fn save(&self) {
+        UnTransactionDB::new()
+            .write_all(self.transactions())
+            .expect("Couldn't write to UnTransaction database");
+    }
+
+    /// Number of pending transactions
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    /// Check if the pool has no pending transactions
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
+    /// Get a pending or staged transaction by its hash
+    pub fn find(&self, hash: &str) -> Option<Transaction> {
+        self.transactions
+            .get(hash)
+            .or_else(|| self.staged.get(hash))
+            .cloned()
+    }
+
+    /// Check if a pending transaction spends `outpoint`
+    pub fn is_spent(&self, outpoint: &OutPoint) -> bool {
+        self.spends.contains_key(outpoint)
+    }
+
+    /// Get the pending transactions, every transaction after the pending transactions it spends
+    pub fn transactions(&self) -> Vec<Transaction> {
+        let mut remaining: Vec<&Transaction> = self.transactions.values().collect();
+        remaining.sort_by(|a, b| (a.timestamp, &a.hash).cmp(&(b.timestamp, &b.hash)));
+
+        let mut sorted: Vec<Transaction> = Vec::with_capacity(remaining.len());
+        let mut done: HashSet<&str> = HashSet::new();
+
+        // take the transactions whose pending parents are taken, until all are
+        while !remaining.is_empty() {
+            let before: usize = remaining.len();
+            remaining.retain(|txn| {
+                let ready: bool = txn.vin.iter().all(|vin| {
+                    !self.transactions.contains_key(&vin.txid) || done.contains(vin.txid.as_str())
+                });
+
+                if ready {
+                    done.insert(&txn.hash);
+                    sorted.push((*txn).clone());
+                }
+                !ready
+            });
+
+            // transactions can't spend each other in a cycle, but don't loop forever if they do
+            if remaining.len() == before {
+                break;
+            }
+        }
+
+        sorted
+    }
+
+    /// Validate a transaction and add it to the pool. It may spend confirmed outputs and the
+    /// outputs of pending transactions, but no output another pending transaction spends, and
+    /// must pay at least the minimum relay fee. `height` is the height of the next block.
+    pub fn add(
+        &mut self,
+        txn: Transaction,
+        utxos: &UtxoSet,
+        height: u32,
+    ) -> Result<(), TransactionError> {
+        if self.transactions.contains_key(&txn.hash) {
+            return Ok(());
+        }
+
+        validate_transaction(&txn)?;
+
+        // coinbase transactions are only valid in their own block
+        if txn.is_coinbase() {
+            return Err(TransactionError::Coinbase);
+        }
+
+        let required: Amount = minimum_fee(txn.size(), MIN_RELAY_FEE_RATE);
+        if txn.fee() < required {
+            return Err(TransactionError::FeeTooLow {
+                fee: txn.fee(),
+                required,
+            });
+        }
+
+        // the first transaction to spend an output wins
+        if let Some(vin) = txn.vin.iter().find(|vin| self.is_spent(&vin.outpoint())) {
+            return Err(TransactionError::Conflict(vin.outpoint().to_string()));
+        }
+
+        // the inputs spend confirmed outputs or outputs of pending parents
+        let parents: Vec<&Transaction> = txn
+            .vin
+            .iter()
+            .filter_map(|vin| self.transactions.get(&vin.txid))
+            .collect();
+
+        if parents.is_empty() {
+            utxos.validate_spend(&txn, height)?;
+        } else {
+            let mut view: UtxoSet = utxos.clone();
+            for parent in parents {
+                view.add_outputs(parent, UNCONFIRMED_HEIGHT);
+            }
+            view.validate_spend(&txn, height)?;
+        }
+
+        for vin in &txn.vin {
+            self.spends.insert(vin.outpoint(), txn.hash.clone());
+        }
+        self.staged.remove(&txn.hash);
+        self.transactions.insert(txn.hash.clone(), txn);
+
+        Ok(())
+    }
+
+    /// Add every transaction that is valid, in any order
+    fn add_all(&mut self, transactions: Vec<Transaction>, utxos: &UtxoSet, height: u32) {
+        let mut remaining: Vec<Transaction> = transactions;
+
+        // a child fails until its parent is added, so retry until nothing more can be added
+        loop {
+            let before: usize = remaining.len();
+            remaining.retain(|txn| self.add(txn.clone(), utxos, height).is_err());
+
+            if remaining.is_empty() || remaining.len() == before {
+                break;
+            }
+        }
+    }
+
+    /// Remove a pending transaction along with every pending transaction spending its outputs
+    fn remove(&mut self, hash: &str) {
+        let Some(txn) = self.transactions.remove(hash) else {
+            return;
+        };
+
+        for vin in &txn.vin {
+            self.spends.remove(&vin.outpoint());
+        }
+
+        let children: Vec<String> = (0..txn.vout.len() as u32)
+            .filter_map(|vout| {
+                self.spends.get(&OutPoint {
+                    txid: txn.hash.clone(),
+                    vout,
+                })
+            })
+            .cloned()
+            .collect();
+
+        for child in children {
+            self.remove(&child);
+        }
+    }
+
+    /// Remove the transactions confirmed by a connected block, and the pending transactions
+    /// that spend the same outputs as them together with their descendants
+    pub fn remove_confirmed(&mut self, transactions: &[Transaction]) {
+        for txn in transactions {
+            // the confirmed transaction itself keeps its descendants, which stay valid
+            if let Some(pending) = self.transactions.remove(&txn.hash) {
+                for vin in &pending.vin {
+                    self.spends.remove(&vin.outpoint());
+                }
+            }
+
+            for vin in &txn.vin {
+                if let Some(conflict) = self.spends.get(&vin.outpoint()).cloned() {
+                    self.remove(&conflict);
+                }
+            }
+        }
+    }
+
+    /// Return the transactions of disconnected blocks to the pool, ahead of the pending
+    /// transactions that may spend them. Transactions that are no longer valid at `height`,
+    /// the height of the next block, are dropped.
+    pub fn restore(&mut self, disconnected: Vec<Transaction>, utxos: &UtxoSet, height: u32) {
+        let pending: Vec<Transaction> = self.transactions();
+        self.transactions.clear();
+        self.spends.clear();
+
+        self.add_all(
+            disconnected.into_iter().chain(pending).collect(),
+            utxos,
+            height,
+        );
+    }
+
+    /// Keep a transaction sent along with a block until the block is validated.
+    /// Transactions whose hash doesn't match their contents are ignored.
+    pub fn stage(&mut self, txn: Transaction) {
+        if !txn.has_valid_hash() || self.transactions.contains_key(&txn.hash) {
+            return;
+        }
+
+        // forget staged transactions of blocks that never arrived rather than growing without bound
+        if self.staged.len() >= MAX_STAGED {
+            self.staged.clear();
+        }
+
+        self.staged.insert(txn.hash.clone(), txn);
+    }
+
+    /// Forget staged transactions once their block is stored
+    pub fn unstage(&mut self, hashes: &[String]) {
+        for hash in hashes {
+            self.staged.remove(hash);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::script::{Script, OP_1};
+    use crate::modules::transactions::{Vin, Vout};
+
+    // an output anyone can spend with an empty unlocking script
+    fn anyone_can_spend(amount: Amount) -> Vout {
+        Vout::new(Script::new().push_opcode(OP_1), amount)
+    }
+
+    // a confirmed transaction paying `amount` to an output anyone can spend, and the UTXO set holding it
+    fn fund(amount: Amount) -> (Transaction, UtxoSet) {
+        let vin: Vin = Vin::new("11".repeat(32), 0, amount);
+        let txn: Transaction = Transaction::new(0, vec![vin], vec![anyone_can_spend(amount)]);
+
+        let mut utxos: UtxoSet = UtxoSet::default();
+        utxos.add_outputs(&txn, 0);
+        (txn, utxos)
+    }
+
+    // spend output `vout` of `parent` to a single output, paying `fee`
+    fn spend(parent: &Transaction, vout: u32, fee: Amount) -> Transaction {
+        let amount: Amount = parent.vout[vout as usize].amount;
+        let vin: Vin = Vin::new(parent.hash.clone(), vout, amount);
+
+        Transaction::new(
+            0,
+            vec![vin],
+            vec![anyone_can_spend(amount.checked_sub(fee).unwrap())],
+        )
+    }
+
+    fn hashes(pool: &Mempool) -> Vec<String> {
+        pool.transactions()
+            .into_iter()
+            .map(|txn| txn.hash)
+            .collect()
+    }
+
+    #[test]
+    fn add_accepts_pending_parents_and_rejects_conflicts() {
+        let (funding, utxos) = fund(Amount::from_sat(100_000));
+        let parent: Transaction = spend(&funding, 0, Amount::from_sat(1_000));
+        let child: Transaction = spend(&parent, 0, Amount::from_sat(1_000));
+        let conflict: Transaction = spend(&funding, 0, Amount::from_sat(2_000));
+
+        let mut pool: Mempool = Mempool::default();
+        assert!(pool.add(parent.clone(), &utxos, 1).is_ok());
+        assert!(pool.add(child.clone(), &utxos, 1).is_ok());
+        assert!(matches!(
+            pool.add(conflict, &utxos, 1),
+            Err(TransactionError::Conflict(outpoint)) if outpoint == parent.vin[0].outpoint().to_string()
+        ));
+
+        assert_eq!(hashes(&pool), vec![parent.hash, child.hash]);
+    }
+
+    #[test]
+    fn add_requires_the_minimum_relay_fee() {
+        let (funding, utxos) = fund(Amount::from_sat(100_000));
+        let cheap: Transaction = spend(&funding, 0, Amount::from_sat(1));
+        let required: Amount = minimum_fee(cheap.size(), MIN_RELAY_FEE_RATE);
+
+        let mut pool: Mempool = Mempool::default();
+        assert!(matches!(
+            pool.add(cheap, &utxos, 1),
+            Err(TransactionError::FeeTooLow { required: min, .. }) if min == required
+        ));
+        assert!(pool.is_empty());
+
+        let paying: Transaction = spend(&funding, 0, required);
+        assert!(pool.add(paying, &utxos, 1).is_ok());
+        assert_eq!(pool.len(), 1);
+    }
+
+    #[test]
+    fn remove_confirmed_keeps_descendants_and_drops_conflicts() {
+        let (funding, utxos) = fund(Amount::from_sat(100_000));
+        let parent: Transaction = spend(&funding, 0, Amount::from_sat(1_000));
+        let child: Transaction = spend(&parent, 0, Amount::from_sat(1_000));
+
+        // the confirmed parent leaves its child pending
+        let mut pool: Mempool = Mempool::default();
+        pool.add(parent.clone(), &utxos, 1).unwrap();
+        pool.add(child.clone(), &utxos, 1).unwrap();
+        pool.remove_confirmed(std::slice::from_ref(&parent));
+        assert_eq!(hashes(&pool), vec![child.hash.clone()]);
+
+        // a confirmed conflict evicts the parent along with its child
+        let conflict: Transaction = spend(&funding, 0, Amount::from_sat(2_000));
+        let mut pool: Mempool = Mempool::default();
+        pool.add(parent.clone(), &utxos, 1).unwrap();
+        pool.add(child, &utxos, 1).unwrap();
+        pool.remove_confirmed(&[conflict]);
+        assert!(pool.is_empty());
+        assert!(!pool.is_spent(&parent.vin[0].outpoint()));
+    }
+
+    #[test]
+    fn restore_returns_disconnected_transactions_before_their_children() {
+        let (funding, utxos) = fund(Amount::from_sat(100_000));
+        let parent: Transaction = spend(&funding, 0, Amount::from_sat(1_000));
+        let child: Transaction = spend(&parent, 0, Amount::from_sat(1_000));
+        let (unknown, _) = fund(Amount::from_sat(50_000));
+        let invalid: Transaction = spend(&unknown, 0, Amount::from_sat(1_000));
+
+        // the child stays pending once its parent is confirmed
+        let mut pool: Mempool = Mempool::default();
+        pool.add(parent.clone(), &utxos, 1).unwrap();
+        pool.add(child.clone(), &utxos, 1).unwrap();
+        pool.remove_confirmed(std::slice::from_ref(&parent));
+
+        // disconnecting the block returns the parent; a transaction spending nothing known is dropped
+        pool.restore(vec![invalid, parent.clone()], &utxos, 1);
+        assert_eq!(hashes(&pool), vec![parent.hash, child.hash]);
+    }
+}
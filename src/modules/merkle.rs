@@ -0,0 +1,167 @@
+use serde::{Deserialize, Serialize};
+
+use super::hashing::{decode_hash, sha256d, HASH_SIZE};
+
+/// Proof that a transaction is included under a merkle root
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MerkleProof {
+    // hash of the transaction being proven
+    pub txid: String,
+    // position of the transaction in the block
+    pub index: usize,
+    // sibling hashes from the leaf level up to the root
+    pub siblings: Vec<String>,
+}
+
+/// Hash two child nodes into their parent node
+fn hash_pair(left: &[u8; HASH_SIZE], right: &[u8; HASH_SIZE]) -> [u8; HASH_SIZE] {
+    sha256d(&[&left[..], &right[..]].concat())
+}
+
+/// Compute the next level of the tree, duplicating the last node of an odd level
+fn next_level(level: &[[u8; HASH_SIZE]]) -> Vec<[u8; HASH_SIZE]> {
+    level
+        .chunks(2)
+        .map(|pair| hash_pair(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+        .collect()
+}
+
+/// Compute the merkle root of a list of transaction hashes, hex encoded.
+/// An empty list has a root of all zeroes.
+pub fn merkle_root(tx: &[String]) -> String {
+    // start with the transaction hashes as leaves
+    let mut level: Vec<[u8; HASH_SIZE]> = tx.iter().map(|hash| decode_hash(hash)).collect();
+
+    if level.is_empty() {
+        return hex::encode([0u8; HASH_SIZE]);
+    }
+
+    // hash pairs of nodes together until a single root remains
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+
+    hex::encode(level[0])
+}
+
+/// Build an inclusion proof for `txid` in the list of transaction hashes `tx`
+pub fn merkle_proof(tx: &[String], txid: &str) -> Option<MerkleProof> {
+    // locate the transaction among the leaves
+    let index: usize = tx.iter().position(|hash| hash == txid)?;
+
+    let mut level: Vec<[u8; HASH_SIZE]> = tx.iter().map(|hash| decode_hash(hash)).collect();
+    let mut position: usize = index;
+    let mut siblings: Vec<String> = Vec::new();
+
+    // collect the sibling of the current node on every level up to the root
+    while level.len() > 1 {
+        let sibling: usize = position ^ 1;
+        siblings.push(hex::encode(level.get(sibling).unwrap_or(&level[position])));
+
+        level = next_level(&level);
+        position /= 2;
+    }
+
+    Some(MerkleProof {
+        txid: txid.to_string(),
+        index,
+        siblings,
+    })
+}
+
+/// Check that `proof` links its transaction to the hex encoded merkle `root`
+pub fn verify_proof(proof: &MerkleProof, root: &str) -> bool {
+    let mut hash: [u8; HASH_SIZE] = decode_hash(&proof.txid);
+    let mut position: usize = proof.index;
+
+    // fold the siblings into the leaf, respecting which side each one is on
+    for sibling in &proof.siblings {
+        let sibling: [u8; HASH_SIZE] = decode_hash(sibling);
+
+        hash = if position.is_multiple_of(2) {
+            hash_pair(&hash, &sibling)
+        } else {
+            hash_pair(&sibling, &hash)
+        };
+        position /= 2;
+    }
+
+    // the proof must consume the whole path to the root
+    position == 0 && hex::encode(hash) == root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // distinct leaf hashes, hex encoded
+    fn leaves(count: u8) -> Vec<String> {
+        (0..count).map(|n| hex::encode(sha256d(&[n]))).collect()
+    }
+
+    #[test]
+    fn root_of_single_leaf_is_the_leaf() {
+        let tx: Vec<String> = leaves(1);
+
+        assert_eq!(merkle_root(&tx), tx[0]);
+    }
+
+    #[test]
+    fn root_of_empty_list_is_zero() {
+        assert_eq!(merkle_root(&[]), "0".repeat(64));
+    }
+
+    #[test]
+    fn odd_level_duplicates_last_node() {
+        let tx: Vec<String> = leaves(3);
+        let [a, b, c] = [0, 1, 2].map(|n| decode_hash(&tx[n]));
+        let root: [u8; HASH_SIZE] = hash_pair(&hash_pair(&a, &b), &hash_pair(&c, &c));
+
+        assert_eq!(merkle_root(&tx), hex::encode(root));
+    }
+
+    #[test]
+    fn proofs_verify_for_every_leaf() {
+        for count in 1..=9 {
+            let tx: Vec<String> = leaves(count);
+            let root: String = merkle_root(&tx);
+
+            for txid in &tx {
+                let proof: MerkleProof = merkle_proof(&tx, txid).unwrap();
+                assert!(verify_proof(&proof, &root), "leaf {txid} of {count}");
+            }
+        }
+    }
+
+    #[test]
+    fn proof_of_unknown_transaction_is_none() {
+        let tx: Vec<String> = leaves(5);
+
+        assert!(merkle_proof(&tx, &hex::encode(sha256d(b"unknown"))).is_none());
+    }
+
+    #[test]
+    fn tampered_proofs_fail() {
+        let tx: Vec<String> = leaves(5);
+        let root: String = merkle_root(&tx);
+        let proof: MerkleProof = merkle_proof(&tx, &tx[4]).unwrap();
+
+        // wrong root
+        assert!(!verify_proof(&proof, &merkle_root(&leaves(4))));
+
+        // wrong position
+        let mut moved: MerkleProof = proof.clone();
+        moved.index = 3;
+        assert!(!verify_proof(&moved, &root));
+
+        // missing sibling
+        let mut short: MerkleProof = proof.clone();
+        short.siblings.pop();
+        assert!(!verify_proof(&short, &root));
+
+        // position past the leaves the path covers
+        let mut outside: MerkleProof = proof;
+        outside.index += 8;
+        assert!(!verify_proof(&outside, &root));
+    }
+}
@@ -0,0 +1,584 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io;
+
+use serde::Deserialize;
+
+use crate::database::{
+    BaseDB, BlockIndexDB, BlockchainDB, TransactionDB, UnTransactionDB, UndoDB, UtxoDB,
+};
+use crate::modules::amount::Amount;
+use crate::modules::blockchain::Block;
+use crate::modules::miner::proof_of_work;
+use crate::modules::script::Script;
+use crate::modules::transactions::{OutPoint, Transaction, Vin, Vout, NULL_TXID, NULL_VOUT};
+use crate::modules::utxo::{BlockUndo, UtxoEntry, UtxoSet};
+
+/// A transaction as stored by earlier versions, with 32-bit amounts and addresses in place of
+/// scripts
+#[derive(Debug, Clone, Deserialize)]
+pub struct LegacyTransaction {
+    pub timestamp: u64,
+    pub vin: Vec<LegacyVin>,
+    pub vout: Vec<LegacyVout>,
+    pub hash: String,
+}
+
+/// An input as stored by earlier versions, signed over the old serialization
+#[derive(Debug, Clone, Deserialize)]
+pub struct LegacyVin {
+    pub txid: String,
+    pub vout: u32,
+    // read as 64 bits, so amounts stored after they were widened still parse
+    pub amount: u64,
+    // DER encoded signature, or the block height (u32 LE) of a coinbase input, hex encoded
+    #[serde(default)]
+    pub signature: String,
+    // compressed public key of the sender, hex encoded
+    #[serde(default)]
+    pub pubkey: String,
+}
+
+/// An output as stored by earlier versions, paying an address
+#[derive(Debug, Clone, Deserialize)]
+pub struct LegacyVout {
+    pub receiver: String,
+    pub amount: u64,
+}
+
+/// An unspent output as stored by earlier versions
+#[derive(Debug, Clone, Deserialize)]
+pub struct LegacyUtxoEntry {
+    pub outpoint: OutPoint,
+    pub output: LegacyVout,
+    pub height: u32,
+    #[serde(default)]
+    pub coinbase: bool,
+}
+
+/// Undo data as stored by earlier versions
+#[derive(Debug, Clone, Deserialize)]
+pub struct LegacyBlockUndo {
+    pub block: String,
+    pub spent: Vec<LegacyUtxoEntry>,
+}
+
+#[derive(Debug)]
+pub enum MigrationError {
+    // the database holds neither the current nor an earlier format
+    Unreadable(String),
+    // an output pays something other than a P2PKH address
+    InvalidAddress(String),
+    // the signature or public key of an input of the transaction isn't hex encoded
+    InvalidSignature(String),
+    // the migrated database couldn't be written
+    Write(String, io::Error),
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MigrationError::Unreadable(path) => {
+                write!(f, "{path} is in neither the current nor an earlier format")
+            }
+            MigrationError::InvalidAddress(address) => {
+                write!(f, "{address} is not a P2PKH address")
+            }
+            MigrationError::InvalidSignature(txid) => {
+                write!(
+                    f,
+                    "transaction {txid} has an input with a malformed signature"
+                )
+            }
+            MigrationError::Write(path, error) => write!(f, "couldn't write {path}: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {}
+
+/// Rewrites items stored by earlier versions in the current format. Transaction IDs and block
+/// hashes commit to the old serialization, so every item gets a new hash and every reference
+/// to an old hash is pointed at the new one.
+#[derive(Debug, Default)]
+pub struct Migration {
+    // new ID of every migrated transaction, by its old ID
+    txids: HashMap<String, String>,
+    // migrated blocks, by their old hash
+    blocks: HashMap<String, Block>,
+}
+
+impl Migration {
+    /// Migrate `legacy` transactions, parents before the transactions spending them, and return
+    /// them in their original order
+    pub fn transactions(
+        &mut self,
+        legacy: Vec<LegacyTransaction>,
+    ) -> Result<Vec<Transaction>, MigrationError> {
+        let mut migrated: HashMap<String, Transaction> = HashMap::new();
+        let mut pending: Vec<&LegacyTransaction> = legacy.iter().collect();
+
+        while !pending.is_empty() {
+            let waiting: HashSet<&str> = pending.iter().map(|txn| txn.hash.as_str()).collect();
+
+            // transactions whose parents are migrated, or all of them if their inputs form a loop
+            let (mut ready, blocked): (Vec<&LegacyTransaction>, Vec<&LegacyTransaction>) =
+                pending.iter().partition(|txn| {
+                    txn.vin
+                        .iter()
+                        .all(|vin| !waiting.contains(vin.txid.as_str()))
+                });
+            if ready.is_empty() {
+                ready = blocked.clone();
+            }
+
+            for txn in &ready {
+                migrated.insert(txn.hash.clone(), self.transaction(txn)?);
+            }
+            pending = blocked
+                .into_iter()
+                .filter(|txn| !migrated.contains_key(&txn.hash))
+                .collect();
+        }
+
+        Ok(legacy
+            .iter()
+            .filter_map(|txn| migrated.remove(&txn.hash))
+            .collect())
+    }
+
+    /// Migrate a single transaction: amounts are widened, addresses become P2PKH locking
+    /// scripts, signatures become `<signature> <pubkey>` unlocking scripts, and the transaction
+    /// gets a new ID
+    pub fn transaction(
+        &mut self,
+        legacy: &LegacyTransaction,
+    ) -> Result<Transaction, MigrationError> {
+        let mut vin: Vec<Vin> = Vec::new();
+        for input in &legacy.vin {
+            let mut migrated: Vin = Vin::new(
+                self.txid(&input.txid),
+                input.vout,
+                Amount::from_sat(input.amount),
+            );
+
+            let malformed = |_| MigrationError::InvalidSignature(legacy.hash.clone());
+            let signature: Vec<u8> = hex::decode(&input.signature).map_err(malformed)?;
+            let pubkey: Vec<u8> = hex::decode(&input.pubkey).map_err(malformed)?;
+            migrated.script_sig = if input.txid == NULL_TXID && input.vout == NULL_VOUT {
+                // the coinbase input pushes the height of its block
+                Script::new().push_slice(&signature)
+            } else if signature.is_empty() {
+                Script::new()
+            } else {
+                Script::new().push_slice(&signature).push_slice(&pubkey)
+            };
+            migrated.hash = migrated.calculate_hash();
+
+            vin.push(migrated);
+        }
+
+        let vout: Vec<Vout> = legacy
+            .vout
+            .iter()
+            .map(migrate_output)
+            .collect::<Result<_, _>>()?;
+
+        let txn: Transaction = Transaction::new(legacy.timestamp, vin, vout);
+        self.txids.insert(legacy.hash.clone(), txn.hash.clone());
+
+        Ok(txn)
+    }
+
+    /// Point `block` at the new IDs of its transactions and the new hash of its parent, mining
+    /// it again if either changed. Parents must be migrated before their children.
+    pub fn block(&mut self, block: Block) -> Block {
+        if let Some(migrated) = self.blocks.get(&block.hash) {
+            return migrated.clone();
+        }
+
+        let tx: Vec<String> = block.tx.iter().map(|hash| self.txid(hash)).collect();
+        let previous_block: String = self
+            .blocks
+            .get(&block.previous_block)
+            .map(|parent| parent.hash.clone())
+            .unwrap_or_else(|| block.previous_block.clone());
+
+        let migrated: Block = if tx == block.tx && previous_block == block.previous_block {
+            block.clone()
+        } else {
+            // the new hash must meet the difficulty target of the block again
+            let mut migrated: Block =
+                Block::new(block.index, block.timestamp, tx, previous_block, block.bits);
+            proof_of_work(&mut migrated);
+            migrated
+        };
+
+        self.blocks.insert(block.hash, migrated.clone());
+        migrated
+    }
+
+    /// Migrate an unspent output recorded by an earlier version
+    pub fn utxo_entry(&self, legacy: &LegacyUtxoEntry) -> Result<UtxoEntry, MigrationError> {
+        Ok(UtxoEntry {
+            outpoint: OutPoint {
+                txid: self.txid(&legacy.outpoint.txid),
+                vout: legacy.outpoint.vout,
+            },
+            output: migrate_output(&legacy.output)?,
+            height: legacy.height,
+            coinbase: legacy.coinbase,
+        })
+    }
+
+    /// Migrate the undo data of a block recorded by an earlier version
+    pub fn block_undo(&self, legacy: &LegacyBlockUndo) -> Result<BlockUndo, MigrationError> {
+        Ok(BlockUndo {
+            block: self
+                .blocks
+                .get(&legacy.block)
+                .map(|block| block.hash.clone())
+                .unwrap_or_else(|| legacy.block.clone()),
+            spent: legacy
+                .spent
+                .iter()
+                .map(|entry| self.utxo_entry(entry))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    // the new ID of a transaction, which is unchanged if it wasn't migrated
+    fn txid(&self, hash: &str) -> String {
+        self.txids
+            .get(hash)
+            .cloned()
+            .unwrap_or_else(|| hash.to_string())
+    }
+}
+
+// migrate an output, locking it to the P2PKH script of its receiver
+fn migrate_output(legacy: &LegacyVout) -> Result<Vout, MigrationError> {
+    let script_pubkey: Script = Script::for_address(&legacy.receiver)
+        .ok_or_else(|| MigrationError::InvalidAddress(legacy.receiver.clone()))?;
+
+    Ok(Vout::new(script_pubkey, Amount::from_sat(legacy.amount)))
+}
+
+// read the transactions of `db` in an earlier format, or `None` if they are current or missing
+fn read_legacy_transactions(
+    db: &impl BaseDB,
+) -> Result<Option<Vec<LegacyTransaction>>, MigrationError> {
+    let raw: String = std::fs::read_to_string(db.get_path()).unwrap_or_default();
+    if raw.trim().is_empty() || serde_json::from_str::<Vec<Transaction>>(&raw).is_ok() {
+        return Ok(None);
+    }
+
+    serde_json::from_str(&raw)
+        .map(Some)
+        .map_err(|_| MigrationError::Unreadable(db.get_path()))
+}
+
+// overwrite `db` with migrated items
+fn write_migrated<T: serde::Serialize>(
+    db: &impl BaseDB,
+    items: Vec<T>,
+) -> Result<(), MigrationError> {
+    db.write_all(items)
+        .map_err(|error| MigrationError::Write(db.get_path(), error))
+}
+
+// migrate the blocks of `db`, overwriting it if any of them changed
+fn migrate_blocks(
+    migration: &mut Migration,
+    db: &impl BaseDB,
+    blocks: Vec<Block>,
+    migrated: &mut Vec<String>,
+) -> Result<Vec<Block>, MigrationError> {
+    let hashes: Vec<String> = blocks.iter().map(|block| block.hash.clone()).collect();
+    let blocks: Vec<Block> = blocks
+        .into_iter()
+        .map(|block| migration.block(block))
+        .collect();
+
+    if blocks.iter().map(|block| &block.hash).ne(hashes.iter()) {
+        write_migrated(db, blocks.clone())?;
+        migrated.push(db.get_path());
+    }
+
+    Ok(blocks)
+}
+
+/// Migrate the local databases written by earlier versions, returning the paths of the
+/// migrated databases. Transactions are rewritten with 64-bit amounts and scripts, blocks are
+/// pointed at the new transaction IDs and mined again, and the UTXO set and undo data follow
+/// the new IDs. Databases already in the current format are left untouched.
+pub fn migrate_databases() -> Result<Vec<String>, MigrationError> {
+    let transaction_db: TransactionDB = TransactionDB::new();
+    let untransaction_db: UnTransactionDB = UnTransactionDB::new();
+    let legacy_transactions = read_legacy_transactions(&transaction_db)?;
+    let legacy_untransactions = read_legacy_transactions(&untransaction_db)?;
+
+    let mut migration: Migration = Migration::default();
+    let mut migrated: Vec<String> = Vec::new();
+
+    // pending transactions may spend confirmed ones, so those are migrated first
+    let transactions: Option<Vec<Transaction>> = legacy_transactions
+        .map(|legacy| migration.transactions(legacy))
+        .transpose()?;
+    let untransactions: Option<Vec<Transaction>> = legacy_untransactions
+        .map(|legacy| migration.transactions(legacy))
+        .transpose()?;
+
+    // blocks only change if the IDs of their transactions do
+    if !migration.txids.is_empty() {
+        let blockchain_db: BlockchainDB = BlockchainDB::new();
+        let block_index_db: BlockIndexDB = BlockIndexDB::new();
+
+        // parents have lower heights, so they are migrated before their children
+        let mut index: Vec<Block> = block_index_db.find_all();
+        index.sort_by_key(|block| block.index);
+        migrate_blocks(&mut migration, &block_index_db, index, &mut migrated)?;
+        let chain: Vec<Block> = migrate_blocks(
+            &mut migration,
+            &blockchain_db,
+            blockchain_db.find_all(),
+            &mut migrated,
+        )?;
+        let tip: Option<String> = chain.last().map(|block| block.hash.clone());
+
+        // the UTXO set is carried over rather than rebuilt, because rebuilding verifies the
+        // signatures of migrated spends, which were made over the old serialization
+        let utxo_db: UtxoDB = UtxoDB::new();
+        if let Some(legacy) = utxo_db.read_value::<Vec<LegacyUtxoEntry>>() {
+            let entries: Vec<UtxoEntry> = legacy
+                .iter()
+                .map(|entry| migration.utxo_entry(entry))
+                .collect::<Result<_, _>>()?;
+            UtxoSet::from_entries(tip, entries).save();
+            migrated.push(utxo_db.get_path());
+        }
+
+        let undo_db: UndoDB = UndoDB::new();
+        if let Some(legacy) = undo_db.read_value::<Vec<LegacyBlockUndo>>() {
+            let undos: Vec<BlockUndo> = legacy
+                .iter()
+                .map(|undo| migration.block_undo(undo))
+                .collect::<Result<_, _>>()?;
+            write_migrated(&undo_db, undos)?;
+            migrated.push(undo_db.get_path());
+        }
+    }
+
+    // the transaction databases are written last, so an interrupted migration runs again
+    if let Some(untransactions) = untransactions {
+        write_migrated(&untransaction_db, untransactions)?;
+        migrated.push(untransaction_db.get_path());
+    }
+    if let Some(transactions) = transactions {
+        write_migrated(&transaction_db, transactions)?;
+        migrated.push(transaction_db.get_path());
+    }
+
+    Ok(migrated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::difficulty::{meets_target, POW_LIMIT_BITS};
+    use crate::modules::miner::GENESIS_PREVIOUS_BLOCK;
+
+    const ADDRESS: &str = "1L8Q3xJyk5MnWoV1Qz6sfT57yGB6bA7DgR";
+    const SIGNATURE: &str = "3006020101020101";
+    const PUBKEY: &str = "02aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
+
+    // confirmed transactions of an earlier version: a coinbase and a spend of its output
+    const TRANSACTIONS: &str = r#"[
+        {
+            "timestamp": 1,
+            "vin": [{
+                "txid": "0000000000000000000000000000000000000000000000000000000000000000",
+                "vout": 4294967295,
+                "sender": "",
+                "amount": 0,
+                "signature": "01000000",
+                "pubkey": "",
+                "hash": "01"
+            }],
+            "vout": [{"receiver": "1L8Q3xJyk5MnWoV1Qz6sfT57yGB6bA7DgR", "amount": 5000, "hash": "02"}],
+            "hash": "1111111111111111111111111111111111111111111111111111111111111111"
+        },
+        {
+            "timestamp": 2,
+            "vin": [{
+                "txid": "1111111111111111111111111111111111111111111111111111111111111111",
+                "vout": 0,
+                "sender": "1L8Q3xJyk5MnWoV1Qz6sfT57yGB6bA7DgR",
+                "amount": 5000,
+                "signature": "3006020101020101",
+                "pubkey": "02aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa",
+                "hash": "03"
+            }],
+            "vout": [{"receiver": "1L8Q3xJyk5MnWoV1Qz6sfT57yGB6bA7DgR", "amount": 4000, "hash": "04"}],
+            "hash": "2222222222222222222222222222222222222222222222222222222222222222"
+        }
+    ]"#;
+
+    // pending transactions of an earlier version, a child saved before its parent
+    const UNTRANSACTIONS: &str = r#"[
+        {
+            "timestamp": 4,
+            "vin": [{
+                "txid": "3333333333333333333333333333333333333333333333333333333333333333",
+                "vout": 0,
+                "sender": "1L8Q3xJyk5MnWoV1Qz6sfT57yGB6bA7DgR",
+                "amount": 3000,
+                "signature": "",
+                "pubkey": "",
+                "hash": "05"
+            }],
+            "vout": [{"receiver": "1L8Q3xJyk5MnWoV1Qz6sfT57yGB6bA7DgR", "amount": 2000, "hash": "06"}],
+            "hash": "4444444444444444444444444444444444444444444444444444444444444444"
+        },
+        {
+            "timestamp": 3,
+            "vin": [{
+                "txid": "2222222222222222222222222222222222222222222222222222222222222222",
+                "vout": 0,
+                "sender": "1L8Q3xJyk5MnWoV1Qz6sfT57yGB6bA7DgR",
+                "amount": 4000,
+                "signature": "",
+                "pubkey": "",
+                "hash": "07"
+            }],
+            "vout": [{"receiver": "1L8Q3xJyk5MnWoV1Qz6sfT57yGB6bA7DgR", "amount": 3000, "hash": "08"}],
+            "hash": "3333333333333333333333333333333333333333333333333333333333333333"
+        }
+    ]"#;
+
+    // the UTXO set of an earlier version, holding the output of the spend
+    const UTXOS: &str = r#"[
+        {
+            "outpoint": {
+                "txid": "2222222222222222222222222222222222222222222222222222222222222222",
+                "vout": 0
+            },
+            "output": {"receiver": "1L8Q3xJyk5MnWoV1Qz6sfT57yGB6bA7DgR", "amount": 4000, "hash": "04"},
+            "height": 1,
+            "coinbase": false
+        }
+    ]"#;
+
+    // the block confirming both transactions, under its old hash
+    fn legacy_block() -> Block {
+        let tx: Vec<String> = vec!["11".repeat(32), "22".repeat(32)];
+        let mut block: Block =
+            Block::new(1, 1, tx, GENESIS_PREVIOUS_BLOCK.to_string(), POW_LIMIT_BITS);
+        block.hash = "55".repeat(32);
+        block
+    }
+
+    fn migrate() -> (Migration, Vec<Transaction>, Vec<Transaction>) {
+        let mut migration: Migration = Migration::default();
+        let transactions: Vec<Transaction> = migration
+            .transactions(serde_json::from_str(TRANSACTIONS).unwrap())
+            .unwrap();
+        let untransactions: Vec<Transaction> = migration
+            .transactions(serde_json::from_str(UNTRANSACTIONS).unwrap())
+            .unwrap();
+
+        (migration, transactions, untransactions)
+    }
+
+    #[test]
+    fn current_transactions_are_not_read_as_legacy() {
+        assert!(serde_json::from_str::<Vec<Transaction>>(TRANSACTIONS).is_err());
+
+        let current: String = serde_json::to_string(&migrate().1).unwrap();
+        assert!(serde_json::from_str::<Vec<LegacyTransaction>>(&current).is_err());
+    }
+
+    #[test]
+    fn transactions_are_rewritten_with_wide_amounts_and_scripts() {
+        let (_, transactions, _) = migrate();
+        let [coinbase, spend] = &transactions[..] else {
+            panic!("expected two transactions");
+        };
+
+        assert!(transactions.iter().all(Transaction::has_valid_hash));
+        assert_eq!(coinbase.coinbase_height(), Some(1));
+        assert_eq!(coinbase.vout[0].amount, Amount::from_sat(5000));
+        assert_eq!(
+            Some(&coinbase.vout[0].script_pubkey),
+            Script::for_address(ADDRESS).as_ref()
+        );
+
+        // the spend points at the new ID of the coinbase and keeps its signature
+        assert_eq!(spend.vin[0].txid, coinbase.hash);
+        assert_eq!(spend.vin[0].amount, Amount::from_sat(5000));
+        assert_eq!(
+            spend.vin[0].script_sig,
+            Script::new()
+                .push_slice(&hex::decode(SIGNATURE).unwrap())
+                .push_slice(&hex::decode(PUBKEY).unwrap())
+        );
+        assert_eq!(spend.fee(), Amount::from_sat(1000));
+    }
+
+    #[test]
+    fn parents_are_migrated_before_their_children() {
+        let (_, transactions, untransactions) = migrate();
+        let [child, parent] = &untransactions[..] else {
+            panic!("expected two pending transactions");
+        };
+
+        // the original order is kept
+        assert_eq!(child.timestamp, 4);
+        assert_eq!(parent.vin[0].txid, transactions[1].hash);
+        assert_eq!(child.vin[0].txid, parent.hash);
+    }
+
+    #[test]
+    fn blocks_follow_the_new_transaction_ids() {
+        let (mut migration, transactions, _) = migrate();
+
+        let block: Block = migration.block(legacy_block());
+
+        assert_eq!(
+            block.tx,
+            vec![transactions[0].hash.clone(), transactions[1].hash.clone()]
+        );
+        assert!(block.has_valid_hash());
+        assert!(meets_target(&block.hash, block.bits));
+        assert_eq!(block.previous_block, GENESIS_PREVIOUS_BLOCK);
+
+        // children point at the new hash of their parent
+        let mut child: Block = Block::new(2, 2, vec![], "55".repeat(32), POW_LIMIT_BITS);
+        child.hash = "66".repeat(32);
+        assert_eq!(migration.block(child).previous_block, block.hash);
+    }
+
+    #[test]
+    fn utxo_entries_follow_the_new_transaction_ids() {
+        let (migration, transactions, _) = migrate();
+        let legacy: Vec<LegacyUtxoEntry> = serde_json::from_str(UTXOS).unwrap();
+
+        let entry: UtxoEntry = migration.utxo_entry(&legacy[0]).unwrap();
+
+        assert_eq!(entry.outpoint.txid, transactions[1].hash);
+        assert_eq!(entry.output.hash, transactions[1].vout[0].hash);
+    }
+
+    #[test]
+    fn outputs_must_pay_an_address() {
+        let legacy: LegacyVout = LegacyVout {
+            receiver: "not an address".to_string(),
+            amount: 1,
+        };
+
+        assert!(matches!(
+            migrate_output(&legacy),
+            Err(MigrationError::InvalidAddress(_))
+        ));
+    }
+}
@@ -0,0 +1,406 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::database::{BaseDB, BlockchainDB};
+use crate::modules::amount::Amount;
+use crate::modules::blockchain::Block;
+use crate::modules::chain::{accept_block, chain_lock};
+use crate::modules::difficulty::{meets_target, next_bits};
+use crate::modules::gossip::relay_block;
+use crate::modules::mempool::mempool;
+use crate::modules::script::Script;
+use crate::modules::subsidy::{block_subsidy, halving_interval};
+use crate::modules::transactions::Transaction;
+use crate::modules::utxo::UtxoSet;
+use crate::modules::validation::median_time_past;
+
+/// Previous block hash of the first block on the chain
+pub const GENESIS_PREVIOUS_BLOCK: &str =
+    "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Most bytes of transactions the miner puts in a block
+pub const MAX_BLOCK_SIZE: usize = 1_000_000;
+
+/// Get the current UNIX time in seconds
+pub fn current_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System time is before the UNIX epoch")
+        .as_secs()
+}
+
+/// Build an unmined block containing `transactions` on top of the last block of `chain`
+pub fn create_block(chain: &[Block], transactions: &[Transaction]) -> Block {
+    // collect the hashes of all transactions to be included in the block
+    let tx: Vec<String> = transactions.iter().map(|txn| txn.hash.clone()).collect();
+
+    // the difficulty target follows from the chain being extended
+    let bits: u32 = next_bits(chain);
+
+    // the timestamp must be after the median time past, even if the clock is behind it
+    let timestamp: u64 = current_timestamp().max(median_time_past(chain) + 1);
+
+    // link the block to the tip of the chain or start a new chain
+    match chain.last() {
+        Some(tip) => Block::new(tip.index + 1, timestamp, tx, tip.hash.clone(), bits),
+        None => Block::new(0, timestamp, tx, GENESIS_PREVIOUS_BLOCK.to_string(), bits),
+    }
+}
+
+/// Compare the fee rates of two packages, given as total fee and size, without rounding
+fn compare_fee_rates((fee_a, size_a): (u64, usize), (fee_b, size_b): (u64, usize)) -> Ordering {
+    (fee_a as u128 * size_b as u128).cmp(&(fee_b as u128 * size_a as u128))
+}
+
+// a transaction together with its ancestors that aren't in the block yet, as queued for the block
+#[derive(PartialEq, Eq)]
+struct Package {
+    // total fee and size of the transaction and those ancestors
+    fee: u64,
+    size: usize,
+    // position of the transaction in the pending transactions
+    position: usize,
+}
+
+impl Ord for Package {
+    // the highest fee rate first, the oldest transaction first among equal rates
+    fn cmp(&self, other: &Package) -> Ordering {
+        compare_fee_rates((self.fee, self.size), (other.fee, other.size))
+            .then(other.position.cmp(&self.position))
+    }
+}
+
+impl PartialOrd for Package {
+    fn partial_cmp(&self, other: &Package) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Pick the transactions of a block from `pending`, ordered so that every transaction comes
+/// after the pending transactions it spends. A transaction is considered together with its
+/// ancestors that aren't in the block yet, and the package with the highest fee rate is added
+/// first, so a child paying a high fee pulls in its parent. Packages that would take the block
+/// past `max_size` bytes, or that don't apply to the UTXO set at `height`, are skipped.
+pub fn select_transactions(
+    pending: &[Transaction],
+    utxos: &UtxoSet,
+    height: u32,
+    max_size: usize,
+) -> Vec<Transaction> {
+    let positions: HashMap<&str, usize> = pending
+        .iter()
+        .enumerate()
+        .map(|(position, txn)| (txn.hash.as_str(), position))
+        .collect();
+    let sizes: Vec<usize> = pending.iter().map(Transaction::size).collect();
+    let fees: Vec<u64> = pending.iter().map(|txn| txn.fee().to_sat()).collect();
+
+    // the pending transactions every transaction spends, and the ones spending it
+    let mut parents: Vec<HashSet<usize>> = vec![HashSet::new(); pending.len()];
+    let mut children: Vec<Vec<usize>> = vec![Vec::new(); pending.len()];
+    for (position, txn) in pending.iter().enumerate() {
+        for vin in &txn.vin {
+            if let Some(&parent) = positions.get(vin.txid.as_str()) {
+                if parent != position && parents[position].insert(parent) {
+                    children[parent].push(position);
+                }
+            }
+        }
+    }
+
+    // the ancestors of every transaction, including itself, with their total fee and size.
+    // Both are only updated when an ancestor is added to the block
+    let mut ancestors: Vec<HashSet<usize>> = (0..pending.len())
+        .map(|position| {
+            let mut members: HashSet<usize> = HashSet::new();
+            let mut stack: Vec<usize> = vec![position];
+            while let Some(member) = stack.pop() {
+                if members.insert(member) {
+                    stack.extend(parents[member].iter().copied());
+                }
+            }
+            members
+        })
+        .collect();
+    let mut packages: Vec<(u64, usize)> = ancestors
+        .iter()
+        .map(|members| {
+            let fee: u64 = members.iter().map(|member| fees[*member]).sum();
+            let size: usize = members.iter().map(|member| sizes[*member]).sum();
+            (fee, size)
+        })
+        .collect();
+
+    let mut queue: BinaryHeap<Package> = packages
+        .iter()
+        .enumerate()
+        .map(|(position, &(fee, size))| Package {
+            fee,
+            size,
+            position,
+        })
+        .collect();
+
+    let mut utxos: UtxoSet = utxos.clone();
+    let mut included: HashSet<usize> = HashSet::new();
+    let mut skipped: HashSet<usize> = HashSet::new();
+    let mut selected: Vec<Transaction> = Vec::new();
+    let mut size: usize = 0;
+
+    while let Some(Package {
+        fee,
+        size: package_size,
+        position,
+    }) = queue.pop()
+    {
+        // packages queued before an ancestor was added to the block are outdated
+        if included.contains(&position)
+            || skipped.contains(&position)
+            || packages[position] != (fee, package_size)
+        {
+            continue;
+        }
+
+        if size + package_size > max_size {
+            skipped.insert(position);
+            continue;
+        }
+
+        // `pending` lists parents before their children
+        let mut members: Vec<usize> = ancestors[position].iter().copied().collect();
+        members.sort_unstable();
+
+        // apply the whole package or nothing of it
+        let mut staged: UtxoSet = utxos.clone();
+        if !members
+            .iter()
+            .all(|member| staged.apply_transaction(&pending[*member], height).is_ok())
+        {
+            skipped.insert(position);
+            continue;
+        }
+
+        utxos = staged;
+        size += package_size;
+        for &member in &members {
+            included.insert(member);
+            selected.push(pending[member].clone());
+        }
+
+        // the descendants of the package no longer pay for the members, requeue them
+        let mut stack: Vec<usize> = members
+            .iter()
+            .flat_map(|member| children[*member].iter().copied())
+            .collect();
+        let mut visited: HashSet<usize> = HashSet::new();
+        while let Some(descendant) = stack.pop() {
+            if included.contains(&descendant) || !visited.insert(descendant) {
+                continue;
+            }
+            stack.extend(children[descendant].iter().copied());
+
+            for member in &members {
+                if ancestors[descendant].remove(member) {
+                    packages[descendant].0 -= fees[*member];
+                    packages[descendant].1 -= sizes[*member];
+                }
+            }
+
+            let (fee, size) = packages[descendant];
+            queue.push(Package {
+                fee,
+                size,
+                position: descendant,
+            });
+        }
+    }
+
+    selected
+}
+
+/// Iterate the nonce of `block` until its hash meets the difficulty target
+pub fn proof_of_work(block: &mut Block) {
+    loop {
+        // hash the block with the current nonce
+        let hash: String = block.calculate_hash();
+
+        if meets_target(&hash, block.bits) {
+            // a valid hash was found, seal the block
+            block.hash = hash;
+            return;
+        }
+
+        // try the next nonce, refreshing the timestamp once the nonce space runs out
+        match block.nonce.checked_add(1) {
+            Some(nonce) => block.nonce = nonce,
+            None => {
+                block.nonce = 0;
+                block.timestamp = block.timestamp.max(current_timestamp());
+            }
+        }
+    }
+}
+
+/// Mine the pending transactions paying the most into a new block, along with a coinbase
+/// transaction paying the subsidy and their fees to `script_pubkey`, store it and announce it
+/// to the network from `address`, the advertised address of the current node.
+/// Returns `None` if the block was not stored.
+pub fn mine(script_pubkey: &Script, address: Option<&str>) -> Option<Block> {
+    // build the block against one consistent view of the chain and its UTXO set
+    let chain_guard = chain_lock();
+    let chain: Vec<Block> = BlockchainDB::new().find_all();
+    let height: u32 = chain.last().map_or(0, |tip| tip.index + 1);
+
+    // leave room for the coinbase transaction, whose size doesn't depend on its amount
+    let placeholder: Transaction = Transaction::coinbase(
+        height,
+        current_timestamp(),
+        script_pubkey.clone(),
+        Amount::ZERO,
+    );
+
+    // fill the block with the transactions paying the highest fee rates, spending them against
+    // a copy of the UTXO set so that conflicting transactions don't end up in the same block
+    let pending: Vec<Transaction> = mempool().transactions();
+    let selected: Vec<Transaction> = select_transactions(
+        &pending,
+        &UtxoSet::load(),
+        height,
+        MAX_BLOCK_SIZE - placeholder.size(),
+    );
+
+    // claim the subsidy and the fees of the block
+    let subsidy: Amount = block_subsidy(height, halving_interval());
+    let reward: Amount = Amount::checked_sum(selected.iter().map(Transaction::fee))
+        .and_then(|fees| subsidy.checked_add(fees))
+        .unwrap_or(subsidy);
+    let coinbase: Transaction =
+        Transaction::coinbase(height, placeholder.timestamp, script_pubkey.clone(), reward);
+
+    let mut transactions: Vec<Transaction> = vec![coinbase.clone()];
+    transactions.extend(selected);
+
+    // build a block on top of the current tip and mine it, letting other blocks in meanwhile
+    let mut block: Block = create_block(&chain, &transactions);
+    drop(chain_guard);
+    proof_of_work(&mut block);
+
+    // store the block on the local chain, moving the mined transactions out of the mempool
+    mempool().stage(coinbase);
+    if let Err(error) = accept_block(block.clone()) {
+        // the chain moved on while mining
+        eprintln!("Miner: Discarding block {}: {error}", block.hash);
+        return None;
+    }
+
+    // announce the block to the network
+    relay_block(block.clone(), None, address);
+
+    Some(block)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::script::OP_1;
+    use crate::modules::transactions::{Vin, Vout};
+
+    const FUNDS: u64 = 100_000;
+
+    // an output anyone can spend with an empty unlocking script
+    fn anyone_can_spend(amount: u64) -> Vout {
+        Vout::new(Script::new().push_opcode(OP_1), Amount::from_sat(amount))
+    }
+
+    // a confirmed transaction with `count` spendable outputs, and the UTXO set holding them
+    fn fund(count: usize) -> (Transaction, UtxoSet) {
+        let vin: Vin = Vin::new("11".repeat(32), 0, Amount::from_sat(FUNDS * count as u64));
+        let vout: Vec<Vout> = (0..count).map(|_| anyone_can_spend(FUNDS)).collect();
+        let txn: Transaction = Transaction::new(0, vec![vin], vout);
+
+        let mut utxos: UtxoSet = UtxoSet::default();
+        utxos.add_outputs(&txn, 0);
+        (txn, utxos)
+    }
+
+    // spend output `vout` of `parent` to a single output, paying `fee`
+    fn spend(parent: &Transaction, vout: u32, fee: u64) -> Transaction {
+        let amount: Amount = parent.vout[vout as usize].amount;
+        let vin: Vin = Vin::new(parent.hash.clone(), vout, amount);
+
+        Transaction::new(0, vec![vin], vec![anyone_can_spend(amount.to_sat() - fee)])
+    }
+
+    fn hashes(transactions: &[Transaction]) -> Vec<&str> {
+        transactions.iter().map(|txn| txn.hash.as_str()).collect()
+    }
+
+    #[test]
+    fn highest_fee_rate_first() {
+        let (funding, utxos) = fund(3);
+        let low: Transaction = spend(&funding, 0, 1_000);
+        let high: Transaction = spend(&funding, 1, 3_000);
+        let middle: Transaction = spend(&funding, 2, 2_000);
+        let pending: Vec<Transaction> = vec![low.clone(), high.clone(), middle.clone()];
+
+        let selected: Vec<Transaction> = select_transactions(&pending, &utxos, 1, MAX_BLOCK_SIZE);
+
+        assert_eq!(hashes(&selected), hashes(&[high, middle, low]));
+    }
+
+    #[test]
+    fn child_pays_for_parent() {
+        let (funding, utxos) = fund(2);
+        let parent: Transaction = spend(&funding, 0, 0);
+        let child: Transaction = spend(&parent, 0, 5_000);
+        let other: Transaction = spend(&funding, 1, 2_000);
+        let pending: Vec<Transaction> = vec![parent.clone(), child.clone(), other.clone()];
+
+        let selected: Vec<Transaction> = select_transactions(&pending, &utxos, 1, MAX_BLOCK_SIZE);
+
+        assert_eq!(hashes(&selected), hashes(&[parent, child, other]));
+    }
+
+    #[test]
+    fn included_ancestors_no_longer_count_for_descendants() {
+        let (funding, utxos) = fund(2);
+        let parent: Transaction = spend(&funding, 0, 10_000);
+        let child: Transaction = spend(&parent, 0, 100);
+        let other: Transaction = spend(&funding, 1, 3_000);
+        let pending: Vec<Transaction> = vec![parent.clone(), child.clone(), other.clone()];
+
+        let selected: Vec<Transaction> = select_transactions(&pending, &utxos, 1, MAX_BLOCK_SIZE);
+
+        // with its parent in the block, the child only pays its own low fee
+        assert_eq!(hashes(&selected), hashes(&[parent, other, child]));
+    }
+
+    #[test]
+    fn packages_past_the_size_limit_are_skipped() {
+        let (funding, utxos) = fund(2);
+        let parent: Transaction = spend(&funding, 0, 0);
+        let child: Transaction = spend(&parent, 0, 5_000);
+        let other: Transaction = spend(&funding, 1, 2_000);
+        let pending: Vec<Transaction> = vec![parent.clone(), child, other.clone()];
+
+        // room for a single transaction
+        let max_size: usize = other.size().max(parent.size());
+        let selected: Vec<Transaction> = select_transactions(&pending, &utxos, 1, max_size);
+
+        assert_eq!(hashes(&selected), hashes(&[other]));
+    }
+
+    #[test]
+    fn packages_that_do_not_apply_are_skipped() {
+        let (funding, utxos) = fund(2);
+        let (unknown, _) = fund(1);
+        let orphan: Transaction = spend(&unknown, 0, 9_000);
+        let valid: Transaction = spend(&funding, 0, 1_000);
+        let pending: Vec<Transaction> = vec![orphan, valid.clone()];
+
+        let selected: Vec<Transaction> = select_transactions(&pending, &utxos, 1, MAX_BLOCK_SIZE);
+
+        assert_eq!(hashes(&selected), hashes(&[valid]));
+    }
+}
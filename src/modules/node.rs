@@ -1,77 +1,325 @@
-use database::{BaseDB, NodeDB};
+use std::net::SocketAddr;
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use database::{BaseDB, BlockchainDB, NodeDB};
+use jsonrpsee_server::ServerHandle;
+use serde::{Deserialize, Serialize};
 
 use crate::database;
-use crate::p2p::{start_server, RPCClient};
+use crate::modules::ban::is_node_banned;
+use crate::modules::mempool::mempool;
+use crate::modules::miner::current_timestamp;
+use crate::modules::sync::sync_chain;
+use crate::p2p::{normalize_address, start_server, AddressError, RPCClient};
+
+/// Most nodes kept in the address book
+pub const MAX_PEERS: usize = 256;
+
+/// Number of times a newly added node is relayed from peer to peer
+pub const MAX_ADDR_HOPS: u32 = 3;
+
+/// Most addresses sent in reply to a single `get_peers` request
+pub const MAX_ADDR_RESPONSE: usize = 100;
+
+/// Time between asking peers for the nodes they know
+pub const PEER_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+// time over which the score for a successful contact decays
+const SEEN_DECAY: u64 = 24 * 60 * 60;
+
+// serializes updates of the address book, which rewrite the whole Node database
+static PEERS: Mutex<()> = Mutex::new(());
+
+// lock the address book while reading it and writing it back
+fn peers_lock() -> MutexGuard<'static, ()> {
+    PEERS.lock().expect("Peers lock poisoned")
+}
+
+/// A known node and what is known about reaching it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "StoredPeer")]
+pub struct Peer {
+    // normalized `http://host:port` address
+    pub address: String,
+    // UNIX time in seconds of the last successful contact
+    pub last_seen: Option<u64>,
+    // failed contacts since the last successful one
+    pub failures: u32,
+    // round trip time of the last successful contact in milliseconds
+    pub latency: Option<u64>,
+}
+
+// nodes used to be stored as bare addresses, without metadata
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredPeer {
+    Address(String),
+    Peer {
+        address: String,
+        last_seen: Option<u64>,
+        #[serde(default)]
+        failures: u32,
+        latency: Option<u64>,
+    },
+}
+
+impl From<StoredPeer> for Peer {
+    fn from(stored: StoredPeer) -> Peer {
+        match stored {
+            StoredPeer::Address(address) => Peer::new(address),
+            StoredPeer::Peer {
+                address,
+                last_seen,
+                failures,
+                latency,
+            } => Peer {
+                address,
+                last_seen,
+                failures,
+                latency,
+            },
+        }
+    }
+}
+
+impl Peer {
+    /// A peer that hasn't been contacted yet
+    pub fn new(address: String) -> Peer {
+        Peer {
+            address,
+            last_seen: None,
+            failures: 0,
+            latency: None,
+        }
+    }
+
+    /// Rank of the peer in the address book. Peers that answered recently and quickly rank
+    /// high, peers that keep failing rank low; a peer that hasn't been contacted scores zero.
+    pub fn score(&self, now: u64) -> i64 {
+        let mut score: i64 = -10 * self.failures as i64;
+
+        // up to ten points for a successful contact, decaying over a day
+        if let Some(last_seen) = self.last_seen {
+            let age: u64 = now.saturating_sub(last_seen).min(SEEN_DECAY);
+            score += 10 - (age * 10 / SEEN_DECAY) as i64;
+        }
+
+        // up to five points off for slow peers
+        if let Some(latency) = self.latency {
+            score -= (latency / 200).min(5) as i64;
+        }
+
+        score
+    }
+}
+
+/// Get all known peers from the local database. Addresses are normalized and duplicates
+/// merged; addresses that can't be used are left out.
+pub fn get_peers() -> Vec<Peer> {
+    let mut peers: Vec<Peer> = Vec::new();
+
+    for mut peer in NodeDB::new().find_all::<Peer>() {
+        let Ok(address) = normalize_address(&peer.address) else {
+            continue;
+        };
+        peer.address = address;
 
+        // keep the most recent contact of duplicate entries
+        match peers.iter_mut().find(|known| known.address == peer.address) {
+            Some(known) if peer.last_seen > known.last_seen => *known = peer,
+            Some(_) => {}
+            None => peers.push(peer),
+        }
+    }
+
+    peers
+}
 
+/// Get the addresses of all known nodes from the local database that aren't banned
 pub fn get_nodes() -> Vec<String> {
-    let client: RPCClient = RPCClient::new("http://127.0.0.1:8332".to_string());
+    get_peers()
+        .into_iter()
+        .map(|peer| peer.address)
+        .filter(|address| !is_node_banned(address))
+        .collect()
+}
 
-    println!("About to be pinging...");
-    let result = client
-        .get_transactions(vec![])
-        .expect("Could not ping user");
-    println!("Result: {result:?}");
-    println!("Pinging too..");
+/// Add a node to the local database, unless it is already known or banned. Once the address
+/// book is full, the node replaces the lowest scoring peer if that peer has been failing.
+/// Returns whether the node was added.
+pub fn write_node(address: String) -> Result<bool, AddressError> {
+    let address: String = normalize_address(&address)?;
+
+    let _peers = peers_lock();
+    let mut peers: Vec<Peer> = get_peers();
+
+    if is_node_banned(&address) || peers.iter().any(|peer| peer.address == address) {
+        return Ok(false);
+    }
+
+    if peers.len() >= MAX_PEERS {
+        let now: u64 = current_timestamp();
+        let (worst, score) = peers
+            .iter()
+            .enumerate()
+            .map(|(position, peer)| (position, peer.score(now)))
+            .min_by_key(|(_, score)| *score)
+            .expect("The address book is full");
+
+        // a node we know nothing about isn't better than a peer that works
+        if score >= Peer::new(address.clone()).score(now) {
+            return Ok(false);
+        }
+        peers.remove(worst);
+    }
+
+    peers.push(Peer::new(address));
+
+    // write all nodes to local database
+    NodeDB::new()
+        .write_all(peers)
+        .expect("Couldn't write to Node database");
 
-    vec!["test".to_string()]
+    Ok(true)
 }
 
-/// Add a node to the local database
-pub fn write_node(address: String) {
-    // Initialize local database API
-    let node_db: NodeDB = NodeDB::new();
+/// Get the addresses of the best scoring peers to share with other nodes
+pub fn best_nodes(max: usize) -> Vec<String> {
+    let now: u64 = current_timestamp();
+    let mut peers: Vec<Peer> = get_peers();
 
-    // initialize address for mutability
-    let mut address: String = address;
+    // peers that have been failing since they were last seen aren't worth sharing
+    peers.retain(|peer| peer.failures == 0 && !is_node_banned(&peer.address));
+    peers.sort_by_key(|peer| std::cmp::Reverse(peer.score(now)));
 
-    // define address schema
-    let schema: &str = "http://";
+    peers
+        .into_iter()
+        .take(max)
+        .map(|peer| peer.address)
+        .collect()
+}
+
+/// Record the outcome of contacting the node at `address`: the round trip time of a
+/// successful contact, or `None` for a failed one
+pub fn update_peer(address: &str, latency: Option<Duration>) {
+    let _peers = peers_lock();
+    let mut peers: Vec<Peer> = get_peers();
 
-    // check if address contains a schema
-    if !address.contains(schema) {
-        // append to the beginning of the string
-        address.insert_str(0, schema);
+    let Some(peer) = peers.iter_mut().find(|peer| peer.address == address) else {
+        return;
+    };
+
+    match latency {
+        Some(latency) => {
+            peer.last_seen = Some(current_timestamp());
+            peer.failures = 0;
+            peer.latency = Some(latency.as_millis() as u64);
+        }
+        None => peer.failures += 1,
     }
 
-    // // write all nodes to local database
-    node_db
-        .write(address)
+    NodeDB::new()
+        .write_all(peers)
         .expect("Couldn't write to Node database");
 }
 
-/// Perform all due diligence to make the current node blockchain-ready
-fn init_node() {
-    // collect blockchain from all nodes on the network
+/// Ping the node at `address`, recording the outcome in the local database.
+/// Returns the round trip time if the node answered.
+pub fn ping_peer(address: &str) -> Option<Duration> {
+    let start: Instant = Instant::now();
+    let answered: bool = RPCClient::new(address.to_string())
+        .ok()
+        .and_then(|client| client.ping(vec![]).ok())
+        .unwrap_or(false);
+    let latency: Option<Duration> = answered.then(|| start.elapsed());
 
-    // collect all the transactions from all nodes on the network
+    update_peer(address, latency);
+    latency
+}
 
-    // initialize local Transaction and Blockchain databases
+/// Ask every reachable peer for the nodes it knows and add them to the local database.
+/// `own_address` is the address of the current node, which is never added.
+pub fn discover_peers(own_address: Option<&str>) {
+    for address in get_nodes() {
+        if ping_peer(&address).is_none() {
+            continue;
+        }
 
-    // get blockchain and transactions from local database
+        let Ok(client) = RPCClient::new(address.clone()) else {
+            continue;
+        };
+        let Ok(addresses) = client.get_peers() else {
+            continue;
+        };
 
-    // iterate through the node_blockchains
-    // if there a blockchain downloaded that is longer than what we have locally, replace local.
+        for candidate in addresses.into_iter().take(MAX_ADDR_RESPONSE) {
+            if normalize_address(&candidate).ok().as_deref() == own_address {
+                continue;
+            }
 
-    // iterate through the node_transactions
-    // if there are transactions downloaded that is longer than what we have locally, replace local.
+            if let Ok(true) = write_node(candidate.clone()) {
+                println!("Discovered node {candidate} from {address}");
+            }
+        }
+    }
 }
 
-pub async fn start_node(address: &str) -> Result<(), Box<dyn std::error::Error>> {
-    // create a runtime to facilitate starting the server asynchrounously
-    // let rt = runtime::Runtime::new().expect("Failed to create runtime");
+/// Perform all due diligence to make the current node blockchain-ready.
+/// Downloads the chain with the most work among all known nodes. `own_address` is the
+/// address of the current node, which isn't added to the known nodes.
+fn init_node(own_address: Option<&str>) {
+    // learn about the rest of the network from the known nodes
+    discover_peers(own_address);
+
+    sync_chain(&get_nodes());
 
-    // make the current node blockchain-ready
-    init_node();
+    match BlockchainDB::new().tip() {
+        Some(tip) => println!("Local chain at height {} ({})", tip.index, tip.hash),
+        None => println!("Local chain is empty"),
+    }
+
+    println!("{} pending transaction(s) in the mempool", mempool().len());
+}
+
+/// Sync the local chain with the network and start the RPC server on `address`, telling peers
+/// to reach it at `advertise`. Returns the address the server is bound to, the address it is
+/// advertised at and a handle for stopping it.
+pub async fn start_node(
+    address: &str,
+    advertise: Option<&str>,
+) -> Result<(SocketAddr, Option<String>, ServerHandle), Box<dyn std::error::Error>> {
+    // make the current node blockchain-ready. The server isn't bound yet, so the node is known
+    // by the address it will be advertised at or else the one it will be bound to
+    let own_address: Option<String> = normalize_address(advertise.unwrap_or(address)).ok();
+    init_node(own_address.as_deref());
 
     println!("Node initialization success");
 
-    // check if schema exists or add schema to address
+    // start the local RPC server
+    let (local_address, advertised, handle) = start_server(address, advertise).await?;
 
-    // thread the local RPC server
+    // keep asking peers for more nodes while the node runs
+    let own_address: Option<String> = advertised.clone();
+    thread::spawn(move || loop {
+        thread::sleep(PEER_POLL_INTERVAL);
+        discover_peers(own_address.as_deref());
+    });
 
-    // run the async funciton inside the runtime
-    start_server(address).await?;
+    Ok((local_address, advertised, handle))
+}
+
+/// Serve until the server stops or the process is interrupted with Ctrl-C, then persist the
+/// mempool for the next start
+pub async fn wait_for_shutdown(handle: ServerHandle) {
+    tokio::select! {
+        _ = handle.clone().stopped() => {}
+        _ = tokio::signal::ctrl_c() => {
+            println!("Shutting down");
+            let _ = handle.stop();
+        }
+    }
 
-    Ok(())
+    mempool().save();
 }
@@ -0,0 +1,986 @@
+use std::fmt;
+
+use secp256k1::ecdsa::Signature;
+use secp256k1::{Message, PublicKey, Secp256k1};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+
+use super::account::{address_to_pubkey_hash, pubkey_hash_to_address};
+use super::hashing::{hash160, sha256d, HASH_SIZE};
+
+/// Most bytes of a script that can be run
+pub const MAX_SCRIPT_SIZE: usize = 10_000;
+
+/// Most bytes a single instruction can push
+pub const MAX_SCRIPT_ELEMENT_SIZE: usize = 520;
+
+/// Most operations other than pushes a script can contain
+pub const MAX_OPS_PER_SCRIPT: usize = 201;
+
+/// Most items on the stack at any time
+pub const MAX_STACK_SIZE: usize = 1000;
+
+// most bytes of a number operand; results of arithmetic may be longer
+const MAX_NUM_SIZE: usize = 4;
+
+// push value
+pub const OP_0: u8 = 0x00;
+pub const OP_PUSHDATA1: u8 = 0x4c;
+pub const OP_PUSHDATA2: u8 = 0x4d;
+pub const OP_PUSHDATA4: u8 = 0x4e;
+pub const OP_1NEGATE: u8 = 0x4f;
+pub const OP_1: u8 = 0x51;
+pub const OP_16: u8 = 0x60;
+
+// flow control
+pub const OP_NOP: u8 = 0x61;
+pub const OP_IF: u8 = 0x63;
+pub const OP_NOTIF: u8 = 0x64;
+pub const OP_ELSE: u8 = 0x67;
+pub const OP_ENDIF: u8 = 0x68;
+pub const OP_VERIFY: u8 = 0x69;
+pub const OP_RETURN: u8 = 0x6a;
+
+// stack
+pub const OP_DEPTH: u8 = 0x74;
+pub const OP_DROP: u8 = 0x75;
+pub const OP_DUP: u8 = 0x76;
+pub const OP_NIP: u8 = 0x77;
+pub const OP_OVER: u8 = 0x78;
+pub const OP_SWAP: u8 = 0x7c;
+pub const OP_SIZE: u8 = 0x82;
+
+// bitwise logic
+pub const OP_EQUAL: u8 = 0x87;
+pub const OP_EQUALVERIFY: u8 = 0x88;
+
+// arithmetic
+pub const OP_1ADD: u8 = 0x8b;
+pub const OP_1SUB: u8 = 0x8c;
+pub const OP_NEGATE: u8 = 0x8f;
+pub const OP_ABS: u8 = 0x90;
+pub const OP_NOT: u8 = 0x91;
+pub const OP_0NOTEQUAL: u8 = 0x92;
+pub const OP_ADD: u8 = 0x93;
+pub const OP_SUB: u8 = 0x94;
+pub const OP_BOOLAND: u8 = 0x9a;
+pub const OP_BOOLOR: u8 = 0x9b;
+pub const OP_NUMEQUAL: u8 = 0x9c;
+pub const OP_NUMEQUALVERIFY: u8 = 0x9d;
+pub const OP_NUMNOTEQUAL: u8 = 0x9e;
+pub const OP_LESSTHAN: u8 = 0x9f;
+pub const OP_GREATERTHAN: u8 = 0xa0;
+pub const OP_LESSTHANOREQUAL: u8 = 0xa1;
+pub const OP_GREATERTHANOREQUAL: u8 = 0xa2;
+pub const OP_MIN: u8 = 0xa3;
+pub const OP_MAX: u8 = 0xa4;
+pub const OP_WITHIN: u8 = 0xa5;
+
+// crypto
+pub const OP_SHA256: u8 = 0xa8;
+pub const OP_HASH160: u8 = 0xa9;
+pub const OP_HASH256: u8 = 0xaa;
+pub const OP_CHECKSIG: u8 = 0xac;
+pub const OP_CHECKSIGVERIFY: u8 = 0xad;
+
+// names of the supported operations, as shown in disassembled scripts
+const OPCODE_NAMES: &[(u8, &str)] = &[
+    (OP_1NEGATE, "OP_1NEGATE"),
+    (OP_NOP, "OP_NOP"),
+    (OP_IF, "OP_IF"),
+    (OP_NOTIF, "OP_NOTIF"),
+    (OP_ELSE, "OP_ELSE"),
+    (OP_ENDIF, "OP_ENDIF"),
+    (OP_VERIFY, "OP_VERIFY"),
+    (OP_RETURN, "OP_RETURN"),
+    (OP_DEPTH, "OP_DEPTH"),
+    (OP_DROP, "OP_DROP"),
+    (OP_DUP, "OP_DUP"),
+    (OP_NIP, "OP_NIP"),
+    (OP_OVER, "OP_OVER"),
+    (OP_SWAP, "OP_SWAP"),
+    (OP_SIZE, "OP_SIZE"),
+    (OP_EQUAL, "OP_EQUAL"),
+    (OP_EQUALVERIFY, "OP_EQUALVERIFY"),
+    (OP_1ADD, "OP_1ADD"),
+    (OP_1SUB, "OP_1SUB"),
+    (OP_NEGATE, "OP_NEGATE"),
+    (OP_ABS, "OP_ABS"),
+    (OP_NOT, "OP_NOT"),
+    (OP_0NOTEQUAL, "OP_0NOTEQUAL"),
+    (OP_ADD, "OP_ADD"),
+    (OP_SUB, "OP_SUB"),
+    (OP_BOOLAND, "OP_BOOLAND"),
+    (OP_BOOLOR, "OP_BOOLOR"),
+    (OP_NUMEQUAL, "OP_NUMEQUAL"),
+    (OP_NUMEQUALVERIFY, "OP_NUMEQUALVERIFY"),
+    (OP_NUMNOTEQUAL, "OP_NUMNOTEQUAL"),
+    (OP_LESSTHAN, "OP_LESSTHAN"),
+    (OP_GREATERTHAN, "OP_GREATERTHAN"),
+    (OP_LESSTHANOREQUAL, "OP_LESSTHANOREQUAL"),
+    (OP_GREATERTHANOREQUAL, "OP_GREATERTHANOREQUAL"),
+    (OP_MIN, "OP_MIN"),
+    (OP_MAX, "OP_MAX"),
+    (OP_WITHIN, "OP_WITHIN"),
+    (OP_SHA256, "OP_SHA256"),
+    (OP_HASH160, "OP_HASH160"),
+    (OP_HASH256, "OP_HASH256"),
+    (OP_CHECKSIG, "OP_CHECKSIG"),
+    (OP_CHECKSIGVERIFY, "OP_CHECKSIGVERIFY"),
+];
+
+/// A locking or unlocking script, stored and sent hex encoded
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Script(Vec<u8>);
+
+/// A single instruction of a script
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction<'a> {
+    // push of data onto the stack, including the empty push of `OP_0`
+    Push(&'a [u8]),
+    // any other operation
+    Op(u8),
+}
+
+// iterator over the instructions of a script, ending after the first malformed one
+struct Instructions<'a> {
+    script: &'a [u8],
+    position: usize,
+}
+
+/// Checks signatures against the hash of the transaction being spent
+pub struct SignatureChecker {
+    signature_hash: [u8; HASH_SIZE],
+}
+
+/// Reasons a script fails
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScriptError {
+    // the script is longer than `MAX_SCRIPT_SIZE`
+    ScriptSize,
+    // an instruction pushes more than `MAX_SCRIPT_ELEMENT_SIZE` bytes
+    PushSize,
+    // the script has more than `MAX_OPS_PER_SCRIPT` operations
+    OpCount,
+    // the stack grew past `MAX_STACK_SIZE` items
+    StackSize,
+    // a push runs past the end of the script
+    TruncatedPush,
+    // the operation is unknown or not supported
+    BadOpcode(u8),
+    // an operation needs more items than are on the stack
+    InvalidStackOperation,
+    // an `OP_ELSE` or `OP_ENDIF` without `OP_IF`, or an `OP_IF` without `OP_ENDIF`
+    UnbalancedConditional,
+    // a number operand is longer than 4 bytes
+    NumberOverflow,
+    // `OP_RETURN` was executed
+    OpReturn,
+    // a `*VERIFY` operation found a false value
+    Verify(u8),
+    // the unlocking script does more than push data
+    SigPushOnly,
+    // the scripts left an empty stack or false on top of it
+    EvalFalse,
+}
+
+impl Script {
+    pub fn new() -> Script {
+        Script::default()
+    }
+
+    /// Append an operation
+    pub fn push_opcode(mut self, opcode: u8) -> Script {
+        self.0.push(opcode);
+        self
+    }
+
+    /// Append a push of `data`, using the shortest push instruction that can hold it
+    pub fn push_slice(mut self, data: &[u8]) -> Script {
+        match data.len() {
+            length @ 0..=0x4b => self.0.push(length as u8),
+            length @ 0x4c..=0xff => self.0.extend([OP_PUSHDATA1, length as u8]),
+            length @ 0x100..=0xffff => {
+                self.0.push(OP_PUSHDATA2);
+                self.0.extend_from_slice(&(length as u16).to_le_bytes());
+            }
+            length => {
+                self.0.push(OP_PUSHDATA4);
+                self.0.extend_from_slice(&(length as u32).to_le_bytes());
+            }
+        }
+
+        self.0.extend_from_slice(data);
+        self
+    }
+
+    /// Pay-to-PubKey-Hash locking script, spendable with a signature and the public key
+    /// whose HASH160 is `pubkey_hash`:
+    /// `OP_DUP OP_HASH160 <pubkey_hash> OP_EQUALVERIFY OP_CHECKSIG`
+    pub fn p2pkh(pubkey_hash: &[u8; 20]) -> Script {
+        Script::new()
+            .push_opcode(OP_DUP)
+            .push_opcode(OP_HASH160)
+            .push_slice(pubkey_hash)
+            .push_opcode(OP_EQUALVERIFY)
+            .push_opcode(OP_CHECKSIG)
+    }
+
+    /// P2PKH locking script paying the Base58Check `address`, or `None` if it is malformed
+    pub fn for_address(address: &str) -> Option<Script> {
+        address_to_pubkey_hash(address).map(|pubkey_hash| Script::p2pkh(&pubkey_hash))
+    }
+
+    /// Address paid by a P2PKH locking script, or `None` for any other script
+    pub fn address(&self) -> Option<String> {
+        match &self.0[..] {
+            [OP_DUP, OP_HASH160, 20, pubkey_hash @ .., OP_EQUALVERIFY, OP_CHECKSIG] => {
+                Some(pubkey_hash_to_address(pubkey_hash.try_into().ok()?))
+            }
+            _ => None,
+        }
+    }
+
+    /// Check if outputs locked by the script can never be spent, so they can be left out
+    /// of the UTXO set
+    pub fn is_unspendable(&self) -> bool {
+        self.0.first() == Some(&OP_RETURN) || self.0.len() > MAX_SCRIPT_SIZE
+    }
+
+    /// Check if every instruction of the script is a well formed push
+    pub fn is_push_only(&self) -> bool {
+        self.instructions().all(|instruction| match instruction {
+            Ok(Instruction::Push(_)) => true,
+            Ok(Instruction::Op(opcode)) => opcode <= OP_16,
+            Err(_) => false,
+        })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Iterate the instructions of the script. A malformed push ends the iteration with an error.
+    pub fn instructions(&self) -> impl Iterator<Item = Result<Instruction<'_>, ScriptError>> + '_ {
+        Instructions {
+            script: &self.0,
+            position: 0,
+        }
+    }
+}
+
+impl<'a> Instructions<'a> {
+    // read the little endian length of a push from the next `size` bytes
+    fn read_length(&mut self, size: usize) -> Option<usize> {
+        let bytes: &[u8] = self.script.get(self.position..self.position + size)?;
+        self.position += size;
+
+        Some(
+            bytes
+                .iter()
+                .rev()
+                .fold(0, |length, byte| (length << 8) | *byte as usize),
+        )
+    }
+}
+
+impl<'a> Iterator for Instructions<'a> {
+    type Item = Result<Instruction<'a>, ScriptError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let opcode: u8 = *self.script.get(self.position)?;
+        self.position += 1;
+
+        let length: Option<usize> = match opcode {
+            // the opcode is the number of bytes pushed
+            OP_0..=0x4b => Some(opcode as usize),
+            OP_PUSHDATA1 => self.read_length(1),
+            OP_PUSHDATA2 => self.read_length(2),
+            OP_PUSHDATA4 => self.read_length(4),
+            _ => return Some(Ok(Instruction::Op(opcode))),
+        };
+
+        let data: Option<&[u8]> =
+            length.and_then(|length| self.script.get(self.position..self.position + length));
+
+        match data {
+            Some(data) => {
+                self.position += data.len();
+                Some(Ok(Instruction::Push(data)))
+            }
+            None => {
+                // nothing after a malformed push can be read
+                self.position = self.script.len();
+                Some(Err(ScriptError::TruncatedPush))
+            }
+        }
+    }
+}
+
+impl SignatureChecker {
+    pub fn new(signature_hash: [u8; HASH_SIZE]) -> SignatureChecker {
+        SignatureChecker { signature_hash }
+    }
+
+    /// Check that `signature` is a DER encoded ECDSA signature of the transaction by the owner
+    /// of the SEC1 encoded `pubkey`
+    fn check(&self, signature: &[u8], pubkey: &[u8]) -> bool {
+        let (Ok(signature), Ok(public_key)) = (
+            Signature::from_der(signature),
+            PublicKey::from_slice(pubkey),
+        ) else {
+            return false;
+        };
+
+        Secp256k1::verification_only()
+            .verify_ecdsa(
+                &Message::from_digest(self.signature_hash),
+                &signature,
+                &public_key,
+            )
+            .is_ok()
+    }
+}
+
+/// Run `script_sig`, the unlocking script of an input, and then `script_pubkey`, the locking
+/// script of the output it spends, on the same stack. The input may spend the output if both
+/// scripts run to the end and leave a true value on top of the stack.
+pub fn verify_script(
+    script_sig: &Script,
+    script_pubkey: &Script,
+    checker: &SignatureChecker,
+) -> Result<(), ScriptError> {
+    // an unlocking script that only pushes data can't change what the locking script does
+    if !script_sig.is_push_only() {
+        return Err(ScriptError::SigPushOnly);
+    }
+
+    let mut stack: Vec<Vec<u8>> = Vec::new();
+    eval_script(&mut stack, script_sig, checker)?;
+    eval_script(&mut stack, script_pubkey, checker)?;
+
+    match stack.last() {
+        Some(top) if cast_to_bool(top) => Ok(()),
+        _ => Err(ScriptError::EvalFalse),
+    }
+}
+
+/// Run `script` on `stack`, within the limits on script size, operations and stack size
+pub fn eval_script(
+    stack: &mut Vec<Vec<u8>>,
+    script: &Script,
+    checker: &SignatureChecker,
+) -> Result<(), ScriptError> {
+    if script.0.len() > MAX_SCRIPT_SIZE {
+        return Err(ScriptError::ScriptSize);
+    }
+
+    // whether the branch of every enclosing `OP_IF` is taken
+    let mut conditions: Vec<bool> = Vec::new();
+    let mut op_count: usize = 0;
+
+    for instruction in script.instructions() {
+        let executing: bool = conditions.iter().all(|taken| *taken);
+
+        match instruction? {
+            Instruction::Push(data) => {
+                if data.len() > MAX_SCRIPT_ELEMENT_SIZE {
+                    return Err(ScriptError::PushSize);
+                }
+                if executing {
+                    stack.push(data.to_vec());
+                }
+            }
+            Instruction::Op(opcode) => {
+                // pushing small numbers is free, every other operation counts even if skipped
+                if opcode > OP_16 {
+                    op_count += 1;
+                    if op_count > MAX_OPS_PER_SCRIPT {
+                        return Err(ScriptError::OpCount);
+                    }
+                }
+
+                // only flow control runs in branches that aren't taken
+                if executing || (OP_IF..=OP_ENDIF).contains(&opcode) {
+                    execute(opcode, stack, &mut conditions, executing, checker)?;
+                }
+            }
+        }
+
+        if stack.len() > MAX_STACK_SIZE {
+            return Err(ScriptError::StackSize);
+        }
+    }
+
+    if !conditions.is_empty() {
+        return Err(ScriptError::UnbalancedConditional);
+    }
+
+    Ok(())
+}
+
+// run a single operation
+fn execute(
+    opcode: u8,
+    stack: &mut Vec<Vec<u8>>,
+    conditions: &mut Vec<bool>,
+    executing: bool,
+    checker: &SignatureChecker,
+) -> Result<(), ScriptError> {
+    match opcode {
+        OP_1NEGATE => stack.push(encode_num(-1)),
+        OP_1..=OP_16 => stack.push(encode_num((opcode - OP_1 + 1) as i64)),
+
+        // flow control
+        OP_NOP => {}
+        OP_IF | OP_NOTIF => {
+            // branches within a branch that isn't taken aren't taken either
+            let taken: bool = executing && (cast_to_bool(&pop(stack)?) == (opcode == OP_IF));
+            conditions.push(taken);
+        }
+        OP_ELSE => {
+            let taken: &mut bool = conditions
+                .last_mut()
+                .ok_or(ScriptError::UnbalancedConditional)?;
+            *taken = !*taken;
+        }
+        OP_ENDIF => {
+            conditions.pop().ok_or(ScriptError::UnbalancedConditional)?;
+        }
+        OP_VERIFY => verify(opcode, cast_to_bool(&pop(stack)?))?,
+        OP_RETURN => return Err(ScriptError::OpReturn),
+
+        // stack
+        OP_DEPTH => stack.push(encode_num(stack.len() as i64)),
+        OP_DROP => {
+            pop(stack)?;
+        }
+        OP_DUP => stack.push(peek(stack, 0)?.to_vec()),
+        OP_NIP => {
+            let top: Vec<u8> = pop(stack)?;
+            pop(stack)?;
+            stack.push(top);
+        }
+        OP_OVER => stack.push(peek(stack, 1)?.to_vec()),
+        OP_SWAP => {
+            peek(stack, 1)?;
+            let length: usize = stack.len();
+            stack.swap(length - 1, length - 2);
+        }
+        OP_SIZE => stack.push(encode_num(peek(stack, 0)?.len() as i64)),
+
+        // bitwise logic
+        OP_EQUAL | OP_EQUALVERIFY => {
+            let equal: bool = pop(stack)? == pop(stack)?;
+            if opcode == OP_EQUALVERIFY {
+                verify(opcode, equal)?;
+            } else {
+                stack.push(encode_num(equal as i64));
+            }
+        }
+
+        // arithmetic
+        OP_1ADD | OP_1SUB | OP_NEGATE | OP_ABS | OP_NOT | OP_0NOTEQUAL => {
+            let a: i64 = pop_num(stack)?;
+            let result: i64 = match opcode {
+                OP_1ADD => a + 1,
+                OP_1SUB => a - 1,
+                OP_NEGATE => -a,
+                OP_ABS => a.abs(),
+                OP_NOT => (a == 0) as i64,
+                _ => (a != 0) as i64,
+            };
+            stack.push(encode_num(result));
+        }
+        OP_ADD
+        | OP_SUB
+        | OP_BOOLAND
+        | OP_BOOLOR
+        | OP_NUMEQUAL
+        | OP_NUMEQUALVERIFY
+        | OP_NUMNOTEQUAL
+        | OP_LESSTHAN
+        | OP_GREATERTHAN
+        | OP_LESSTHANOREQUAL
+        | OP_GREATERTHANOREQUAL
+        | OP_MIN
+        | OP_MAX => {
+            let b: i64 = pop_num(stack)?;
+            let a: i64 = pop_num(stack)?;
+            let result: i64 = match opcode {
+                OP_ADD => a + b,
+                OP_SUB => a - b,
+                OP_BOOLAND => (a != 0 && b != 0) as i64,
+                OP_BOOLOR => (a != 0 || b != 0) as i64,
+                OP_NUMEQUAL | OP_NUMEQUALVERIFY => (a == b) as i64,
+                OP_NUMNOTEQUAL => (a != b) as i64,
+                OP_LESSTHAN => (a < b) as i64,
+                OP_GREATERTHAN => (a > b) as i64,
+                OP_LESSTHANOREQUAL => (a <= b) as i64,
+                OP_GREATERTHANOREQUAL => (a >= b) as i64,
+                OP_MIN => a.min(b),
+                _ => a.max(b),
+            };
+
+            if opcode == OP_NUMEQUALVERIFY {
+                verify(opcode, result != 0)?;
+            } else {
+                stack.push(encode_num(result));
+            }
+        }
+        OP_WITHIN => {
+            let max: i64 = pop_num(stack)?;
+            let min: i64 = pop_num(stack)?;
+            let x: i64 = pop_num(stack)?;
+            stack.push(encode_num((min <= x && x < max) as i64));
+        }
+
+        // crypto
+        OP_SHA256 => {
+            let data: Vec<u8> = pop(stack)?;
+            stack.push(Sha256::digest(data).to_vec());
+        }
+        OP_HASH160 => {
+            let data: Vec<u8> = pop(stack)?;
+            stack.push(hash160(&data).to_vec());
+        }
+        OP_HASH256 => {
+            let data: Vec<u8> = pop(stack)?;
+            stack.push(sha256d(&data).to_vec());
+        }
+        OP_CHECKSIG | OP_CHECKSIGVERIFY => {
+            let pubkey: Vec<u8> = pop(stack)?;
+            let signature: Vec<u8> = pop(stack)?;
+            let valid: bool = checker.check(&signature, &pubkey);
+
+            if opcode == OP_CHECKSIGVERIFY {
+                verify(opcode, valid)?;
+            } else {
+                stack.push(encode_num(valid as i64));
+            }
+        }
+
+        _ => return Err(ScriptError::BadOpcode(opcode)),
+    }
+
+    Ok(())
+}
+
+// fail the `*VERIFY` operation `opcode` unless `condition` holds
+fn verify(opcode: u8, condition: bool) -> Result<(), ScriptError> {
+    if condition {
+        Ok(())
+    } else {
+        Err(ScriptError::Verify(opcode))
+    }
+}
+
+// take the top item off the stack
+fn pop(stack: &mut Vec<Vec<u8>>) -> Result<Vec<u8>, ScriptError> {
+    stack.pop().ok_or(ScriptError::InvalidStackOperation)
+}
+
+// look at the item `depth` places below the top of the stack
+fn peek(stack: &[Vec<u8>], depth: usize) -> Result<&[u8], ScriptError> {
+    stack
+        .len()
+        .checked_sub(depth + 1)
+        .map(|index| stack[index].as_slice())
+        .ok_or(ScriptError::InvalidStackOperation)
+}
+
+// take the top item off the stack as a number
+fn pop_num(stack: &mut Vec<Vec<u8>>) -> Result<i64, ScriptError> {
+    decode_num(&pop(stack)?)
+}
+
+/// Encode a number the way scripts store them: little endian magnitude, with the sign in the
+/// highest bit of the last byte. Zero is the empty array.
+pub fn encode_num(n: i64) -> Vec<u8> {
+    let mut bytes: Vec<u8> = Vec::new();
+    let mut magnitude: u64 = n.unsigned_abs();
+
+    while magnitude > 0 {
+        bytes.push(magnitude as u8);
+        magnitude >>= 8;
+    }
+
+    // add a byte for the sign if the highest bit is taken
+    match bytes.last_mut() {
+        Some(last) if *last & 0x80 != 0 => bytes.push(if n < 0 { 0x80 } else { 0x00 }),
+        Some(last) if n < 0 => *last |= 0x80,
+        _ => {}
+    }
+
+    bytes
+}
+
+/// Decode a number operand of at most 4 bytes
+pub fn decode_num(bytes: &[u8]) -> Result<i64, ScriptError> {
+    if bytes.len() > MAX_NUM_SIZE {
+        return Err(ScriptError::NumberOverflow);
+    }
+
+    let Some((last, _)) = bytes.split_last() else {
+        return Ok(0);
+    };
+
+    let magnitude: i64 = bytes
+        .iter()
+        .rev()
+        .fold(0, |n, byte| (n << 8) | *byte as i64)
+        & !(0x80 << (8 * (bytes.len() - 1)));
+
+    if last & 0x80 != 0 {
+        Ok(-magnitude)
+    } else {
+        Ok(magnitude)
+    }
+}
+
+/// Interpret a stack item as a boolean: false if every byte is zero, or if only the sign bit
+/// of the last byte is set (negative zero)
+pub fn cast_to_bool(bytes: &[u8]) -> bool {
+    match bytes.split_last() {
+        Some((last, rest)) => rest.iter().any(|byte| *byte != 0) || (*last & 0x7f) != 0,
+        None => false,
+    }
+}
+
+impl Serialize for Script {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(&self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for Script {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let encoded: String = String::deserialize(deserializer)?;
+        hex::decode(encoded)
+            .map(Script)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Disassemble the script, showing pushes as hex and operations by name,
+/// such as `OP_DUP OP_HASH160 <hex> OP_EQUALVERIFY OP_CHECKSIG`
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, instruction) in self.instructions().enumerate() {
+            if index > 0 {
+                write!(f, " ")?;
+            }
+
+            match instruction {
+                Ok(Instruction::Push([])) => write!(f, "0")?,
+                Ok(Instruction::Push(data)) => write!(f, "{}", hex::encode(data))?,
+                Ok(Instruction::Op(opcode @ OP_1..=OP_16)) => write!(f, "{}", opcode - OP_1 + 1)?,
+                Ok(Instruction::Op(opcode)) => {
+                    match OPCODE_NAMES.iter().find(|(known, _)| *known == opcode) {
+                        Some((_, name)) => write!(f, "{name}")?,
+                        None => write!(f, "OP_UNKNOWN({opcode:#04x})")?,
+                    }
+                }
+                Err(_) => write!(f, "[error]")?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScriptError::ScriptSize => {
+                write!(f, "script is longer than {MAX_SCRIPT_SIZE} bytes")
+            }
+            ScriptError::PushSize => {
+                write!(f, "push is longer than {MAX_SCRIPT_ELEMENT_SIZE} bytes")
+            }
+            ScriptError::OpCount => {
+                write!(f, "script has more than {MAX_OPS_PER_SCRIPT} operations")
+            }
+            ScriptError::StackSize => write!(f, "stack has more than {MAX_STACK_SIZE} items"),
+            ScriptError::TruncatedPush => write!(f, "push runs past the end of the script"),
+            ScriptError::BadOpcode(opcode) => write!(f, "unsupported opcode {opcode:#04x}"),
+            ScriptError::InvalidStackOperation => write!(f, "not enough items on the stack"),
+            ScriptError::UnbalancedConditional => write!(f, "unbalanced conditional"),
+            ScriptError::NumberOverflow => {
+                write!(f, "number operand is longer than {MAX_NUM_SIZE} bytes")
+            }
+            ScriptError::OpReturn => write!(f, "OP_RETURN was executed"),
+            ScriptError::Verify(opcode) => {
+                let name: &str = OPCODE_NAMES
+                    .iter()
+                    .find(|(known, _)| known == opcode)
+                    .map_or("verify", |(_, name)| name);
+                write!(f, "{name} failed")
+            }
+            ScriptError::SigPushOnly => write!(f, "unlocking script is not push only"),
+            ScriptError::EvalFalse => write!(f, "script evaluated to false"),
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+#[cfg(test)]
+mod tests {
+    use secp256k1::SecretKey;
+
+    use super::*;
+
+    // a key pair derived from `seed`, with the SEC1 encoded public key
+    fn keys(seed: u8) -> (SecretKey, Vec<u8>) {
+        let secret_key: SecretKey = SecretKey::from_slice(&[seed; 32]).unwrap();
+        let public_key: PublicKey = PublicKey::from_secret_key(&Secp256k1::new(), &secret_key);
+
+        (secret_key, public_key.serialize().to_vec())
+    }
+
+    // DER encoded signature of `signature_hash` by `secret_key`
+    fn sign(secret_key: &SecretKey, signature_hash: [u8; HASH_SIZE]) -> Vec<u8> {
+        Secp256k1::signing_only()
+            .sign_ecdsa(&Message::from_digest(signature_hash), secret_key)
+            .serialize_der()
+            .to_vec()
+    }
+
+    fn checker() -> SignatureChecker {
+        SignatureChecker::new(sha256d(b"transaction"))
+    }
+
+    // run a locking script on its own
+    fn run(script_pubkey: &Script) -> Result<(), ScriptError> {
+        verify_script(&Script::new(), script_pubkey, &checker())
+    }
+
+    // a script of `count` repetitions of `opcode`
+    fn repeat(opcode: u8, count: usize) -> Script {
+        (0..count).fold(Script::new(), |script, _| script.push_opcode(opcode))
+    }
+
+    fn p2pkh_spend(signer: &SecretKey, pubkey: &[u8], owner: &[u8]) -> Result<(), ScriptError> {
+        let script_sig: Script = Script::new()
+            .push_slice(&sign(signer, sha256d(b"transaction")))
+            .push_slice(pubkey);
+        let script_pubkey: Script = Script::p2pkh(&hash160(owner));
+
+        verify_script(&script_sig, &script_pubkey, &checker())
+    }
+
+    #[test]
+    fn p2pkh_spend_is_valid() {
+        let (secret_key, pubkey) = keys(1);
+
+        assert_eq!(p2pkh_spend(&secret_key, &pubkey, &pubkey), Ok(()));
+    }
+
+    #[test]
+    fn p2pkh_spend_with_another_key_fails() {
+        let (owner_key, owner) = keys(1);
+        let (other_key, other) = keys(2);
+
+        // signed by another key for the owner's public key
+        assert_eq!(
+            p2pkh_spend(&other_key, &owner, &owner),
+            Err(ScriptError::EvalFalse)
+        );
+
+        // a public key that doesn't match the hash in the locking script
+        assert_eq!(
+            p2pkh_spend(&other_key, &other, &owner),
+            Err(ScriptError::Verify(OP_EQUALVERIFY))
+        );
+        assert_eq!(
+            p2pkh_spend(&owner_key, &other, &owner),
+            Err(ScriptError::Verify(OP_EQUALVERIFY))
+        );
+    }
+
+    #[test]
+    fn signature_of_another_transaction_fails() {
+        let (secret_key, pubkey) = keys(1);
+        let script_sig: Script = Script::new()
+            .push_slice(&sign(&secret_key, sha256d(b"another transaction")))
+            .push_slice(&pubkey);
+        let script_pubkey: Script = Script::p2pkh(&hash160(&pubkey));
+
+        assert_eq!(
+            verify_script(&script_sig, &script_pubkey, &checker()),
+            Err(ScriptError::EvalFalse)
+        );
+    }
+
+    #[test]
+    fn script_sig_must_only_push() {
+        let (secret_key, pubkey) = keys(1);
+        let script_pubkey: Script = Script::p2pkh(&hash160(&pubkey));
+
+        // a valid spend with an operation added to the unlocking script
+        let script_sig: Script = Script::new()
+            .push_slice(&sign(&secret_key, sha256d(b"transaction")))
+            .push_slice(&pubkey)
+            .push_opcode(OP_NOP);
+
+        assert_eq!(
+            verify_script(&script_sig, &script_pubkey, &checker()),
+            Err(ScriptError::SigPushOnly)
+        );
+
+        // small numbers are pushes
+        let script_sig: Script = Script::new().push_opcode(OP_1NEGATE).push_opcode(OP_16);
+        assert!(script_sig.is_push_only());
+    }
+
+    #[test]
+    fn operation_count_is_limited() {
+        let within: Script = repeat(OP_NOP, MAX_OPS_PER_SCRIPT).push_opcode(OP_1);
+        assert_eq!(run(&within), Ok(()));
+
+        let past: Script = repeat(OP_NOP, MAX_OPS_PER_SCRIPT + 1).push_opcode(OP_1);
+        assert_eq!(run(&past), Err(ScriptError::OpCount));
+
+        // operations in branches that aren't taken count as well
+        let skipped: Script = Script(
+            [
+                &[OP_0, OP_IF][..],
+                repeat(OP_NOP, MAX_OPS_PER_SCRIPT).as_bytes(),
+                &[OP_ENDIF, OP_1],
+            ]
+            .concat(),
+        );
+        assert_eq!(run(&skipped), Err(ScriptError::OpCount));
+    }
+
+    #[test]
+    fn stack_size_is_limited() {
+        assert_eq!(run(&repeat(OP_1, MAX_STACK_SIZE)), Ok(()));
+        assert_eq!(
+            run(&repeat(OP_1, MAX_STACK_SIZE + 1)),
+            Err(ScriptError::StackSize)
+        );
+    }
+
+    #[test]
+    fn push_size_is_limited() {
+        let within: Script = Script::new().push_slice(&[1; MAX_SCRIPT_ELEMENT_SIZE]);
+        assert_eq!(run(&within), Ok(()));
+
+        let past: Script = Script::new().push_slice(&[1; MAX_SCRIPT_ELEMENT_SIZE + 1]);
+        assert_eq!(run(&past), Err(ScriptError::PushSize));
+    }
+
+    #[test]
+    fn script_size_is_limited() {
+        let past: Script = repeat(OP_1, MAX_SCRIPT_SIZE + 1);
+
+        assert_eq!(run(&past), Err(ScriptError::ScriptSize));
+        assert!(past.is_unspendable());
+    }
+
+    #[test]
+    fn conditionals_must_balance() {
+        for script in [
+            vec![OP_1, OP_IF, OP_1],
+            vec![OP_1, OP_ENDIF],
+            vec![OP_1, OP_ELSE, OP_1],
+            vec![OP_1, OP_IF, OP_1, OP_ENDIF, OP_ENDIF],
+            vec![OP_0, OP_NOTIF, OP_1, OP_IF, OP_1, OP_ENDIF],
+        ] {
+            assert_eq!(
+                run(&Script(script.clone())),
+                Err(ScriptError::UnbalancedConditional),
+                "{}",
+                Script(script)
+            );
+        }
+    }
+
+    #[test]
+    fn conditionals_take_one_branch() {
+        let branches = |condition: u8| {
+            Script(vec![
+                condition, OP_IF, OP_16, OP_ELSE, OP_1, OP_ENDIF, OP_16, OP_EQUAL,
+            ])
+        };
+
+        assert_eq!(run(&branches(OP_1)), Ok(()));
+        assert_eq!(run(&branches(OP_0)), Err(ScriptError::EvalFalse));
+
+        // nested branches of a branch that isn't taken aren't taken either
+        let nested: Script = Script(vec![
+            OP_0, OP_IF, OP_1, OP_IF, OP_RETURN, OP_ENDIF, OP_ELSE, OP_1, OP_ENDIF,
+        ]);
+        assert_eq!(run(&nested), Ok(()));
+    }
+
+    #[test]
+    fn op_return_fails() {
+        let script_pubkey: Script = Script::new().push_opcode(OP_RETURN).push_slice(b"data");
+
+        assert_eq!(run(&script_pubkey), Err(ScriptError::OpReturn));
+        assert!(script_pubkey.is_unspendable());
+
+        // unless it is in a branch that isn't taken
+        let skipped: Script = Script(vec![OP_0, OP_IF, OP_RETURN, OP_ENDIF, OP_1]);
+        assert_eq!(run(&skipped), Ok(()));
+        assert!(!skipped.is_unspendable());
+    }
+
+    #[test]
+    fn numbers_round_trip() {
+        for n in [
+            0,
+            1,
+            -1,
+            16,
+            127,
+            -127,
+            128,
+            -128,
+            255,
+            256,
+            -256,
+            32767,
+            32768,
+            -32768,
+            0x7f_ffff,
+            0x80_0000,
+            0x7fff_ffff,
+            -0x7fff_ffff,
+        ] {
+            assert_eq!(decode_num(&encode_num(n)), Ok(n), "{n}");
+        }
+    }
+
+    #[test]
+    fn numbers_use_the_minimal_encoding() {
+        assert_eq!(encode_num(0), Vec::<u8>::new());
+        assert_eq!(encode_num(1), vec![0x01]);
+        assert_eq!(encode_num(-1), vec![0x81]);
+        assert_eq!(encode_num(127), vec![0x7f]);
+        assert_eq!(encode_num(128), vec![0x80, 0x00]);
+        assert_eq!(encode_num(-128), vec![0x80, 0x80]);
+        assert_eq!(encode_num(256), vec![0x00, 0x01]);
+    }
+
+    #[test]
+    fn number_operands_are_limited_to_four_bytes() {
+        // negative zero
+        assert_eq!(decode_num(&[0x80]), Ok(0));
+        assert_eq!(decode_num(&[0xff, 0xff, 0xff, 0xff]), Ok(-0x7fff_ffff));
+        assert_eq!(
+            decode_num(&encode_num(0x8000_0000)),
+            Err(ScriptError::NumberOverflow)
+        );
+
+        // results of arithmetic may be longer, but can't be used as operands
+        let script: Script = Script::new()
+            .push_slice(&encode_num(0x7fff_ffff))
+            .push_opcode(OP_1ADD)
+            .push_opcode(OP_1ADD);
+        assert_eq!(run(&script), Err(ScriptError::NumberOverflow));
+    }
+}
@@ -0,0 +1,39 @@
+use std::sync::OnceLock;
+
+use crate::modules::amount::{Amount, COIN};
+
+/// New coins created by each block until the first halving
+pub const INITIAL_SUBSIDY: Amount = Amount::from_sat(50 * COIN);
+
+/// Default number of blocks after which the subsidy halves. With the initial subsidy, the
+/// subsidies of all blocks add up to just under 21,000 bitcoins.
+pub const DEFAULT_HALVING_INTERVAL: u32 = 210;
+
+/// Number of blocks built on a coinbase transaction before its outputs can be spent
+pub const COINBASE_MATURITY: u32 = 10;
+
+// number of blocks after which the subsidy halves, configured when the node starts
+static HALVING_INTERVAL: OnceLock<u32> = OnceLock::new();
+
+/// Set the number of blocks after which the subsidy halves. Every node of a network must use
+/// the same interval. Only the first call has an effect.
+pub fn set_halving_interval(blocks: u32) {
+    let _ = HALVING_INTERVAL.set(blocks);
+}
+
+/// Get the number of blocks after which the subsidy halves
+pub fn halving_interval() -> u32 {
+    HALVING_INTERVAL
+        .get()
+        .copied()
+        .unwrap_or(DEFAULT_HALVING_INTERVAL)
+}
+
+/// New coins the block at `height` may create when the subsidy halves every
+/// `halving_interval` blocks
+pub fn block_subsidy(height: u32, halving_interval: u32) -> Amount {
+    let halvings: u32 = height / halving_interval.max(1);
+
+    // the subsidy is shifted out entirely after 64 halvings
+    Amount::from_sat(INITIAL_SUBSIDY.to_sat().checked_shr(halvings).unwrap_or(0))
+}
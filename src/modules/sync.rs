@@ -0,0 +1,400 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::thread;
+
+use primitive_types::U256;
+use serde::{Deserialize, Serialize};
+
+use crate::database::{BaseDB, BlockchainDB, TransactionDB};
+use crate::modules::ban::node_misbehaving;
+use crate::modules::blockchain::{Block, BlockHeader};
+use crate::modules::chain::{accept_block, block_locator, block_work, BlockTree};
+use crate::modules::mempool::mempool;
+use crate::modules::node::ping_peer;
+use crate::modules::transactions::Transaction;
+use crate::modules::validation::{validate_header, BlockError};
+use crate::p2p::{AddressError, RPCClient};
+
+/// Most headers sent in reply to a single `get_headers` request
+pub const MAX_HEADERS: usize = 2000;
+
+/// Most blocks sent in reply to a single `get_blocks` request
+pub const MAX_BLOCKS: usize = 16;
+
+/// Most headers downloaded from a peer in one round of syncing. Longer chains are synced over
+/// several rounds, so a peer can't make the node hold an endless chain of headers.
+pub const MAX_SYNC_HEADERS: usize = 10 * MAX_HEADERS;
+
+/// Penalty for headers that don't build on the locator or on each other
+pub const UNCONNECTED_HEADERS_PENALTY: u32 = 20;
+
+/// A block together with the transactions it references
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockData {
+    pub block: Block,
+    pub transactions: Vec<Transaction>,
+}
+
+/// Reasons syncing with a peer fails
+#[derive(Debug)]
+pub enum SyncError {
+    // the address of the peer can't be used
+    Address(AddressError),
+    // the peer couldn't be reached or sent a malformed response
+    Rpc(jsonrpc::Error),
+    // the peer sent a header that fails validation
+    InvalidHeader(String, BlockError),
+    // the peer sent a header that builds on neither a known block nor the header before it
+    UnconnectedHeader(String),
+    // the peer didn't send a block it announced
+    MissingBlock(String),
+}
+
+impl fmt::Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SyncError::Address(error) => write!(f, "bad address: {error}"),
+            SyncError::Rpc(error) => write!(f, "request failed: {error}"),
+            SyncError::InvalidHeader(hash, error) => write!(f, "header {hash}: {error}"),
+            SyncError::UnconnectedHeader(hash) => {
+                write!(f, "header {hash} doesn't connect to the chain")
+            }
+            SyncError::MissingBlock(hash) => write!(f, "block {hash} was not sent"),
+        }
+    }
+}
+
+impl std::error::Error for SyncError {}
+
+impl SyncError {
+    /// Short machine readable name of the error
+    pub fn reason(&self) -> &'static str {
+        match self {
+            SyncError::Address(_) => "bad-address",
+            SyncError::Rpc(_) => "request-failed",
+            SyncError::InvalidHeader(_, error) => error.reason(),
+            SyncError::UnconnectedHeader(_) => "unconnected-headers",
+            SyncError::MissingBlock(_) => "missing-block",
+        }
+    }
+
+    /// Misbehavior score of the peer syncing failed with. Headers sent in reply to a locator
+    /// must connect, so headers that don't are held against the peer; a peer that can't be
+    /// reached isn't.
+    pub fn penalty(&self) -> u32 {
+        match self {
+            SyncError::Address(_) | SyncError::Rpc(_) | SyncError::MissingBlock(_) => 0,
+            SyncError::InvalidHeader(_, error) => error.penalty(),
+            SyncError::UnconnectedHeader(_) => UNCONNECTED_HEADERS_PENALTY,
+        }
+    }
+}
+
+impl From<AddressError> for SyncError {
+    fn from(error: AddressError) -> SyncError {
+        SyncError::Address(error)
+    }
+}
+
+impl From<jsonrpc::Error> for SyncError {
+    fn from(error: jsonrpc::Error) -> SyncError {
+        SyncError::Rpc(error)
+    }
+}
+
+/// Headers of a peer's chain that extend a block we already know
+struct PeerHeaders {
+    node: String,
+    headers: Vec<BlockHeader>,
+    // total work of the chain ending at the last header
+    work: U256,
+}
+
+/// Download the headers of a peer's active chain that follow the last block we have in common,
+/// checking the proof-of-work and linkage of every header. At most `MAX_SYNC_HEADERS` headers
+/// are downloaded.
+fn download_headers(
+    node: &str,
+    tree: &BlockTree,
+    active: &[Block],
+) -> Result<PeerHeaders, SyncError> {
+    let client: RPCClient = RPCClient::new(node.to_string())?;
+    let mut headers: Vec<BlockHeader> = Vec::new();
+    let mut locator: Vec<String> = block_locator(active);
+
+    loop {
+        let batch: Vec<BlockHeader> = client.get_headers(&locator, MAX_HEADERS)?;
+        let full: bool = batch.len() == MAX_HEADERS;
+
+        for header in batch {
+            // the first header builds on a block we know, the others on the header before them
+            let parent: Option<BlockHeader> = headers
+                .last()
+                .cloned()
+                .or_else(|| tree.get(&header.previous_block).map(Block::to_header));
+
+            validate_header(&header, parent.as_ref()).map_err(|error| match error {
+                BlockError::UnknownParent(_) => SyncError::UnconnectedHeader(header.hash.clone()),
+                error => SyncError::InvalidHeader(header.hash.clone(), error),
+            })?;
+
+            headers.push(header);
+        }
+
+        // a batch that isn't full means the peer has no more headers, the rest of a long
+        // chain is downloaded in the next round
+        match headers.last() {
+            Some(last) if full && headers.len() < MAX_SYNC_HEADERS => {
+                locator = vec![last.hash.clone()]
+            }
+            _ => break,
+        }
+    }
+
+    let base: U256 = headers
+        .first()
+        .map_or(U256::zero(), |first| tree.chain_work(&first.previous_block));
+    let work: U256 = headers.iter().fold(base, |work, header| {
+        work.saturating_add(block_work(header.bits))
+    });
+
+    Ok(PeerHeaders {
+        node: node.to_string(),
+        headers,
+        work,
+    })
+}
+
+/// Make the transactions sent along with `block` available to its validation.
+/// Transactions the block doesn't reference are ignored, and those whose hash doesn't match
+/// their contents are refused by the mempool.
+pub fn stage_transactions(block: &Block, transactions: Vec<Transaction>) {
+    let txn_db: TransactionDB = TransactionDB::new();
+
+    for txn in transactions {
+        if block.tx.contains(&txn.hash) && txn_db.find(&txn.hash).is_none() {
+            mempool().stage(txn);
+        }
+    }
+}
+
+/// Request a batch of blocks from a peer, making sure it sends exactly the blocks requested
+fn fetch_blocks(client: &RPCClient, hashes: &[String]) -> Result<Vec<BlockData>, SyncError> {
+    let mut blocks: Vec<BlockData> = client.get_blocks(hashes)?;
+    blocks.truncate(hashes.len());
+
+    for (position, hash) in hashes.iter().enumerate() {
+        if blocks.get(position).map(|data| &data.block.hash) != Some(hash) {
+            return Err(SyncError::MissingBlock(hash.clone()));
+        }
+    }
+
+    Ok(blocks)
+}
+
+/// Download the blocks of `best` that aren't known yet. Batches of blocks are spread over every
+/// peer that announced them and downloaded in parallel; a failed batch is retried with the other
+/// peers. Blocks are returned in chain order.
+fn download_blocks(
+    best: &PeerHeaders,
+    peers: &[PeerHeaders],
+    tree: &BlockTree,
+) -> Result<Vec<BlockData>, SyncError> {
+    let missing: Vec<String> = best
+        .headers
+        .iter()
+        .filter(|header| tree.get(&header.hash).is_none())
+        .map(|header| header.hash.clone())
+        .collect();
+    let batches: Vec<&[String]> = missing.chunks(MAX_BLOCKS).collect();
+
+    // a peer that announced the last block of a batch has every block before it
+    let sources: Vec<Vec<&str>> = batches
+        .iter()
+        .map(|batch| {
+            let last: &String = batch.last().expect("Batches are never empty");
+            peers
+                .iter()
+                .filter(|peer| peer.headers.iter().any(|header| &header.hash == last))
+                .map(|peer| peer.node.as_str())
+                .collect()
+        })
+        .collect();
+
+    // spread the batches over their sources
+    let mut assigned: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (position, nodes) in sources.iter().enumerate() {
+        let node: &str = nodes[position % nodes.len()];
+        assigned.entry(node).or_default().push(position);
+    }
+
+    // download from every peer at the same time
+    let mut results: Vec<Option<Result<Vec<BlockData>, SyncError>>> =
+        batches.iter().map(|_| None).collect();
+    thread::scope(|scope| {
+        let workers: Vec<_> = assigned
+            .iter()
+            .map(|(node, positions)| {
+                let batches: &[&[String]] = &batches;
+                scope.spawn(move || {
+                    let client: Result<RPCClient, AddressError> = RPCClient::new(node.to_string());
+                    positions
+                        .iter()
+                        .map(|&position| {
+                            let result = match &client {
+                                Ok(client) => fetch_blocks(client, batches[position]),
+                                Err(error) => Err(SyncError::Address(error.clone())),
+                            };
+                            (position, result)
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+
+        for worker in workers {
+            for (position, result) in worker.join().expect("Block download thread panicked") {
+                results[position] = Some(result);
+            }
+        }
+    });
+
+    let mut blocks: Vec<BlockData> = Vec::new();
+    for (position, result) in results.into_iter().enumerate() {
+        let mut result: Result<Vec<BlockData>, SyncError> =
+            result.expect("Every batch is assigned to a peer");
+
+        // retry a failed batch with the other peers that have it
+        let first: &str = sources[position][position % sources[position].len()];
+        for node in sources[position].iter().filter(|node| **node != first) {
+            if result.is_ok() {
+                break;
+            }
+            result = RPCClient::new(node.to_string())
+                .map_err(SyncError::from)
+                .and_then(|client| fetch_blocks(&client, batches[position]));
+        }
+
+        blocks.extend(result?);
+    }
+
+    Ok(blocks)
+}
+
+/// Feed downloaded blocks through block validation in chain order. Returns the number of blocks
+/// accepted and the first block rejected, if any.
+fn connect_blocks(blocks: Vec<BlockData>) -> (usize, Option<(String, BlockError)>) {
+    let mut accepted: usize = 0;
+
+    for data in blocks {
+        let hash: String = data.block.hash.clone();
+        stage_transactions(&data.block, data.transactions);
+
+        match accept_block(data.block) {
+            Ok(()) | Err(BlockError::AlreadyKnown) => accepted += 1,
+            // the descendants of an invalid block can't be valid either
+            Err(error) => return (accepted, Some((hash, error))),
+        }
+    }
+
+    (accepted, None)
+}
+
+/// Bring the local chain up to date with `nodes`, in rounds of at most `MAX_SYNC_HEADERS`
+/// blocks
+pub fn sync_chain(nodes: &[String]) {
+    while sync_round(nodes) {}
+}
+
+/// Download headers from every peer, then download and validate the blocks of the chain with
+/// the most work. Returns whether the chain goes on past the downloaded headers and the local
+/// chain moved forward without rejecting a block, so another round is worth syncing.
+fn sync_round(nodes: &[String]) -> bool {
+    let tree: BlockTree = BlockTree::load();
+    let active: Vec<Block> = BlockchainDB::new().find_all();
+    let local_work: U256 = active
+        .last()
+        .map_or(U256::zero(), |tip| tree.chain_work(&tip.hash));
+
+    println!("Syncing with {} node(s)", nodes.len());
+
+    // collect the headers every peer has and we don't
+    let mut peers: Vec<PeerHeaders> = Vec::new();
+    for (position, node) in nodes.iter().enumerate() {
+        let progress: String = format!("[{}/{}] {node}", position + 1, nodes.len());
+
+        if ping_peer(node).is_none() {
+            println!("{progress}: Unreachable");
+            continue;
+        }
+
+        match download_headers(node, &tree, &active) {
+            Ok(peer) => {
+                println!("{progress}: {} new header(s)", peer.headers.len());
+                peers.push(peer);
+            }
+            // an unreachable or misbehaving peer doesn't prevent syncing with the others
+            Err(error) => {
+                println!("{progress}: Sync failed: {error}");
+                node_misbehaving(node, error.penalty(), error.reason());
+            }
+        }
+    }
+
+    // only a chain with more work than the local one is worth downloading
+    let Some(best) = peers
+        .iter()
+        .filter(|peer| peer.work > local_work)
+        .max_by_key(|peer| peer.work)
+    else {
+        println!("Local chain is up to date");
+        return false;
+    };
+
+    println!(
+        "Downloading {} block(s) up to {}",
+        best.headers.len(),
+        best.headers
+            .last()
+            .map_or("", |header| header.hash.as_str())
+    );
+
+    let blocks: Vec<BlockData> = match download_blocks(best, &peers, &tree) {
+        Ok(blocks) => blocks,
+        Err(error) => {
+            println!("Block download failed: {error}");
+            return false;
+        }
+    };
+
+    let (accepted, rejected) = connect_blocks(blocks);
+    println!("Accepted {accepted} block(s)");
+
+    // the peer with the most work announced the chain the invalid block is on
+    if let Some((hash, error)) = rejected {
+        println!("Rejected block {hash}: {error}");
+        node_misbehaving(&best.node, error.penalty(), error.reason());
+        return false;
+    }
+
+    // only go on while the local chain makes progress
+    let tip: Option<String> = BlockchainDB::new().tip().map(|tip| tip.hash);
+    best.headers.len() >= MAX_SYNC_HEADERS && tip != active.last().map(|tip| tip.hash.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headers_that_dont_connect_count_against_the_peer() {
+        let error: SyncError = SyncError::UnconnectedHeader("11".repeat(32));
+        assert_eq!(error.penalty(), UNCONNECTED_HEADERS_PENALTY);
+        assert_eq!(error.reason(), "unconnected-headers");
+
+        let error: SyncError = SyncError::InvalidHeader("11".repeat(32), BlockError::InvalidHash);
+        assert_eq!(error.penalty(), BlockError::InvalidHash.penalty());
+
+        assert_eq!(SyncError::MissingBlock("11".repeat(32)).penalty(), 0);
+    }
+}
@@ -1,5 +1,20 @@
+use std::fmt;
+
+use secp256k1::ecdsa::Signature;
+use secp256k1::{Message, Secp256k1};
 use serde::{Deserialize, Serialize};
 
+use super::account::Account;
+use super::amount::Amount;
+use super::hashing::{decode_hash, sha256d, write_compact_size, write_var_bytes, HASH_SIZE};
+use super::script::{Instruction, Script};
+
+/// Transaction hash of the null outpoint spent by coinbase inputs
+pub const NULL_TXID: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Output index of the null outpoint spent by coinbase inputs
+pub const NULL_VOUT: u32 = u32::MAX;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Transaction {
     pub timestamp: u64,
@@ -10,19 +25,48 @@ pub struct Transaction {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Vin {
-    pub sender: String,
-    pub amount: u32,
+    // hash of the transaction holding the output being spent
+    pub txid: String,
+    // index of the output being spent in that transaction
+    pub vout: u32,
+    pub amount: Amount,
+    // unlocking script satisfying the locking script of the output being spent, such as
+    // `<signature> <pubkey>` for P2PKH. The input of a coinbase transaction has nothing to
+    // unlock and pushes the height of its block (u32 LE) instead.
+    pub script_sig: Script,
     pub hash: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Vout {
-    pub receiver: String,
-    pub amount: u32,
+    // locking script that must be satisfied to spend the output
+    pub script_pubkey: Script,
+    pub amount: Amount,
     pub hash: String,
 }
 
+/// Reference to a specific output of a previous transaction
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct OutPoint {
+    pub txid: String,
+    pub vout: u32,
+}
+
+impl OutPoint {
+    /// Check if this is the null outpoint spent by coinbase inputs
+    pub fn is_null(&self) -> bool {
+        self.txid == NULL_TXID && self.vout == NULL_VOUT
+    }
+}
+
+impl fmt::Display for OutPoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.txid, self.vout)
+    }
+}
+
 impl Transaction {
+    #[allow(dead_code)]
     pub fn default() -> Transaction {
         Transaction {
             timestamp: 0,
@@ -31,4 +75,217 @@ impl Transaction {
             hash: "".to_string(),
         }
     }
+
+    /// Construct a transaction and derive its ID from its contents
+    pub fn new(timestamp: u64, vin: Vec<Vin>, vout: Vec<Vout>) -> Transaction {
+        let mut txn: Transaction = Transaction {
+            timestamp,
+            vin,
+            vout,
+            hash: "".to_string(),
+        };
+        txn.hash = txn.calculate_hash();
+        txn
+    }
+
+    /// Construct the coinbase transaction of the block at `height`, paying `amount` of new
+    /// coins and fees to `script_pubkey`. Its single input spends the null outpoint and commits
+    /// to the height, so coinbase transactions of different blocks never share a hash.
+    pub fn coinbase(
+        height: u32,
+        timestamp: u64,
+        script_pubkey: Script,
+        amount: Amount,
+    ) -> Transaction {
+        let mut vin: Vin = Vin::new(NULL_TXID.to_string(), NULL_VOUT, Amount::ZERO);
+        vin.script_sig = Script::new().push_slice(&height.to_le_bytes());
+        vin.hash = vin.calculate_hash();
+
+        Transaction::new(timestamp, vec![vin], vec![Vout::new(script_pubkey, amount)])
+    }
+
+    /// Check if the transaction is a coinbase transaction, with a single input spending the
+    /// null outpoint
+    pub fn is_coinbase(&self) -> bool {
+        matches!(&self.vin[..], [vin] if vin.outpoint().is_null())
+    }
+
+    /// Height of the block a coinbase transaction commits to
+    pub fn coinbase_height(&self) -> Option<u32> {
+        if !self.is_coinbase() {
+            return None;
+        }
+
+        // the height is the first push of the unlocking script
+        match self.vin[0].script_sig.instructions().next()? {
+            Ok(Instruction::Push(data)) => Some(u32::from_le_bytes(data.try_into().ok()?)),
+            _ => None,
+        }
+    }
+
+    /// Serialize the transaction in its canonical byte layout:
+    /// timestamp (u64 LE), inputs and outputs, each list prefixed with its length
+    pub fn serialize(&self) -> Vec<u8> {
+        self.serialize_with(true)
+    }
+
+    /// Serialize the transaction, optionally leaving out the unlocking scripts of the inputs
+    fn serialize_with(&self, include_signatures: bool) -> Vec<u8> {
+        let mut data: Vec<u8> = Vec::new();
+
+        data.extend_from_slice(&self.timestamp.to_le_bytes());
+
+        write_compact_size(&mut data, self.vin.len() as u64);
+        for vin in &self.vin {
+            vin.serialize_into(&mut data, include_signatures);
+        }
+
+        write_compact_size(&mut data, self.vout.len() as u64);
+        for vout in &self.vout {
+            vout.serialize_into(&mut data);
+        }
+
+        data
+    }
+
+    /// Size of the serialized transaction in bytes
+    pub fn size(&self) -> usize {
+        self.serialize().len()
+    }
+
+    /// The fee paid to the miner: what the inputs spend minus what the outputs pay.
+    /// Coinbase transactions create new coins and pay no fee, and neither do transactions
+    /// whose amounts are out of range or whose outputs exceed their inputs.
+    pub fn fee(&self) -> Amount {
+        if self.is_coinbase() {
+            return Amount::ZERO;
+        }
+
+        self.input_value()
+            .zip(self.output_value())
+            .and_then(|(inputs, outputs)| inputs.checked_sub(outputs))
+            .unwrap_or(Amount::ZERO)
+    }
+
+    /// Total amount claimed by the inputs, or `None` if it is more than `MAX_MONEY`
+    pub fn input_value(&self) -> Option<Amount> {
+        Amount::checked_sum(self.vin.iter().map(|vin| vin.amount))
+    }
+
+    /// Total amount paid by the outputs, or `None` if it is more than `MAX_MONEY`
+    pub fn output_value(&self) -> Option<Amount> {
+        Amount::checked_sum(self.vout.iter().map(|vout| vout.amount))
+    }
+
+    /// Calculate the transaction ID, the double SHA-256 of the serialized transaction
+    pub fn calculate_hash(&self) -> String {
+        hex::encode(sha256d(&self.serialize()))
+    }
+
+    /// Calculate the message signed by every input: the double SHA-256 of the transaction
+    /// without the unlocking scripts, which can't sign themselves
+    pub fn signature_hash(&self) -> [u8; HASH_SIZE] {
+        sha256d(&self.serialize_with(false))
+    }
+
+    /// Sign every input with the private key of `account`, unlocking outputs paid to the
+    /// P2PKH address of the account
+    pub fn sign(&mut self, account: &Account) -> Result<(), secp256k1::Error> {
+        let secp = Secp256k1::signing_only();
+        let message: Message = Message::from_digest(self.signature_hash());
+        let signature: Signature = secp.sign_ecdsa(&message, &account.secret_key()?);
+        let pubkey: Vec<u8> =
+            hex::decode(&account.pubkey).map_err(|_| secp256k1::Error::InvalidPublicKey)?;
+
+        for vin in self.vin.iter_mut() {
+            vin.script_sig = Script::new()
+                .push_slice(&signature.serialize_der())
+                .push_slice(&pubkey);
+            vin.hash = vin.calculate_hash();
+        }
+
+        // the signatures are part of the transaction ID
+        self.hash = self.calculate_hash();
+
+        Ok(())
+    }
+
+    /// Check that the transaction ID and the hashes of all inputs and outputs match their contents
+    pub fn has_valid_hash(&self) -> bool {
+        self.hash == self.calculate_hash()
+            && self.vin.iter().all(|vin| vin.hash == vin.calculate_hash())
+            && self
+                .vout
+                .iter()
+                .all(|vout| vout.hash == vout.calculate_hash())
+    }
+}
+
+impl Vin {
+    /// Construct an unsigned input spending output `vout` of transaction `txid`
+    pub fn new(txid: String, vout: u32, amount: Amount) -> Vin {
+        let mut vin: Vin = Vin {
+            txid,
+            vout,
+            amount,
+            script_sig: Script::new(),
+            hash: "".to_string(),
+        };
+        vin.hash = vin.calculate_hash();
+        vin
+    }
+
+    /// The previous output this input spends
+    pub fn outpoint(&self) -> OutPoint {
+        OutPoint {
+            txid: self.txid.clone(),
+            vout: self.vout,
+        }
+    }
+
+    /// Append the canonical serialization of the input: previous transaction hash,
+    /// output index (u32 LE), amount in satoshis (u64 LE) and unlocking script
+    fn serialize_into(&self, data: &mut Vec<u8>, include_script_sig: bool) {
+        data.extend_from_slice(&decode_hash(&self.txid));
+        data.extend_from_slice(&self.vout.to_le_bytes());
+        data.extend_from_slice(&self.amount.to_le_bytes());
+
+        if include_script_sig {
+            write_var_bytes(data, self.script_sig.as_bytes());
+        }
+    }
+
+    /// Calculate the double SHA-256 of the serialized input
+    pub fn calculate_hash(&self) -> String {
+        let mut data: Vec<u8> = Vec::new();
+        self.serialize_into(&mut data, true);
+        hex::encode(sha256d(&data))
+    }
+}
+
+impl Vout {
+    /// Construct an output paying `amount` to whoever can satisfy `script_pubkey`
+    pub fn new(script_pubkey: Script, amount: Amount) -> Vout {
+        let mut vout: Vout = Vout {
+            script_pubkey,
+            amount,
+            hash: "".to_string(),
+        };
+        vout.hash = vout.calculate_hash();
+        vout
+    }
+
+    /// Append the canonical serialization of the output: locking script and amount in
+    /// satoshis (u64 LE)
+    fn serialize_into(&self, data: &mut Vec<u8>) {
+        write_var_bytes(data, self.script_pubkey.as_bytes());
+        data.extend_from_slice(&self.amount.to_le_bytes());
+    }
+
+    /// Calculate the double SHA-256 of the serialized output
+    pub fn calculate_hash(&self) -> String {
+        let mut data: Vec<u8> = Vec::new();
+        self.serialize_into(&mut data);
+        hex::encode(sha256d(&data))
+    }
 }
@@ -0,0 +1,479 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::database::{BaseDB, BlockchainDB, TransactionDB, UndoDB, UtxoDB};
+use crate::modules::amount::{Amount, MAX_MONEY};
+use crate::modules::blockchain::Block;
+use crate::modules::miner::GENESIS_PREVIOUS_BLOCK;
+use crate::modules::script::{verify_script, Script, SignatureChecker};
+use crate::modules::subsidy::{block_subsidy, halving_interval, COINBASE_MATURITY};
+use crate::modules::transactions::{OutPoint, Transaction, Vout};
+use crate::modules::validation::{BlockError, TransactionError};
+
+/// An unspent transaction output
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UtxoEntry {
+    pub outpoint: OutPoint,
+    pub output: Vout,
+    // index of the block that created the output
+    pub height: u32,
+    // whether the output was created by a coinbase transaction
+    #[serde(default)]
+    pub coinbase: bool,
+}
+
+impl UtxoEntry {
+    /// Check if the output can be spent by the block at `height`. Outputs of coinbase
+    /// transactions must be `COINBASE_MATURITY` blocks deep first.
+    pub fn is_mature(&self, height: u32) -> bool {
+        !self.coinbase || height >= self.height.saturating_add(COINBASE_MATURITY)
+    }
+}
+
+/// Outputs spent by a block, kept so the block can be disconnected again
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockUndo {
+    pub block: String,
+    pub spent: Vec<UtxoEntry>,
+}
+
+/// The set of all unspent transaction outputs on the chain
+#[derive(Debug, Clone, Default)]
+pub struct UtxoSet {
+    // hash of the last block connected to the set
+    tip: Option<String>,
+    entries: HashMap<OutPoint, UtxoEntry>,
+}
+
+// the UTXO set as stored in the local database
+#[derive(Serialize, Deserialize)]
+struct UtxoSnapshot {
+    tip: Option<String>,
+    entries: Vec<UtxoEntry>,
+}
+
+impl UtxoSet {
+    /// Load the UTXO set from the local database. The set is rebuilt from the chain, and stored
+    /// again, if it is missing or was saved for a different tip than the one of the chain.
+    pub fn load() -> UtxoSet {
+        let tip: Option<String> = BlockchainDB::new().tip().map(|block| block.hash);
+
+        match UtxoDB::new().read_value::<UtxoSnapshot>() {
+            Some(snapshot) if snapshot.tip == tip => {
+                UtxoSet::from_entries(snapshot.tip, snapshot.entries)
+            }
+            _ if tip.is_none() => UtxoSet::default(),
+            _ => {
+                let utxos: UtxoSet = UtxoSet::build();
+                utxos.save();
+                utxos
+            }
+        }
+    }
+
+    /// Rebuild the UTXO set by replaying every block on the local chain
+    pub fn build() -> UtxoSet {
+        let txn_db: TransactionDB = TransactionDB::new();
+        let mut utxos: UtxoSet = UtxoSet::default();
+
+        for block in BlockchainDB::new().find_all::<Block>() {
+            // collect the transactions of the block from the local database
+            let transactions: Vec<Transaction> = block
+                .tx
+                .iter()
+                .filter_map(|hash| txn_db.find(hash))
+                .collect();
+
+            if let Err(error) = utxos.connect_block(&block, &transactions) {
+                eprintln!("UTXO: Stopped rebuilding at block {}: {error}", block.hash);
+                break;
+            }
+        }
+
+        utxos
+    }
+
+    /// Construct the UTXO set holding `entries`, built for the chain ending at `tip`
+    pub fn from_entries(tip: Option<String>, entries: Vec<UtxoEntry>) -> UtxoSet {
+        UtxoSet {
+            tip,
+            entries: entries
+                .into_iter()
+                .map(|entry| (entry.outpoint.clone(), entry))
+                .collect(),
+        }
+    }
+
+    /// Persist the UTXO set to the local database, along with the tip it was built for
+    pub fn save(&self) {
+        let snapshot: UtxoSnapshot = UtxoSnapshot {
+            tip: self.tip.clone(),
+            entries: self.entries.values().cloned().collect(),
+        };
+
+        UtxoDB::new()
+            .write_value(&snapshot)
+            .expect("Couldn't write to UTXO database");
+    }
+
+    /// Get an unspent output
+    pub fn get(&self, outpoint: &OutPoint) -> Option<&UtxoEntry> {
+        self.entries.get(outpoint)
+    }
+
+    /// Get all unspent outputs locked to the P2PKH `address`
+    pub fn find_by_address(&self, address: &str) -> Vec<&UtxoEntry> {
+        let Some(script_pubkey) = Script::for_address(address) else {
+            return Vec::new();
+        };
+
+        self.entries
+            .values()
+            .filter(|entry| entry.output.script_pubkey == script_pubkey)
+            .collect()
+    }
+
+    /// Check that every input of `txn` spends an existing, unspent output of the same amount,
+    /// satisfies the locking script of that output, and that the inputs cover the outputs.
+    /// Outputs of coinbase transactions can be spent once they are `COINBASE_MATURITY` blocks
+    /// deep at `height`, the height of the block spending them.
+    pub fn validate_spend(&self, txn: &Transaction, height: u32) -> Result<(), TransactionError> {
+        // coinbase transactions have nothing to spend
+        if txn.is_coinbase() {
+            return Err(TransactionError::Coinbase);
+        }
+
+        let mut seen: HashSet<OutPoint> = HashSet::new();
+        let mut inputs: Amount = Amount::ZERO;
+        let checker: SignatureChecker = SignatureChecker::new(txn.signature_hash());
+
+        for vin in &txn.vin {
+            let outpoint: OutPoint = vin.outpoint();
+
+            // the same output can't be spent twice by one transaction
+            if !seen.insert(outpoint.clone()) {
+                return Err(TransactionError::DuplicateInput(outpoint.to_string()));
+            }
+
+            // the output must exist and be unspent
+            let entry: &UtxoEntry = self
+                .get(&outpoint)
+                .ok_or_else(|| TransactionError::MissingInput(outpoint.to_string()))?;
+
+            // new coins must wait before they can be spent
+            if !entry.is_mature(height) {
+                return Err(TransactionError::ImmatureCoinbase(outpoint.to_string()));
+            }
+
+            // the input must claim exactly the value of the output
+            if vin.amount != entry.output.amount {
+                return Err(TransactionError::AmountMismatch(outpoint.to_string()));
+            }
+
+            // the unlocking script must satisfy the locking script of the output
+            verify_script(&vin.script_sig, &entry.output.script_pubkey, &checker)
+                .map_err(|error| TransactionError::InvalidScript(outpoint.to_string(), error))?;
+
+            inputs = inputs
+                .checked_add(entry.output.amount)
+                .ok_or(TransactionError::AmountOutOfRange)?;
+        }
+
+        let outputs: Amount = txn
+            .output_value()
+            .ok_or(TransactionError::AmountOutOfRange)?;
+        if inputs < outputs {
+            return Err(TransactionError::InsufficientInputs { inputs, outputs });
+        }
+
+        Ok(())
+    }
+
+    /// Validate `txn` and apply it to the set, returning the outputs it spent
+    pub fn apply_transaction(
+        &mut self,
+        txn: &Transaction,
+        height: u32,
+    ) -> Result<Vec<UtxoEntry>, TransactionError> {
+        self.validate_spend(txn, height)?;
+
+        // remove the spent outputs
+        let spent: Vec<UtxoEntry> = txn
+            .vin
+            .iter()
+            .filter_map(|vin| self.entries.remove(&vin.outpoint()))
+            .collect();
+
+        self.add_outputs(txn, height);
+
+        Ok(spent)
+    }
+
+    /// Add the outputs created by `txn` at `height`, without checking its inputs.
+    /// Outputs that can never be spent, such as `OP_RETURN` outputs, are left out.
+    pub fn add_outputs(&mut self, txn: &Transaction, height: u32) {
+        for (index, vout) in txn.vout.iter().enumerate() {
+            if vout.script_pubkey.is_unspendable() {
+                continue;
+            }
+
+            let outpoint: OutPoint = OutPoint {
+                txid: txn.hash.clone(),
+                vout: index as u32,
+            };
+
+            self.entries.insert(
+                outpoint.clone(),
+                UtxoEntry {
+                    outpoint,
+                    output: vout.clone(),
+                    height,
+                    coinbase: txn.is_coinbase(),
+                },
+            );
+        }
+    }
+
+    /// Apply every transaction of `block`, in order. The coinbase transaction may pay no more
+    /// than the subsidy and the fees of the block. Either all transactions are applied or,
+    /// if one of them is invalid, the set is left untouched.
+    pub fn connect_block(
+        &mut self,
+        block: &Block,
+        transactions: &[Transaction],
+    ) -> Result<BlockUndo, BlockError> {
+        // stage the changes on a copy of the set
+        let mut staged: UtxoSet = self.clone();
+        let mut spent: Vec<UtxoEntry> = Vec::new();
+        let mut fees: Amount = Amount::ZERO;
+        let mut coinbase: Option<&Transaction> = None;
+
+        for (position, hash) in block.tx.iter().enumerate() {
+            let txn: &Transaction = transactions
+                .iter()
+                .find(|txn| &txn.hash == hash)
+                .ok_or_else(|| BlockError::MissingTransaction(hash.clone()))?;
+
+            // the coinbase transaction spends nothing, it only creates outputs
+            if position == 0 && txn.is_coinbase() {
+                staged.add_outputs(txn, block.index);
+                coinbase = Some(txn);
+                continue;
+            }
+
+            let mut spent_by_txn: Vec<UtxoEntry> = staged
+                .apply_transaction(txn, block.index)
+                .map_err(|error| BlockError::InvalidTransaction(hash.clone(), error))?;
+            spent.append(&mut spent_by_txn);
+
+            fees = fees.checked_add(txn.fee()).ok_or_else(|| {
+                BlockError::InvalidTransaction(hash.clone(), TransactionError::AmountOutOfRange)
+            })?;
+        }
+
+        // the coinbase can claim the subsidy and the fees the other transactions leave
+        let coinbase: &Transaction = coinbase.ok_or(BlockError::MissingCoinbase)?;
+        let max: Amount = block_subsidy(block.index, halving_interval())
+            .checked_add(fees)
+            .unwrap_or(MAX_MONEY);
+        let found: Amount = coinbase.output_value().ok_or_else(|| {
+            BlockError::InvalidTransaction(
+                coinbase.hash.clone(),
+                TransactionError::AmountOutOfRange,
+            )
+        })?;
+        if found > max {
+            return Err(BlockError::CoinbaseTooLarge { max, found });
+        }
+
+        // every transaction is valid, commit the changes
+        staged.tip = Some(block.hash.clone());
+        *self = staged;
+
+        Ok(BlockUndo {
+            block: block.hash.clone(),
+            spent,
+        })
+    }
+
+    /// Revert the transactions of `block` using the outputs recorded in `undo`
+    pub fn disconnect_block(
+        &mut self,
+        block: &Block,
+        transactions: &[Transaction],
+        undo: &BlockUndo,
+    ) {
+        // remove the outputs created by the block
+        for txn in transactions
+            .iter()
+            .filter(|txn| block.tx.contains(&txn.hash))
+        {
+            for index in 0..txn.vout.len() {
+                self.entries.remove(&OutPoint {
+                    txid: txn.hash.clone(),
+                    vout: index as u32,
+                });
+            }
+        }
+
+        // restore the outputs spent by the block, except those the block created itself,
+        // which were removed above
+        for entry in undo
+            .spent
+            .iter()
+            .filter(|entry| !block.tx.contains(&entry.outpoint.txid))
+        {
+            self.entries.insert(entry.outpoint.clone(), entry.clone());
+        }
+
+        self.tip = match block.previous_block.as_str() {
+            GENESIS_PREVIOUS_BLOCK => None,
+            previous => Some(previous.to_string()),
+        };
+    }
+}
+
+/// Record the undo data of connected blocks, replacing any earlier records for the blocks
+pub fn save_undo(undos: Vec<BlockUndo>) {
+    let undo_db: UndoDB = UndoDB::new();
+
+    let mut known: Vec<BlockUndo> = undo_db
+        .find_all::<BlockUndo>()
+        .into_iter()
+        .filter(|known| !undos.iter().any(|undo| undo.block == known.block))
+        .collect();
+    known.extend(undos);
+
+    undo_db
+        .write_all(known)
+        .expect("Couldn't write to Undo database");
+}
+
+/// Find the undo data of a block
+pub fn find_undo(block: &str) -> Option<BlockUndo> {
+    UndoDB::new()
+        .find_all::<BlockUndo>()
+        .into_iter()
+        .find(|undo| undo.block == block)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::script::OP_1;
+    use crate::modules::transactions::Vin;
+
+    // an output anyone can spend with an empty unlocking script
+    fn anyone_can_spend(amount: Amount) -> Vout {
+        Vout::new(Script::new().push_opcode(OP_1), amount)
+    }
+
+    // a transaction paying `amount` to an output anyone can spend, and the UTXO set holding it
+    fn fund(amount: Amount) -> (Transaction, UtxoSet) {
+        let vin: Vin = Vin::new("11".repeat(32), 0, amount);
+        let txn: Transaction = Transaction::new(0, vec![vin], vec![anyone_can_spend(amount)]);
+
+        let mut utxos: UtxoSet = UtxoSet::default();
+        utxos.add_outputs(&txn, 0);
+        (txn, utxos)
+    }
+
+    // spend output `vout` of `parent` to a single output, paying `fee`
+    fn spend(parent: &Transaction, vout: u32, fee: Amount) -> Transaction {
+        let amount: Amount = parent.vout[vout as usize].amount;
+        let vin: Vin = Vin::new(parent.hash.clone(), vout, amount);
+
+        Transaction::new(
+            0,
+            vec![vin],
+            vec![anyone_can_spend(amount.checked_sub(fee).unwrap())],
+        )
+    }
+
+    fn coinbase(height: u32, amount: Amount) -> Transaction {
+        Transaction::coinbase(height, 0, Script::new().push_opcode(OP_1), amount)
+    }
+
+    // the block at `height` holding `transactions`
+    fn block(height: u32, transactions: &[Transaction]) -> Block {
+        Block::new(
+            height,
+            0,
+            transactions.iter().map(|txn| txn.hash.clone()).collect(),
+            GENESIS_PREVIOUS_BLOCK.to_string(),
+            0,
+        )
+    }
+
+    fn outpoints(utxos: &UtxoSet) -> HashSet<OutPoint> {
+        utxos.entries.keys().cloned().collect()
+    }
+
+    #[test]
+    fn disconnect_reverts_spends_within_the_block() {
+        let (funding, mut utxos) = fund(Amount::from_sat(100_000));
+        let before: HashSet<OutPoint> = outpoints(&utxos);
+
+        let height: u32 = COINBASE_MATURITY;
+        let parent: Transaction = spend(&funding, 0, Amount::ZERO);
+        let child: Transaction = spend(&parent, 0, Amount::ZERO);
+        let transactions: Vec<Transaction> = vec![
+            coinbase(height, block_subsidy(height, halving_interval())),
+            parent,
+            child,
+        ];
+        let block: Block = block(height, &transactions);
+
+        let undo: BlockUndo = utxos.connect_block(&block, &transactions).unwrap();
+        utxos.disconnect_block(&block, &transactions, &undo);
+
+        assert_eq!(outpoints(&utxos), before);
+        assert_eq!(utxos.tip, None);
+    }
+
+    #[test]
+    fn coinbase_claims_the_subsidy_and_fees() {
+        let (funding, utxos) = fund(Amount::from_sat(100_000));
+        let fee: Amount = Amount::from_sat(1_000);
+        let txn: Transaction = spend(&funding, 0, fee);
+        let max: Amount = block_subsidy(1, halving_interval())
+            .checked_add(fee)
+            .unwrap();
+
+        let transactions: Vec<Transaction> = vec![coinbase(1, max), txn.clone()];
+        assert!(utxos
+            .clone()
+            .connect_block(&block(1, &transactions), &transactions)
+            .is_ok());
+
+        let found: Amount = max.checked_add(Amount::from_sat(1)).unwrap();
+        let transactions: Vec<Transaction> = vec![coinbase(1, found), txn];
+        let mut rejected: UtxoSet = utxos.clone();
+        assert!(matches!(
+            rejected.connect_block(&block(1, &transactions), &transactions),
+            Err(BlockError::CoinbaseTooLarge { max: m, found: f }) if m == max && f == found
+        ));
+
+        // a rejected block leaves the set untouched
+        assert_eq!(outpoints(&rejected), outpoints(&utxos));
+    }
+
+    #[test]
+    fn coinbase_outputs_mature_before_they_are_spent() {
+        let reward: Amount = block_subsidy(1, halving_interval());
+        let mined: Transaction = coinbase(1, reward);
+        let mut utxos: UtxoSet = UtxoSet::default();
+        utxos.add_outputs(&mined, 1);
+
+        let txn: Transaction = spend(&mined, 0, Amount::ZERO);
+        let outpoint: String = txn.vin[0].outpoint().to_string();
+
+        let height: u32 = COINBASE_MATURITY;
+        assert!(matches!(
+            utxos.validate_spend(&txn, height),
+            Err(TransactionError::ImmatureCoinbase(immature)) if immature == outpoint
+        ));
+        assert!(utxos.validate_spend(&txn, height + 1).is_ok());
+    }
+}
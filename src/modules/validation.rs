@@ -0,0 +1,706 @@
+use std::fmt;
+
+use crate::database::TransactionDB;
+use crate::modules::amount::{Amount, MAX_MONEY};
+use crate::modules::blockchain::{Block, BlockHeader};
+use crate::modules::difficulty::{meets_target, next_bits};
+use crate::modules::mempool::mempool;
+use crate::modules::miner::{current_timestamp, GENESIS_PREVIOUS_BLOCK};
+use crate::modules::script::ScriptError;
+use crate::modules::transactions::Transaction;
+
+/// How far in the future (in seconds) a block timestamp may be
+pub const MAX_FUTURE_BLOCK_TIME: u64 = 2 * 60 * 60;
+
+/// Number of previous blocks used to compute the median time past
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+/// Reasons a transaction is rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransactionError {
+    // the transaction hash doesn't match its contents
+    InvalidHash,
+    // the transaction has no inputs
+    NoInputs,
+    // the transaction has no outputs
+    NoOutputs,
+    // the inputs don't cover the outputs
+    InsufficientInputs { inputs: Amount, outputs: Amount },
+    // an amount, or the sum of the input or output amounts, is more than `MAX_MONEY`
+    AmountOutOfRange,
+    // an input spends an output that doesn't exist or is already spent
+    MissingInput(String),
+    // the same output is spent more than once
+    DuplicateInput(String),
+    // an input claims a different amount than the output it spends
+    AmountMismatch(String),
+    // the unlocking script of an input doesn't satisfy the locking script of the output
+    InvalidScript(String, ScriptError),
+    // an input spends an output that a pending transaction already spends
+    Conflict(String),
+    // the fee is below the minimum relay fee for the size of the transaction
+    FeeTooLow { fee: Amount, required: Amount },
+    // a coinbase transaction appears outside of the first position of a block
+    Coinbase,
+    // an input spends the output of a coinbase transaction that hasn't matured yet
+    ImmatureCoinbase(String),
+}
+
+/// Reasons a block is rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockError {
+    // the block is already known
+    AlreadyKnown,
+    // the hash or merkle root doesn't match the block contents
+    InvalidHash,
+    // the hash doesn't meet the difficulty target
+    InsufficientWork,
+    // the difficulty target doesn't follow the retargeting rule
+    InvalidBits { expected: u32, found: u32 },
+    // the previous block is unknown
+    UnknownParent(String),
+    // the index is not one higher than the parent's
+    InvalidIndex { expected: u32, found: u32 },
+    // the timestamp is not after the median time past
+    TimestampTooOld { median: u64, found: u64 },
+    // the timestamp is too far in the future
+    TimestampTooNew { max: u64, found: u64 },
+    // the same transaction is included more than once
+    DuplicateTransaction(String),
+    // a referenced transaction is unknown
+    MissingTransaction(String),
+    // a referenced transaction is invalid
+    InvalidTransaction(String, TransactionError),
+    // the first transaction is not a coinbase transaction
+    MissingCoinbase,
+    // the coinbase transaction doesn't commit to the height of the block
+    InvalidCoinbaseHeight(u32),
+    // the coinbase transaction pays more than the subsidy and fees of the block
+    CoinbaseTooLarge { max: Amount, found: Amount },
+    // the outputs spent by a block of the active chain are not recorded, so it can't be
+    // disconnected
+    MissingUndo(String),
+}
+
+impl TransactionError {
+    /// Short machine readable name of the error
+    pub fn reason(&self) -> &'static str {
+        match self {
+            TransactionError::InvalidHash => "invalid-hash",
+            TransactionError::NoInputs => "no-inputs",
+            TransactionError::NoOutputs => "no-outputs",
+            TransactionError::InsufficientInputs { .. } => "insufficient-inputs",
+            TransactionError::AmountOutOfRange => "amount-out-of-range",
+            TransactionError::MissingInput(_) => "missing-input",
+            TransactionError::DuplicateInput(_) => "duplicate-input",
+            TransactionError::AmountMismatch(_) => "amount-mismatch",
+            TransactionError::InvalidScript(..) => "invalid-script",
+            TransactionError::Conflict(_) => "conflict",
+            TransactionError::FeeTooLow { .. } => "fee-too-low",
+            TransactionError::Coinbase => "coinbase",
+            TransactionError::ImmatureCoinbase(_) => "immature-coinbase",
+        }
+    }
+
+    /// Misbehavior score of a peer that sends the transaction. Spending an unknown, already
+    /// spent or just maturing output can happen to honest peers that know of transactions or
+    /// blocks we don't, and the minimum relay fee is a local policy.
+    pub fn penalty(&self) -> u32 {
+        match self {
+            TransactionError::MissingInput(_)
+            | TransactionError::Conflict(_)
+            | TransactionError::FeeTooLow { .. }
+            | TransactionError::ImmatureCoinbase(_) => 0,
+            TransactionError::NoInputs
+            | TransactionError::NoOutputs
+            | TransactionError::Coinbase
+            | TransactionError::InsufficientInputs { .. }
+            | TransactionError::DuplicateInput(_) => 10,
+            TransactionError::InvalidHash
+            | TransactionError::AmountOutOfRange
+            | TransactionError::AmountMismatch(_)
+            | TransactionError::InvalidScript(..) => 20,
+        }
+    }
+}
+
+impl BlockError {
+    /// Short machine readable name of the error
+    pub fn reason(&self) -> &'static str {
+        match self {
+            BlockError::AlreadyKnown => "already-known",
+            BlockError::InvalidHash => "invalid-hash",
+            BlockError::InsufficientWork => "insufficient-work",
+            BlockError::InvalidBits { .. } => "invalid-bits",
+            BlockError::UnknownParent(_) => "unknown-parent",
+            BlockError::InvalidIndex { .. } => "invalid-index",
+            BlockError::TimestampTooOld { .. } => "timestamp-too-old",
+            BlockError::TimestampTooNew { .. } => "timestamp-too-new",
+            BlockError::DuplicateTransaction(_) => "duplicate-transaction",
+            BlockError::MissingTransaction(_) => "missing-transaction",
+            BlockError::InvalidTransaction(..) => "invalid-transaction",
+            BlockError::MissingCoinbase => "missing-coinbase",
+            BlockError::InvalidCoinbaseHeight(_) => "invalid-coinbase-height",
+            BlockError::CoinbaseTooLarge { .. } => "coinbase-too-large",
+            BlockError::MissingUndo(_) => "missing-undo",
+        }
+    }
+
+    /// Misbehavior score of a peer that sends the block. A block that breaks the consensus
+    /// rules gets the peer banned at once; a block that is merely early, known or missing its
+    /// parent or transactions can come from honest peers too.
+    pub fn penalty(&self) -> u32 {
+        match self {
+            BlockError::AlreadyKnown
+            | BlockError::UnknownParent(_)
+            | BlockError::TimestampTooNew { .. }
+            | BlockError::MissingTransaction(_)
+            | BlockError::MissingUndo(_) => 0,
+            BlockError::InvalidHash
+            | BlockError::InsufficientWork
+            | BlockError::InvalidBits { .. }
+            | BlockError::InvalidIndex { .. }
+            | BlockError::TimestampTooOld { .. }
+            | BlockError::DuplicateTransaction(_)
+            | BlockError::InvalidTransaction(..)
+            | BlockError::MissingCoinbase
+            | BlockError::InvalidCoinbaseHeight(_)
+            | BlockError::CoinbaseTooLarge { .. } => 100,
+        }
+    }
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransactionError::InvalidHash => write!(f, "hash does not match contents"),
+            TransactionError::NoInputs => write!(f, "transaction has no inputs"),
+            TransactionError::NoOutputs => write!(f, "transaction has no outputs"),
+            TransactionError::InsufficientInputs { inputs, outputs } => {
+                write!(f, "inputs ({inputs}) do not cover outputs ({outputs})")
+            }
+            TransactionError::AmountOutOfRange => {
+                write!(f, "amounts are more than the {MAX_MONEY} that can exist")
+            }
+            TransactionError::MissingInput(outpoint) => {
+                write!(f, "output {outpoint} does not exist or is already spent")
+            }
+            TransactionError::DuplicateInput(outpoint) => {
+                write!(f, "output {outpoint} is spent more than once")
+            }
+            TransactionError::AmountMismatch(outpoint) => {
+                write!(f, "input amount does not match output {outpoint}")
+            }
+            TransactionError::InvalidScript(outpoint, error) => {
+                write!(f, "script spending output {outpoint} failed: {error}")
+            }
+            TransactionError::Conflict(outpoint) => {
+                write!(
+                    f,
+                    "output {outpoint} is already spent by a pending transaction"
+                )
+            }
+            TransactionError::FeeTooLow { fee, required } => {
+                write!(f, "fee {fee} is below the minimum relay fee of {required}")
+            }
+            TransactionError::Coinbase => {
+                write!(
+                    f,
+                    "coinbase transaction outside of the first position of a block"
+                )
+            }
+            TransactionError::ImmatureCoinbase(outpoint) => {
+                write!(f, "coinbase output {outpoint} is not mature yet")
+            }
+        }
+    }
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockError::AlreadyKnown => write!(f, "block is already known"),
+            BlockError::InvalidHash => write!(f, "hash does not match contents"),
+            BlockError::InsufficientWork => write!(f, "hash does not meet the difficulty target"),
+            BlockError::InvalidBits { expected, found } => {
+                write!(f, "bits {found:#010x} should be {expected:#010x}")
+            }
+            BlockError::UnknownParent(hash) => write!(f, "previous block {hash} is unknown"),
+            BlockError::InvalidIndex { expected, found } => {
+                write!(f, "index {found} should be {expected}")
+            }
+            BlockError::TimestampTooOld { median, found } => {
+                write!(
+                    f,
+                    "timestamp {found} is not after median time past {median}"
+                )
+            }
+            BlockError::TimestampTooNew { max, found } => {
+                write!(f, "timestamp {found} is after the maximum of {max}")
+            }
+            BlockError::DuplicateTransaction(hash) => {
+                write!(f, "transaction {hash} is included more than once")
+            }
+            BlockError::MissingTransaction(hash) => write!(f, "transaction {hash} is unknown"),
+            BlockError::InvalidTransaction(hash, error) => {
+                write!(f, "transaction {hash} is invalid: {error}")
+            }
+            BlockError::MissingCoinbase => write!(f, "first transaction is not a coinbase"),
+            BlockError::InvalidCoinbaseHeight(height) => {
+                write!(f, "coinbase does not commit to height {height}")
+            }
+            BlockError::CoinbaseTooLarge { max, found } => {
+                write!(f, "coinbase pays {found}, more than the maximum of {max}")
+            }
+            BlockError::MissingUndo(hash) => {
+                write!(f, "undo data of active block {hash} is missing")
+            }
+        }
+    }
+}
+
+impl std::error::Error for TransactionError {}
+
+impl std::error::Error for BlockError {}
+
+/// Check a transaction on its own, without looking at the rest of the chain
+pub fn validate_transaction(txn: &Transaction) -> Result<(), TransactionError> {
+    // the hash must cover the transaction contents
+    if !txn.has_valid_hash() {
+        return Err(TransactionError::InvalidHash);
+    }
+
+    // a transaction must spend something and pay to someone
+    if txn.vin.is_empty() {
+        return Err(TransactionError::NoInputs);
+    }
+    if txn.vout.is_empty() {
+        return Err(TransactionError::NoOutputs);
+    }
+
+    // no amount may be more than can ever exist
+    let outputs: Amount = txn
+        .output_value()
+        .ok_or(TransactionError::AmountOutOfRange)?;
+    let inputs: Amount = txn
+        .input_value()
+        .ok_or(TransactionError::AmountOutOfRange)?;
+
+    // coinbase transactions create coins, checked against the subsidy when their block connects
+    if txn.is_coinbase() {
+        return Ok(());
+    }
+
+    // the null outpoint can only be spent by the single input of a coinbase transaction
+    if txn.vin.iter().any(|vin| vin.outpoint().is_null()) {
+        return Err(TransactionError::Coinbase);
+    }
+
+    // other transactions must not spend more than they receive
+    if inputs < outputs {
+        return Err(TransactionError::InsufficientInputs { inputs, outputs });
+    }
+
+    Ok(())
+}
+
+/// Median timestamp of the last `MEDIAN_TIME_SPAN` blocks of `chain`
+pub fn median_time_past(chain: &[Block]) -> u64 {
+    let start: usize = chain.len().saturating_sub(MEDIAN_TIME_SPAN);
+    let mut timestamps: Vec<u64> = chain[start..].iter().map(|block| block.timestamp).collect();
+
+    if timestamps.is_empty() {
+        return 0;
+    }
+
+    timestamps.sort_unstable();
+    timestamps[timestamps.len() / 2]
+}
+
+/// Look up a transaction referenced by a block among mined, pending and staged transactions
+pub fn find_transaction(hash: &str) -> Option<Transaction> {
+    TransactionDB::new()
+        .find(hash)
+        .or_else(|| mempool().find(hash))
+}
+
+/// Check that the hash of `header` covers its contents and meets its target, and that it
+/// extends `parent`, or starts a new chain if there is no parent. Needs no transactions, so
+/// headers can be checked before their blocks are downloaded.
+pub fn validate_header(
+    header: &BlockHeader,
+    parent: Option<&BlockHeader>,
+) -> Result<(), BlockError> {
+    // the hash must cover the header and meet the target
+    if header.hash != header.calculate_hash() {
+        return Err(BlockError::InvalidHash);
+    }
+    if !meets_target(&header.hash, header.bits) {
+        return Err(BlockError::InsufficientWork);
+    }
+
+    // the header must link to its parent, or start a new chain
+    let expected_index: u32 = match parent {
+        Some(parent) => {
+            if parent.hash != header.previous_block {
+                return Err(BlockError::UnknownParent(header.previous_block.clone()));
+            }
+            parent.index + 1
+        }
+        None => {
+            if header.previous_block != GENESIS_PREVIOUS_BLOCK {
+                return Err(BlockError::UnknownParent(header.previous_block.clone()));
+            }
+            0
+        }
+    };
+    if header.index != expected_index {
+        return Err(BlockError::InvalidIndex {
+            expected: expected_index,
+            found: header.index,
+        });
+    }
+
+    Ok(())
+}
+
+/// Check that `block` can extend `branch`, the chain of its ancestors ordered from the first
+/// block. Spends are checked separately, when the block is connected to the UTXO set.
+pub fn validate_block(block: &Block, branch: &[Block]) -> Result<(), BlockError> {
+    // the merkle root must cover the transactions of the block
+    if !block.has_valid_hash() {
+        return Err(BlockError::InvalidHash);
+    }
+
+    let parent: Option<BlockHeader> = branch.last().map(Block::to_header);
+    validate_header(&block.to_header(), parent.as_ref())?;
+
+    // the difficulty target must follow from the branch being extended
+    let expected_bits: u32 = next_bits(branch);
+    if block.bits != expected_bits {
+        return Err(BlockError::InvalidBits {
+            expected: expected_bits,
+            found: block.bits,
+        });
+    }
+
+    // the timestamp must be after the median of recent blocks and not too far in the future
+    let median: u64 = median_time_past(branch);
+    if !branch.is_empty() && block.timestamp <= median {
+        return Err(BlockError::TimestampTooOld {
+            median,
+            found: block.timestamp,
+        });
+    }
+    let max: u64 = current_timestamp() + MAX_FUTURE_BLOCK_TIME;
+    if block.timestamp > max {
+        return Err(BlockError::TimestampTooNew {
+            max,
+            found: block.timestamp,
+        });
+    }
+
+    // the block must start with a coinbase transaction
+    if block.tx.is_empty() {
+        return Err(BlockError::MissingCoinbase);
+    }
+
+    // every referenced transaction must be known and valid
+    for (position, hash) in block.tx.iter().enumerate() {
+        if block.tx[..position].contains(hash) {
+            return Err(BlockError::DuplicateTransaction(hash.clone()));
+        }
+
+        let txn: Transaction =
+            find_transaction(hash).ok_or_else(|| BlockError::MissingTransaction(hash.clone()))?;
+
+        validate_transaction(&txn)
+            .map_err(|error| BlockError::InvalidTransaction(hash.clone(), error))?;
+
+        validate_coinbase(&txn, position, block.index)?;
+    }
+
+    Ok(())
+}
+
+/// Check that the transaction at `position` of the block at `height` is a coinbase if and only
+/// if it is the first one, and that the coinbase commits to the height of the block
+pub fn validate_coinbase(
+    txn: &Transaction,
+    position: usize,
+    height: u32,
+) -> Result<(), BlockError> {
+    if position == 0 {
+        if !txn.is_coinbase() {
+            return Err(BlockError::MissingCoinbase);
+        }
+        if txn.coinbase_height() != Some(height) {
+            return Err(BlockError::InvalidCoinbaseHeight(height));
+        }
+    } else if txn.is_coinbase() {
+        return Err(BlockError::InvalidTransaction(
+            txn.hash.clone(),
+            TransactionError::Coinbase,
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::difficulty::POW_LIMIT_BITS;
+    use crate::modules::script::{Script, OP_1};
+    use crate::modules::transactions::{Vin, Vout};
+
+    fn coinbase(height: u32) -> Transaction {
+        Transaction::coinbase(
+            height,
+            0,
+            Script::new().push_opcode(OP_1),
+            Amount::from_sat(1),
+        )
+    }
+
+    // a transaction spending `inputs` satoshis to an output of `outputs` satoshis
+    fn transfer(inputs: u64, outputs: u64) -> Transaction {
+        let vin: Vin = Vin::new("11".repeat(32), 0, Amount::from_sat(inputs));
+        let vout: Vout = Vout::new(Script::new().push_opcode(OP_1), Amount::from_sat(outputs));
+        Transaction::new(0, vec![vin], vec![vout])
+    }
+
+    // find a nonce for which the hash of `block` meets its target
+    fn mine(mut block: Block) -> Block {
+        loop {
+            block.hash = block.calculate_hash();
+            if meets_target(&block.hash, block.bits) {
+                return block;
+            }
+            block.nonce += 1;
+        }
+    }
+
+    // a mined block on top of the last block of `branch`, holding no transactions
+    fn empty_block(branch: &[Block], timestamp: u64) -> Block {
+        let (index, previous) = match branch.last() {
+            Some(parent) => (parent.index + 1, parent.hash.clone()),
+            None => (0, GENESIS_PREVIOUS_BLOCK.to_string()),
+        };
+        mine(Block::new(
+            index,
+            timestamp,
+            Vec::new(),
+            previous,
+            next_bits(branch),
+        ))
+    }
+
+    #[test]
+    fn malformed_transactions_are_rejected() {
+        assert!(validate_transaction(&transfer(2, 1)).is_ok());
+
+        let mut tampered: Transaction = transfer(2, 1);
+        tampered.timestamp += 1;
+        assert_eq!(
+            validate_transaction(&tampered),
+            Err(TransactionError::InvalidHash)
+        );
+
+        let no_inputs: Transaction = Transaction::new(0, Vec::new(), transfer(2, 1).vout);
+        assert_eq!(
+            validate_transaction(&no_inputs),
+            Err(TransactionError::NoInputs)
+        );
+
+        let no_outputs: Transaction = Transaction::new(0, transfer(2, 1).vin, Vec::new());
+        assert_eq!(
+            validate_transaction(&no_outputs),
+            Err(TransactionError::NoOutputs)
+        );
+
+        let too_much: u64 = MAX_MONEY.to_sat() + 1;
+        assert_eq!(
+            validate_transaction(&transfer(too_much, 1)),
+            Err(TransactionError::AmountOutOfRange)
+        );
+
+        assert_eq!(
+            validate_transaction(&transfer(1, 2)),
+            Err(TransactionError::InsufficientInputs {
+                inputs: Amount::from_sat(1),
+                outputs: Amount::from_sat(2),
+            })
+        );
+
+        // the null outpoint can't be spent along with other outputs
+        let mut vin: Vec<Vin> = coinbase(7).vin;
+        vin.extend(transfer(2, 1).vin);
+        let spends_null: Transaction = Transaction::new(0, vin, transfer(2, 1).vout);
+        assert_eq!(
+            validate_transaction(&spends_null),
+            Err(TransactionError::Coinbase)
+        );
+    }
+
+    #[test]
+    fn malformed_headers_are_rejected() {
+        let branch: Vec<Block> = vec![empty_block(&[], 1)];
+        let first: BlockHeader = branch[0].to_header();
+        assert!(validate_header(&first, None).is_ok());
+
+        let mut tampered: BlockHeader = first.clone();
+        tampered.timestamp += 1;
+        assert_eq!(
+            validate_header(&tampered, None),
+            Err(BlockError::InvalidHash)
+        );
+
+        // the hash of an unmined header almost never meets a hard target
+        let mut unmined: BlockHeader = first.clone();
+        unmined.bits = 0x1d00ffff;
+        unmined.hash = unmined.calculate_hash();
+        assert_eq!(
+            validate_header(&unmined, None),
+            Err(BlockError::InsufficientWork)
+        );
+
+        let second: BlockHeader = empty_block(&branch, 2).to_header();
+        assert!(validate_header(&second, Some(&first)).is_ok());
+        assert_eq!(
+            validate_header(&second, None),
+            Err(BlockError::UnknownParent(first.hash.clone()))
+        );
+        assert_eq!(
+            validate_header(&second, Some(&second)),
+            Err(BlockError::UnknownParent(first.hash.clone()))
+        );
+
+        let mut skipped: Block = empty_block(&branch, 2);
+        skipped.index = 2;
+        let skipped: BlockHeader = mine(skipped).to_header();
+        assert_eq!(
+            validate_header(&skipped, Some(&first)),
+            Err(BlockError::InvalidIndex {
+                expected: 1,
+                found: 2
+            })
+        );
+    }
+
+    #[test]
+    fn blocks_that_dont_follow_their_branch_are_rejected() {
+        let branch: Vec<Block> = vec![empty_block(&[], 1)];
+        let block: Block = empty_block(&branch, 2);
+
+        // the merkle root must cover the transactions
+        let mut tampered: Block = block.clone();
+        tampered.tx.push("11".repeat(32));
+        assert_eq!(
+            validate_block(&tampered, &branch),
+            Err(BlockError::InvalidHash)
+        );
+
+        // the block pays for a target other than the one retargeting gives
+        let mut harder: Block = block.clone();
+        harder.bits = 0x1f00fffe;
+        harder.nonce = 0;
+        let harder: Block = mine(harder);
+        assert_eq!(
+            validate_block(&harder, &branch),
+            Err(BlockError::InvalidBits {
+                expected: POW_LIMIT_BITS,
+                found: 0x1f00fffe
+            })
+        );
+
+        // only the hash of the parent is checked against the block, so its timestamp can move
+        let mut later: Vec<Block> = branch.clone();
+        later[0].timestamp = 2;
+        assert_eq!(
+            validate_block(&block, &later),
+            Err(BlockError::TimestampTooOld {
+                median: 2,
+                found: 2
+            })
+        );
+
+        // a valid header without transactions lacks the coinbase
+        assert_eq!(
+            validate_block(&block, &branch),
+            Err(BlockError::MissingCoinbase)
+        );
+    }
+
+    #[test]
+    fn consensus_violations_ban_at_once() {
+        let invalid: Vec<BlockError> = vec![
+            BlockError::InvalidHash,
+            BlockError::InsufficientWork,
+            BlockError::InvalidIndex {
+                expected: 1,
+                found: 2,
+            },
+            BlockError::MissingCoinbase,
+            BlockError::InvalidTransaction("11".repeat(32), TransactionError::NoInputs),
+        ];
+        for error in invalid {
+            assert_eq!(error.penalty(), 100, "{}", error.reason());
+        }
+
+        // blocks honest peers may send
+        let early: Vec<BlockError> = vec![
+            BlockError::AlreadyKnown,
+            BlockError::UnknownParent("11".repeat(32)),
+            BlockError::MissingTransaction("11".repeat(32)),
+            BlockError::TimestampTooNew { max: 1, found: 2 },
+        ];
+        for error in early {
+            assert_eq!(error.penalty(), 0, "{}", error.reason());
+        }
+    }
+
+    #[test]
+    fn transaction_penalties_spare_local_policy() {
+        assert_eq!(TransactionError::InvalidHash.penalty(), 20);
+        assert_eq!(TransactionError::NoInputs.penalty(), 10);
+        assert_eq!(TransactionError::Conflict("11".repeat(32)).penalty(), 0);
+        assert_eq!(TransactionError::MissingInput("11".repeat(32)).penalty(), 0);
+
+        let fee_too_low: TransactionError = TransactionError::FeeTooLow {
+            fee: Amount::from_sat(1),
+            required: Amount::from_sat(2),
+        };
+        assert_eq!(fee_too_low.penalty(), 0);
+        assert_eq!(fee_too_low.reason(), "fee-too-low");
+    }
+
+    #[test]
+    fn coinbase_commits_to_the_block_height() {
+        assert!(validate_coinbase(&coinbase(7), 0, 7).is_ok());
+        assert!(matches!(
+            validate_coinbase(&coinbase(7), 0, 8),
+            Err(BlockError::InvalidCoinbaseHeight(8))
+        ));
+    }
+
+    #[test]
+    fn only_the_first_transaction_is_a_coinbase() {
+        let vin: Vin = Vin::new("11".repeat(32), 0, Amount::from_sat(1));
+        let vout: Vout = Vout::new(Script::new().push_opcode(OP_1), Amount::from_sat(1));
+        let spend: Transaction = Transaction::new(0, vec![vin], vec![vout]);
+
+        assert!(matches!(
+            validate_coinbase(&spend, 0, 7),
+            Err(BlockError::MissingCoinbase)
+        ));
+        assert!(validate_coinbase(&spend, 1, 7).is_ok());
+        assert!(matches!(
+            validate_coinbase(&coinbase(7), 1, 7),
+            Err(BlockError::InvalidTransaction(
+                _,
+                TransactionError::Coinbase
+            ))
+        ));
+    }
+}
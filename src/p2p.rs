@@ -1,28 +1,90 @@
-use std::net::{IpAddr, SocketAddr};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 // use jsonrpc::simple_tcp::TcpTransport;
+use jsonrpc::http::simple_http::{self, SimpleHttpTransport};
 use jsonrpc::Client;
 use jsonrpc::{Error, Request, Response};
-use jsonrpsee_server::types::Params;
-use jsonrpsee_server::ServerHandle;
+use jsonrpsee_server::types::error::INVALID_PARAMS_CODE;
+use jsonrpsee_server::types::{ErrorObjectOwned, Params};
 // use jsonrpsee::server::{RpcModule, Server};
-use jsonrpsee_server::{RpcModule, Server};
-use serde::{de::DeserializeOwned, Serialize};
+use jsonrpsee_server::{
+    serve_with_graceful_shutdown, stop_channel, Extensions, HttpRequest, Methods, RpcModule,
+    Server, ServerHandle, StopHandle,
+};
+use serde::de::DeserializeOwned;
 use serde_json::value::{to_raw_value, RawValue};
-
-use crate::database::{BaseDB, BlockchainDB, TransactionDB, UnTransactionDB};
-use crate::modules::blockchain::Block;
-use crate::modules::node::{get_nodes, write_node};
+use serde_json::Value;
+use tokio::net::TcpListener;
+use tokio::task::{self, JoinHandle};
+use tokio::time::sleep;
+use tower::Service;
+
+use crate::database::{BaseDB, BlockchainDB, TransactionDB};
+use crate::modules::ban::{is_banned, misbehaving, MALFORMED_PENALTY};
+use crate::modules::blockchain::{Block, BlockHeader};
+use crate::modules::chain::{block_transactions, headers_after, BlockTree};
+use crate::modules::gossip::{
+    inventory_data, receive_block, receive_inventory, receive_transaction, Inventory, InventoryData,
+};
+use crate::modules::mempool::mempool;
+use crate::modules::merkle::{merkle_proof, MerkleProof};
+use crate::modules::node::{best_nodes, get_nodes, write_node, MAX_ADDR_HOPS, MAX_ADDR_RESPONSE};
+use crate::modules::sync::{BlockData, MAX_BLOCKS, MAX_HEADERS};
 use crate::modules::transactions::Transaction;
+use crate::modules::validation::{validate_transaction, BlockError, TransactionError};
+
+// JSON-RPC error code for a block that failed validation
+const BLOCK_REJECTED_CODE: i32 = -32001;
+
+// JSON-RPC error code for a transaction that failed validation
+const TRANSACTION_REJECTED_CODE: i32 = -32002;
+
+// JSON-RPC error code for a call from a banned node
+const BANNED_CODE: i32 = -32003;
+
+// JSON-RPC error code for a call whose peer address is unknown
+const UNKNOWN_PEER_CODE: i32 = -32004;
+
+/// How long a peer may take to accept, read or answer a broadcast call
+pub const BROADCAST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Number of times a broadcast call is repeated when the peer can't be reached
+pub const MAX_RETRIES: u32 = 2;
+
+/// Delay before the first retry, doubling with every further retry
+pub const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Port of the RPC server when an address doesn't specify one
+pub const DEFAULT_PORT: u16 = 8332;
+
+/// Reasons a node address can't be used
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AddressError {
+    // the address is empty
+    Empty,
+    // the address uses a scheme other than http
+    UnsupportedScheme(String),
+    // the host is neither an IP address nor a valid hostname
+    InvalidHost(String),
+    // the port is not a number between 1 and 65535
+    InvalidPort(String),
+    // the hostname couldn't be resolved
+    Unresolved(String),
+}
 
 // represent the current node as a RPC Server ready to receive connections
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 struct RPCServer {
-    // server address: `tcp://127.0.0.1:8000
-    // server: String,
+    // address peers reach the server at, if it is known
+    address: Option<String>,
 }
 
 // represent the current node ready to send connections
+#[allow(dead_code)]
 pub struct RPCClient {
     // server address
     node: String,
@@ -30,115 +92,223 @@ pub struct RPCClient {
     client: Client,
 }
 
-struct BroadCast {}
+pub struct BroadCast {}
 
-impl BroadCast {
-    pub fn ping(args: Vec<String>) {
-        // collect all nodes from local database
-        let clients: Vec<RPCClient> = get_clients();
-
-        // interate through all clients
-        for client in clients {
-            // make RPC call
-            match client.ping(args.clone()) {
-                Ok(_result) => {}
-                Err(_result) => {}
-            }
+// params of a call together with the peer that made it
+struct CallParams {
+    values: Vec<Value>,
+    // IP address of the connection the call came in on
+    peer: IpAddr,
+}
+
+/// Reasons a broadcast to a peer fails
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BroadcastError {
+    // the peer answered with an error
+    Rejected(String),
+    // the peer couldn't be reached
+    Unreachable(String),
+    // the peer didn't answer in time
+    Timeout,
+}
+
+impl fmt::Display for BroadcastError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BroadcastError::Rejected(message) => write!(f, "rejected: {message}"),
+            BroadcastError::Unreachable(message) => write!(f, "unreachable: {message}"),
+            BroadcastError::Timeout => write!(f, "timed out"),
         }
     }
+}
 
-    fn get_blockchain(args: Vec<String>) -> Result<Vec<Vec<Block>>, Error> {
-        // collect all nodes from local database
-        let clients: Vec<RPCClient> = get_clients();
+impl std::error::Error for BroadcastError {}
 
-        // storing all blocks from every node
-        let mut all_blocks: Vec<Vec<Block>> = vec![];
+/// Outcome of a broadcast to one peer
+#[derive(Debug)]
+pub struct PeerResult<T> {
+    // address of the peer
+    pub node: String,
+    pub result: Result<T, BroadcastError>,
+    // duration of the last attempt
+    pub elapsed: Duration,
+}
 
-        // interate through all clients
-        for client in clients {
-            // make RPC call
-            all_blocks.push(client.get_blockchain(args.clone())?);
+impl BroadCast {
+    /// Make the same call to every client concurrently, retrying calls that don't reach the
+    /// peer. Returns the outcome for every peer.
+    async fn fan_out<T, F>(clients: Vec<RPCClient>, call: F) -> Vec<PeerResult<T>>
+    where
+        T: Send + 'static,
+        F: Fn(&RPCClient) -> Result<T, Error> + Send + Sync + 'static,
+    {
+        let call: Arc<F> = Arc::new(call);
+
+        // start a task per peer, so a slow peer doesn't hold up the others
+        let tasks: Vec<(String, JoinHandle<PeerResult<T>>)> = clients
+            .into_iter()
+            .map(|client| {
+                let node: String = client.node.clone();
+                (
+                    node,
+                    tokio::spawn(call_peer(Arc::new(client), call.clone())),
+                )
+            })
+            .collect();
+
+        let mut results: Vec<PeerResult<T>> = Vec::with_capacity(tasks.len());
+        for (node, task) in tasks {
+            let start: Instant = Instant::now();
+
+            // a task that panicked or was cancelled still reports its peer
+            results.push(task.await.unwrap_or_else(|error| PeerResult {
+                node,
+                result: Err(BroadcastError::Unreachable(error.to_string())),
+                elapsed: start.elapsed(),
+            }));
         }
 
-        Ok(all_blocks)
+        results
     }
 
-    fn new_block(block: Block) {
-        // collect all nodes from local database
-        let clients: Vec<RPCClient> = get_clients();
+    pub async fn ping(args: Vec<String>) -> Vec<PeerResult<bool>> {
+        // collect all nodes from local database and make the RPC call to each
+        Self::fan_out(get_clients(), move |client| client.ping(args.clone())).await
+    }
 
-        // interate through all clients
-        for client in clients {
-            // make RPC call
-            match client.new_block(block.clone()) {
-                Ok(_result) => _result,
-                Err(_) => {}
-            };
-        }
+    #[allow(dead_code)]
+    pub async fn get_blockchain(args: Vec<String>) -> Vec<PeerResult<Vec<Block>>> {
+        // collect all nodes from local database and make the RPC call to each
+        Self::fan_out(get_clients(), move |client| {
+            client.get_blockchain(args.clone())
+        })
+        .await
     }
 
-    fn add_node(address: String) {
-        // collect all nodes from local database
-        let clients: Vec<RPCClient> = get_clients();
+    pub async fn new_block(block: Block) -> Vec<PeerResult<()>> {
+        // collect all nodes from local database and make the RPC call to each
+        Self::fan_out(get_clients(), move |client| client.new_block(block.clone())).await
+    }
 
-        // interate through all clients
-        for client in clients {
-            // make RPC call
-            match client.add_node(address.clone()) {
-                Ok(_result) => _result,
-                Err(_) => {}
-            };
-        }
+    /// Announce a node to all peers, which relay it `hops` more times
+    pub async fn add_node(address: String, hops: u32) -> Vec<PeerResult<()>> {
+        // collect all nodes from local database, not telling the node about itself
+        let clients: Vec<RPCClient> = get_clients()
+            .into_iter()
+            .filter(|client| client.node != address)
+            .collect();
+
+        // make the RPC call to each
+        Self::fan_out(clients, move |client| {
+            client.add_node(address.clone(), hops)
+        })
+        .await
     }
 
-    pub fn get_transactions(args: Vec<String>) -> Result<Vec<Vec<Transaction>>, Error> {
-        // collect all nodes from local database
-        let clients: Vec<RPCClient> = get_clients();
-        let mut all_transactions: Vec<Vec<Transaction>> = vec![];
+    #[allow(dead_code)]
+    pub async fn get_transactions(args: Vec<String>) -> Vec<PeerResult<Vec<Transaction>>> {
+        // collect all nodes from local database and make the RPC call to each
+        Self::fan_out(get_clients(), move |client| {
+            client.get_transactions(args.clone())
+        })
+        .await
+    }
 
-        // interate through all clients
-        for client in clients {
-            // make RPC call
-            all_transactions.push(client.get_transactions(args.clone())?);
-        }
+    /// Announce items available from the node at `address` to all peers except `except`
+    pub async fn inv(
+        items: Vec<Inventory>,
+        address: String,
+        except: Option<String>,
+    ) -> Vec<PeerResult<()>> {
+        // collect all nodes from local database, skipping the node itself and the sender
+        let clients: Vec<RPCClient> = get_clients()
+            .into_iter()
+            .filter(|client| client.node != address && Some(&client.node) != except.as_ref())
+            .collect();
+
+        // make the RPC call to each
+        Self::fan_out(clients, move |client| client.inv(&items, &address)).await
+    }
 
-        Ok(all_transactions)
+    pub async fn new_untransaction(txn: Transaction) -> Vec<PeerResult<()>> {
+        // collect all nodes from local database and make the RPC call to each
+        Self::fan_out(get_clients(), move |client| {
+            client.new_untransaction(txn.clone())
+        })
+        .await
     }
 
-    fn new_untransaction<T: Copy + Serialize + DeserializeOwned>(args: T) {
-        // collect all nodes from local database
-        let clients: Vec<RPCClient> = get_clients();
+    pub async fn block_transaction(txn: Transaction) -> Vec<PeerResult<()>> {
+        // collect all nodes from local database and make the RPC call to each
+        Self::fan_out(get_clients(), move |client| {
+            client.block_transaction(txn.clone())
+        })
+        .await
+    }
+}
 
-        // interate through all clients
-        for client in clients {
-            // make RPC call
-            match client.new_untransaction(args.clone()) {
-                Ok(_result) => _result,
-                Err(_) => {}
+/// Make a call to a single peer, retrying with exponential backoff while the
+/// peer can't be reached. A peer that answers with an error isn't asked again.
+async fn call_peer<T, F>(client: Arc<RPCClient>, call: Arc<F>) -> PeerResult<T>
+where
+    T: Send + 'static,
+    F: Fn(&RPCClient) -> Result<T, Error> + Send + Sync + 'static,
+{
+    let mut attempt: u32 = 0;
+
+    loop {
+        let start: Instant = Instant::now();
+
+        // the RPC client is blocking, so the call runs off the async runtime. The client's
+        // transport gives up after `BROADCAST_TIMEOUT`, which ends the blocking task with it
+        let (peer, request) = (client.clone(), call.clone());
+        let call_result = task::spawn_blocking(move || request(&peer)).await;
+
+        let result: Result<T, BroadcastError> = match call_result {
+            Ok(Ok(value)) => Ok(value),
+            Ok(Err(Error::Rpc(error))) => Err(BroadcastError::Rejected(error.message)),
+            Ok(Err(error)) if is_timeout(&error) => Err(BroadcastError::Timeout),
+            Ok(Err(error)) => Err(BroadcastError::Unreachable(error.to_string())),
+            Err(error) => Err(BroadcastError::Unreachable(error.to_string())),
+        };
+
+        let retry: bool = matches!(
+            result,
+            Err(BroadcastError::Unreachable(_) | BroadcastError::Timeout)
+        );
+
+        if !retry || attempt >= MAX_RETRIES {
+            return PeerResult {
+                node: client.node.clone(),
+                result,
+                elapsed: start.elapsed(),
             };
         }
-    }
 
-    fn block_transaction<T: Copy + Serialize + DeserializeOwned>(txn: T) {
-        // collect all nodes from local database
-        let clients: Vec<RPCClient> = get_clients();
+        sleep(RETRY_BACKOFF * 2u32.pow(attempt)).await;
+        attempt += 1;
+    }
+}
 
-        // interate through all clients
-        for client in clients {
-            // make RPC call
-            if let Ok(result) = client.block_transaction(txn) {
-                result
-            }
-        }
+/// Check if a call failed because the peer didn't answer within the transport's timeout
+fn is_timeout(error: &Error) -> bool {
+    let Error::Transport(error) = error else {
+        return false;
+    };
+
+    match error.downcast_ref::<simple_http::Error>() {
+        Some(simple_http::Error::SocketError(error)) => matches!(
+            error.kind(),
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+        ),
+        _ => false,
     }
 }
 
 impl RPCServer {
-    fn new() -> RPCServer {
+    fn new(address: Option<String>) -> RPCServer {
         // return an initialized RPC Server
-        RPCServer {
-            // server
-        }
+        RPCServer { address }
     }
 
     /// Check for connectivity
@@ -153,20 +323,82 @@ impl RPCServer {
         BlockchainDB::new().find_all()
     }
 
-    /// Add a new block to the local database
-    fn new_block<T: Serialize + DeserializeOwned>(&self, block: T) {
-        // insert a new block to the blockchain database
-        BlockchainDB::new().insert(block).unwrap();
+    /// Get the headers of the active chain following the last block of `locator` on it
+    fn get_headers(&self, locator: Vec<String>, max: usize) -> Vec<BlockHeader> {
+        let chain: Vec<Block> = BlockchainDB::new().find_all();
+
+        headers_after(&chain, &locator, max.min(MAX_HEADERS))
+    }
+
+    /// Get known blocks by their hashes, together with their transactions
+    fn get_blocks(&self, hashes: Vec<String>) -> Vec<BlockData> {
+        let tree: BlockTree = BlockTree::load();
+
+        hashes
+            .iter()
+            .take(MAX_BLOCKS)
+            .filter_map(|hash| tree.get(hash))
+            .map(|block| BlockData {
+                block: block.clone(),
+                transactions: block_transactions(block),
+            })
+            .collect()
+    }
+
+    /// Validate a new block from the peer at `peer` and add it to the local database
+    fn new_block(&self, block: Block, peer: IpAddr) -> Result<(), BlockError> {
+        let hash: String = block.hash.clone();
+
+        // the transactions of a pushed block are sent ahead of it
+        let data: BlockData = BlockData {
+            block,
+            transactions: vec![],
+        };
+
+        // reject blocks that can't extend the local chain
+        if let Err(error) = receive_block(data, None, self.address.as_deref()) {
+            println!("Rejected block {hash}: {error}");
+            misbehaving(peer, error.penalty(), error.reason());
+            return Err(error);
+        }
+
+        println!("Received New Block");
+        Ok(())
+    }
+
+    /// Add a node announced by the peer at `peer` to the local database and relay it to all
+    /// peers if it is new and has `hops` left
+    fn add_node(&self, address: String, hops: u32, peer: IpAddr) -> Result<(), AddressError> {
+        match write_node(address.clone()) {
+            Ok(true) if hops > 0 => {
+                let address: String = normalize_address(&address)?;
+                tokio::spawn(BroadCast::add_node(address, hops - 1));
+            }
+            Ok(_) => {}
+            Err(error) => {
+                println!("Rejected node {address}: {error}");
+                misbehaving(peer, MALFORMED_PENALTY, "invalid-address");
+                return Err(error);
+            }
+        }
 
-        // clear database that stores transactions to be mined
-        UnTransactionDB::new().clear().unwrap();
+        Ok(())
+    }
 
-        println!("Received New Block")
+    /// Get the addresses of the best known nodes
+    fn get_peers(&self) -> Vec<String> {
+        best_nodes(MAX_ADDR_RESPONSE)
     }
 
-    /// Add a node to the local database
-    fn add_node(&self, address: String) {
-        write_node(address);
+    /// Get the hash of the block including a transaction and a merkle proof of its inclusion
+    fn get_merkle_proof(&self, txid: String) -> Option<(String, MerkleProof)> {
+        // locate the block that includes the transaction
+        let block: Block = BlockchainDB::new().find_by_transaction(&txid)?;
+
+        // prove the transaction against the block's merkle root
+        let proof: MerkleProof = merkle_proof(&block.tx, &txid)?;
+
+        Some((block.hash, proof))
     }
 
     /// Get all transactions from the local database
@@ -176,41 +408,72 @@ impl RPCServer {
         TransactionDB::new().find_all()
     }
 
-    /// Add an un-mined transaction to the local database
-    fn new_untransaction<T: Serialize + DeserializeOwned>(&self, untxns: T) {
-        // TODO: What if it fails to insert the transaction?
-        UnTransactionDB::new().insert(untxns).unwrap()
+    /// Get the pending transactions of the mempool, parents before children
+    fn get_mempool(&self) -> Vec<Transaction> {
+        mempool().transactions()
     }
 
-    /// Write a new mined transaction to the local database
-    fn block_transaction<T: Serialize + DeserializeOwned>(&self, txns: T) {
-        println!("Received new block transaction!");
+    /// Validate an un-mined transaction from the peer at `peer` and add it to the local
+    /// database
+    fn new_untransaction(&self, untxn: Transaction, peer: IpAddr) -> Result<(), TransactionError> {
+        let hash: String = untxn.hash.clone();
 
-        // TODO: What if it fails to write a transaction?
-        TransactionDB::new().write(txns).unwrap()
-    }
-}
+        // the transaction must be well formed and spend outputs of the local chain
+        if let Err(error) = receive_transaction(untxn, None, self.address.as_deref()) {
+            println!("Rejected transaction {hash}: {error}");
+            misbehaving(peer, error.penalty(), error.reason());
+            return Err(error);
+        }
 
-impl RPCClient {
-    pub fn new(node: String) -> RPCClient {
-        // If any, strip scheme from address
-        let stripped_node: String = node.strip_prefix("http://").unwrap_or(&node).to_string();
+        Ok(())
+    }
 
-        // split the node address `127.0.0.1:8000` to `127.0.0.1` and `8080`
-        let address_split: Vec<&str> = stripped_node.split(':').collect();
+    /// Request the items the peer at `peer` announced that are missing from the node at
+    /// `sender`
+    fn inv(&self, items: Vec<Inventory>, sender: String, peer: IpAddr) -> Result<(), AddressError> {
+        receive_inventory(items, sender, peer, self.address.as_deref())
+    }
 
-        // parse String to IP address
-        let addr: IpAddr = address_split[0].parse().unwrap();
+    /// Get the requested items that are in the local database
+    fn get_data(&self, items: Vec<Inventory>) -> InventoryData {
+        inventory_data(&items)
+    }
 
-        // parse String to port
-        let port: u16 = address_split[1].parse::<u16>().unwrap();
+    /// Validate a transaction of a block that the peer at `peer` is about to push and stage it
+    /// in the mempool until the block arrives. Its spends are checked, and it is stored in the
+    /// local database, once the block is accepted.
+    fn block_transaction(&self, txn: Transaction, peer: IpAddr) -> Result<(), TransactionError> {
+        if let Err(error) = validate_transaction(&txn) {
+            println!("Rejected block transaction {}: {error}", txn.hash);
+            misbehaving(peer, error.penalty(), error.reason());
+            return Err(error);
+        }
 
-        // construct a RPC client
-        RPCClient {
-            node,
-            // construct client with transport tcp transport protocol
-            client: Client::simple_http("http://127.0.0.1:8332", None, None).unwrap(),
+        println!("Received new block transaction!");
+        if TransactionDB::new().find(&txn.hash).is_none() {
+            mempool().stage(txn);
         }
+        Ok(())
+    }
+}
+
+impl RPCClient {
+    /// Construct a client for the node at `node`, which may be an IP address or a hostname
+    /// with an optional port. Hostnames are resolved once, when the client is constructed.
+    pub fn new(node: String) -> Result<RPCClient, AddressError> {
+        // validate the address and bring it into the `http://host:port` form
+        let url: String = normalize_address(&node)?;
+
+        // construct client with the http transport protocol, giving up on a peer that
+        // doesn't answer in time
+        let transport: SimpleHttpTransport = SimpleHttpTransport::builder()
+            .url(&url)
+            .map_err(|_| AddressError::Unresolved(url.clone()))?
+            .timeout(BROADCAST_TIMEOUT)
+            .build();
+        let client: Client = Client::with_transport(transport);
+
+        Ok(RPCClient { node: url, client })
     }
 
     pub fn ping(&self, args: Vec<String>) -> Result<bool, Error> {
@@ -219,15 +482,15 @@ impl RPCClient {
 
         // build request with parameters
         let request: Request = self.client.build_request("ping", &params);
-        println!("About sending request");
+
         // send request
         let response: Response = self.client.send_request(request)?;
 
-        println!("Request sent");
         // deserialize response or an error
         response.result::<bool>()
     }
 
+    #[allow(dead_code)]
     fn get_blockchain(&self, args: Vec<String>) -> Result<Vec<Block>, Error> {
         // serialize arguments to raw json
         let params: [Box<RawValue>; 1] = [to_raw_value(&args)?];
@@ -241,6 +504,34 @@ impl RPCClient {
         response.result::<Vec<Block>>()
     }
 
+    /// Get up to `max` headers following the last block of `locator` the node has
+    pub fn get_headers(&self, locator: &[String], max: usize) -> Result<Vec<BlockHeader>, Error> {
+        // serialize arguments to raw json
+        let params: [Box<RawValue>; 2] = [to_raw_value(locator)?, to_raw_value(&max)?];
+
+        // build request with parameters
+        let request: Request = self.client.build_request("get_headers", &params);
+
+        // send request
+        let response: Response = self.client.send_request(request)?;
+        // deserialize response or return an error
+        response.result::<Vec<BlockHeader>>()
+    }
+
+    /// Get the blocks with the given hashes together with their transactions
+    pub fn get_blocks(&self, hashes: &[String]) -> Result<Vec<BlockData>, Error> {
+        // serialize arguments to raw json
+        let params: [Box<RawValue>; 1] = [to_raw_value(hashes)?];
+
+        // build request with parameters
+        let request: Request = self.client.build_request("get_blocks", &params);
+
+        // send request
+        let response: Response = self.client.send_request(request)?;
+        // deserialize response or return an error
+        response.result::<Vec<BlockData>>()
+    }
+
     fn new_block(&self, block: Block) -> Result<(), Error> {
         // serialize arguments to raw json
         let params: [Box<RawValue>; 1] = [to_raw_value(&block)?];
@@ -248,28 +539,38 @@ impl RPCClient {
         // construct request with parameters
         let request: Request = self.client.build_request("new_block", &params);
 
-        // send request || doesn't require a response
-        self.client.send_request(request)?;
+        // send request and check whether the block was accepted
+        self.client.send_request(request)?.check_error()?;
 
         // print debug message
         println!("Sent new block");
         Ok(())
     }
 
-    pub fn add_node(&self, address: String) -> Result<(), Error> {
+    pub fn add_node(&self, address: String, hops: u32) -> Result<(), Error> {
         // serialize arguments to raw json
-        let params: [Box<RawValue>; 1] = [to_raw_value(&address)?];
+        let params: [Box<RawValue>; 2] = [to_raw_value(&address)?, to_raw_value(&hops)?];
 
         // construct request with parameters
         let request: Request = self.client.build_request("add_node", &params);
 
-        println!("Adding node {address} to network");
-        // send request || doesn't require a respnse
-        let response = self.client.send_request(request)?;
-        println!("{response:?}");
+        // send request and check whether the node was accepted
+        self.client.send_request(request)?.check_error()?;
+
         Ok(())
     }
 
+    /// Get the addresses of the nodes the node knows
+    pub fn get_peers(&self) -> Result<Vec<String>, Error> {
+        // construct request without parameters
+        let request: Request = self.client.build_request("get_peers", &[]);
+
+        // send request
+        let response: Response = self.client.send_request(request)?;
+        // deserialize response or return an error
+        response.result::<Vec<String>>()
+    }
+
     pub fn get_transactions(&self, args: Vec<String>) -> Result<Vec<Transaction>, Error> {
         // serialize arguments to raw json
         let params: [Box<RawValue>; 1] = [to_raw_value(&args)?];
@@ -283,32 +584,235 @@ impl RPCClient {
         response.result::<Vec<Transaction>>()
     }
 
-    fn new_untransaction<T: Serialize + DeserializeOwned>(&self, args: T) -> Result<(), Error> {
+    /// Get the pending transactions of the node
+    pub fn get_mempool(&self) -> Result<Vec<Transaction>, Error> {
+        // construct request without parameters
+        let request: Request = self.client.build_request("get_mempool", &[]);
+
+        // send request
+        let response: Response = self.client.send_request(request)?;
+        // deserialize response or return an error
+        response.result::<Vec<Transaction>>()
+    }
+
+    /// Announce items available from the node at `address`
+    pub fn inv(&self, items: &[Inventory], address: &str) -> Result<(), Error> {
         // serialize arguments to raw json
-        let params: [Box<RawValue>; 1] = [to_raw_value(&args)?];
+        let params: [Box<RawValue>; 2] = [to_raw_value(items)?, to_raw_value(address)?];
+
+        // construct request with parameters
+        let request: Request = self.client.build_request("inv", &params);
+
+        // send request and check whether the announcement was accepted
+        self.client.send_request(request)?.check_error()?;
+
+        Ok(())
+    }
+
+    /// Get announced items by their hashes
+    pub fn get_data(&self, items: &[Inventory]) -> Result<InventoryData, Error> {
+        // serialize arguments to raw json
+        let params: [Box<RawValue>; 1] = [to_raw_value(items)?];
+
+        // construct request with parameters
+        let request: Request = self.client.build_request("get_data", &params);
+
+        // send request
+        let response: Response = self.client.send_request(request)?;
+        // deserialize response or return an error
+        response.result::<InventoryData>()
+    }
+
+    pub fn new_untransaction(&self, txn: Transaction) -> Result<(), Error> {
+        // serialize arguments to raw json
+        let params: [Box<RawValue>; 1] = [to_raw_value(&txn)?];
 
         // construct request with parameters
         let request: Request = self.client.build_request("new_untransaction", &params);
 
-        // send request || doesn't require response
-        self.client.send_request(request)?;
+        // send request and check whether the transaction was accepted
+        self.client.send_request(request)?.check_error()?;
 
         Ok(())
     }
 
-    fn block_transaction<T: Serialize + DeserializeOwned>(&self, txn: T) -> Result<(), Error> {
+    pub fn block_transaction(&self, txn: Transaction) -> Result<(), Error> {
         // serialize arguments to json
         let params: [Box<RawValue>; 1] = [to_raw_value(&txn)?];
 
         // construct request with parameters
         let request: Request = self.client.build_request("block_transaction", &params);
 
-        self.client.send_request(request)?;
+        // send request and check whether the transaction was accepted
+        self.client.send_request(request)?.check_error()?;
 
         Ok(())
     }
 }
 
+impl CallParams {
+    /// Read the params of a call together with the IP address of the peer that made it.
+    /// Calls from unknown or banned peers are refused.
+    fn new(params: Params, extensions: &Extensions) -> Result<CallParams, ErrorObjectOwned> {
+        let Some(peer) = extensions.get::<IpAddr>().copied() else {
+            let message: &str = "the address of the peer is unknown";
+            return Err(ErrorObjectOwned::owned(
+                UNKNOWN_PEER_CODE,
+                message,
+                None::<()>,
+            ));
+        };
+
+        if is_banned(peer) {
+            let message: String = format!("{peer} is banned");
+            return Err(ErrorObjectOwned::owned(BANNED_CODE, message, None::<()>));
+        }
+
+        let values: Vec<Value> = params.parse()?;
+
+        Ok(CallParams { values, peer })
+    }
+
+    /// Parse the parameter at `position`, a missing parameter being `null`. A malformed
+    /// parameter counts against the peer.
+    fn get<T: DeserializeOwned>(&self, position: usize) -> Result<T, ErrorObjectOwned> {
+        let value: Value = self.values.get(position).cloned().unwrap_or(Value::Null);
+
+        serde_json::from_value(value).map_err(|error| {
+            misbehaving(self.peer, MALFORMED_PENALTY, "malformed-params");
+            ErrorObjectOwned::owned(
+                INVALID_PARAMS_CODE,
+                "Invalid params",
+                Some(error.to_string()),
+            )
+        })
+    }
+}
+
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AddressError::Empty => write!(f, "address is empty"),
+            AddressError::UnsupportedScheme(scheme) => {
+                write!(f, "scheme {scheme} is not supported, use http")
+            }
+            AddressError::InvalidHost(host) => write!(f, "{host} is not a valid host"),
+            AddressError::InvalidPort(port) => write!(f, "{port} is not a valid port"),
+            AddressError::Unresolved(url) => write!(f, "could not resolve {url}"),
+        }
+    }
+}
+
+impl std::error::Error for AddressError {}
+
+/// Check if `host` is a valid DNS hostname
+fn is_valid_hostname(host: &str) -> bool {
+    !host.is_empty()
+        && host.len() <= 253
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// Validate a node address and bring it into the `http://host:port` form. Accepts IPv4
+/// addresses, IPv6 addresses (in brackets when followed by a port) and hostnames, with or
+/// without the `http://` scheme. The port defaults to `DEFAULT_PORT`.
+pub fn normalize_address(node: &str) -> Result<String, AddressError> {
+    let node: &str = node.trim();
+
+    // strip the scheme
+    let rest: &str = match node.split_once("://") {
+        Some(("http", rest)) => rest,
+        Some((scheme, _)) => return Err(AddressError::UnsupportedScheme(scheme.to_string())),
+        None => node,
+    };
+    let rest: &str = rest.trim_end_matches('/');
+
+    if rest.is_empty() {
+        return Err(AddressError::Empty);
+    }
+
+    // separate the host from the port
+    let (host, port): (&str, Option<&str>) = if let Some(bracketed) = rest.strip_prefix('[') {
+        // an IPv6 address in brackets, optionally followed by a port
+        let (host, after) = bracketed
+            .split_once(']')
+            .ok_or_else(|| AddressError::InvalidHost(rest.to_string()))?;
+
+        match after {
+            "" => (host, None),
+            _ => match after.strip_prefix(':') {
+                Some(port) => (host, Some(port)),
+                None => return Err(AddressError::InvalidPort(after.to_string())),
+            },
+        }
+    } else if rest.matches(':').count() > 1 {
+        // a bare IPv6 address can't be followed by a port
+        (rest, None)
+    } else {
+        match rest.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (rest, None),
+        }
+    };
+
+    let port: u16 = match port {
+        Some(port) => port
+            .parse::<u16>()
+            .ok()
+            .filter(|port| *port != 0)
+            .ok_or_else(|| AddressError::InvalidPort(port.to_string()))?,
+        None => DEFAULT_PORT,
+    };
+
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => Ok(format!("http://{ip}:{port}")),
+        Ok(IpAddr::V6(ip)) => Ok(format!("http://[{ip}]:{port}")),
+        Err(_) if is_valid_hostname(host) => {
+            Ok(format!("http://{}:{port}", host.to_ascii_lowercase()))
+        }
+        Err(_) => Err(AddressError::InvalidHost(host.to_string())),
+    }
+}
+
+// Send the rejection reason of a block back to the peer that sent it
+impl From<BlockError> for ErrorObjectOwned {
+    fn from(error: BlockError) -> ErrorObjectOwned {
+        ErrorObjectOwned::owned(BLOCK_REJECTED_CODE, error.to_string(), Some(error.reason()))
+    }
+}
+
+// Send the rejection reason of a transaction back to the peer that sent it
+impl From<TransactionError> for ErrorObjectOwned {
+    fn from(error: TransactionError) -> ErrorObjectOwned {
+        ErrorObjectOwned::owned(
+            TRANSACTION_REJECTED_CODE,
+            error.to_string(),
+            Some(error.reason()),
+        )
+    }
+}
+
+// A node address that can't be used is an invalid parameter
+impl From<AddressError> for ErrorObjectOwned {
+    fn from(error: AddressError) -> ErrorObjectOwned {
+        ErrorObjectOwned::owned(INVALID_PARAMS_CODE, error.to_string(), None::<()>)
+    }
+}
+
+/// Resolve the IP address of the node at `node`, which may also be a bare IP address
+pub fn node_ip(node: &str) -> Result<IpAddr, AddressError> {
+    let url: String = normalize_address(node)?;
+
+    listen_address(&url)
+        .map(|address| address.ip())
+        .map_err(|_| AddressError::Unresolved(url))
+}
+
 // Returns an iterable RPCClient(s)
 fn get_clients() -> Vec<RPCClient> {
     // placeholder to store queried clients
@@ -319,67 +823,310 @@ fn get_clients() -> Vec<RPCClient> {
 
     // iterate through all nodes
     for node in nodes {
-        // construct RPC client from the node
-        clients.push(RPCClient::new(node));
+        // construct RPC client from the node, skipping unusable addresses
+        match RPCClient::new(node.clone()) {
+            Ok(client) => clients.push(client),
+            Err(error) => eprintln!("Skipping node {node}: {error}"),
+        }
     }
 
     // return clients
     clients
 }
 
-// ip: String, port: u16
+/// Resolve the address the RPC server binds to. The port defaults to `DEFAULT_PORT`;
+/// port 0 lets the operating system pick a free port.
+fn listen_address(address: &str) -> io::Result<SocketAddr> {
+    let address: &str = address.strip_prefix("http://").unwrap_or(address);
+    let address: &str = address.trim_end_matches('/');
+
+    // fall back to the default port if the address has none
+    let mut addresses = match address.to_socket_addrs() {
+        Ok(addresses) => addresses,
+        Err(_) => {
+            let host: &str = address.trim_start_matches('[').trim_end_matches(']');
+            (host, DEFAULT_PORT).to_socket_addrs()?
+        }
+    };
+
+    addresses.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{address} did not resolve to an address"),
+        )
+    })
+}
+
+/// Address peers can reach a server bound to `bound` at: `advertise` if given, otherwise the
+/// bound address. Unspecified addresses like `0.0.0.0` can't be reached and are never used.
+fn advertised_address(
+    bound: SocketAddr,
+    advertise: Option<&str>,
+) -> Result<Option<String>, AddressError> {
+    let address: String = match advertise {
+        Some(address) => normalize_address(address)?,
+        None => normalize_address(&bound.to_string())?,
+    };
+
+    match listen_address(&address) {
+        Ok(resolved) if resolved.ip().is_unspecified() => match advertise {
+            Some(_) => Err(AddressError::InvalidHost(resolved.ip().to_string())),
+            None => Ok(None),
+        },
+        _ => Ok(Some(address)),
+    }
+}
+
+/// Start the RPC server on `address`, telling peers to reach it at `advertise` or else at the
+/// bound address. Returns the address it is bound to, the address it is advertised at, if any,
+/// and a handle for stopping it. The server keeps running until the handle is stopped.
+pub async fn start_server(
+    address: &str,
+    advertise: Option<&str>,
+) -> Result<(SocketAddr, Option<String>, ServerHandle), Box<dyn std::error::Error>> {
+    // bind the server, accepting connections once the methods are registered
+    let listener: TcpListener = TcpListener::bind(listen_address(address)?).await?;
 
-pub async fn start_server(_address: &str) -> Result<(), Box<dyn std::error::Error>> {
-    // debug purposes
-    let address = "0.0.0.0:8332";
+    // the actual address, with the port chosen by the operating system if port 0 was requested
+    let local_address: SocketAddr = listener.local_addr()?;
+    let advertised: Option<String> = advertised_address(local_address, advertise)?;
 
-    // initialize RPC Server
-    let rpc_server: RPCServer = RPCServer::new();
+    // initialize RPC Server, shared by all methods as their context
+    let rpc_server: RPCServer = RPCServer::new(advertised.clone());
 
     // initialize Input/Output handler
-    let mut io: RpcModule<()> = RpcModule::new(());
+    let mut io: RpcModule<RPCServer> = RpcModule::new(rpc_server);
 
-    // registering RPCServer methods
-    io.register_method("ping", move |_, _| rpc_server.ping())?;
-    io.register_method("get_blockchain", move |_, _| rpc_server.get_blockchain())?;
+    // registering RPCServer methods, malformed params are answered with an invalid params error
+    io.register_method("ping", |_, rpc_server, _| rpc_server.ping())?;
+    io.register_method("get_blockchain", |_, rpc_server, _| {
+        rpc_server.get_blockchain()
+    })?;
 
-    io.register_method("new_block", move |params: Params, _| {
-        // parse values to RawValue within a condition
-        if let Ok(params) = params.parse::<[Box<RawValue>; 1]>() {
-            let block = serde_json::from_str::<Block>(params[0].get()).unwrap();
-            rpc_server.new_block(block);
-        }
+    io.register_method("get_headers", |params: Params, rpc_server, _| {
+        let (locator, max) = params.parse::<(Vec<String>, usize)>()?;
+        Ok::<_, ErrorObjectOwned>(rpc_server.get_headers(locator, max))
     })?;
 
-    io.register_method("add_node", move |params: Params, _| {
-        // parse values to RawValue within a condition
-        if let Ok(params) = params.parse::<[Box<RawValue>; 1]>() {
-            let node = serde_json::from_str::<String>(params[0].get()).unwrap();
-            rpc_server.add_node(node);
-        }
+    io.register_method("get_blocks", |params: Params, rpc_server, _| {
+        let hashes = params.one::<Vec<String>>()?;
+        Ok::<_, ErrorObjectOwned>(rpc_server.get_blocks(hashes))
     })?;
 
-    io.register_method("get_transactions", move |_, _| {
+    // peers pushing blocks, transactions and addresses are scored by their IP address.
+    // validating what they push reads the local databases and waits for the chain lock, so it
+    // runs off the async runtime
+    io.register_blocking_method("new_block", |params: Params, rpc_server, extensions| {
+        let params: CallParams = CallParams::new(params, &extensions)?;
+        let block: Block = params.get(0)?;
+        rpc_server
+            .new_block(block, params.peer)
+            .map_err(ErrorObjectOwned::from)
+    })?;
+
+    // relaying a node calls other peers, so it runs off the async runtime
+    io.register_blocking_method("add_node", |params: Params, rpc_server, extensions| {
+        // the number of hops is optional, nodes without it aren't relayed. Peers can't make
+        // an address travel further than the nodes we announce ourselves.
+        let params: CallParams = CallParams::new(params, &extensions)?;
+        let address: String = params.get(0)?;
+        let hops: u32 = params
+            .get::<Option<u32>>(1)?
+            .unwrap_or(0)
+            .min(MAX_ADDR_HOPS);
+
+        rpc_server
+            .add_node(address, hops, params.peer)
+            .map_err(ErrorObjectOwned::from)
+    })?;
+
+    io.register_method("get_peers", |_, rpc_server, _| rpc_server.get_peers())?;
+
+    io.register_method("get_transactions", |_, rpc_server, _| {
         rpc_server.get_transactions()
     })?;
 
-    io.register_method("block_transactions", move |_, _params| {
-        // deserialize parameter to Transaction or Vec<Transactions>
+    io.register_method("get_mempool", |_, rpc_server, _| rpc_server.get_mempool())?;
+
+    io.register_method("get_merkle_proof", |params: Params, rpc_server, _| {
+        let txid = params.one::<String>()?;
+        Ok::<_, ErrorObjectOwned>(rpc_server.get_merkle_proof(txid))
+    })?;
+
+    io.register_blocking_method(
+        "new_untransaction",
+        |params: Params, rpc_server, extensions| {
+            let params: CallParams = CallParams::new(params, &extensions)?;
+            let txn: Transaction = params.get(0)?;
+            rpc_server
+                .new_untransaction(txn, params.peer)
+                .map_err(ErrorObjectOwned::from)
+        },
+    )?;
+
+    // requesting the announced items calls the sender, so it runs off the async runtime
+    io.register_blocking_method("inv", |params: Params, rpc_server, extensions| {
+        let params: CallParams = CallParams::new(params, &extensions)?;
+        let items: Vec<Inventory> = params.get(0)?;
+        let sender: String = params.get(1)?;
+        rpc_server
+            .inv(items, sender, params.peer)
+            .map_err(ErrorObjectOwned::from)
+    })?;
+
+    io.register_method("get_data", |params: Params, rpc_server, _| {
+        let items = params.one::<Vec<Inventory>>()?;
+        Ok::<_, ErrorObjectOwned>(rpc_server.get_data(items))
     })?;
 
-    // Create a server instance and bind
-    let server: Server = Server::builder()
-        .build(address.parse::<SocketAddr>()?)
-        .await?;
+    io.register_blocking_method(
+        "block_transaction",
+        |params: Params, rpc_server, extensions| {
+            let params: CallParams = CallParams::new(params, &extensions)?;
+            let txn: Transaction = params.get(0)?;
+            rpc_server
+                .block_transaction(txn, params.peer)
+                .map_err(ErrorObjectOwned::from)
+        },
+    )?;
 
     // start the server
-    println!("{:?}", &server.local_addr());
-    let handle: ServerHandle = server.start(io);
+    let (stop_handle, handle): (StopHandle, ServerHandle) = stop_channel();
+    tokio::spawn(accept_connections(listener, io.into(), stop_handle));
 
-    println!("Server running at 0.0.0.0:8332");
+    println!("Server running at {local_address}");
+    match &advertised {
+        Some(address) => println!("Announcing the node as {address}"),
+        None => println!("No address to announce the node at, pushing blocks and transactions"),
+    }
 
-    // handle the server shutdown gracefully
-    handle.stopped().await;
+    Ok((local_address, advertised, handle))
+}
 
-    Ok(())
+/// Serve the connections to `listener` until the server is stopped. Connections from banned
+/// peers are closed right away, and the IP address of every other peer is passed on to the
+/// methods it calls, which score the peer by it.
+async fn accept_connections(listener: TcpListener, methods: Methods, stop_handle: StopHandle) {
+    let service_builder = Server::builder().to_service_builder();
+
+    loop {
+        let (socket, remote) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(connection) => connection,
+                Err(_) => continue,
+            },
+            _ = stop_handle.clone().shutdown() => break,
+        };
+
+        if is_banned(remote.ip()) {
+            continue;
+        }
+
+        let service = service_builder
+            .clone()
+            .build(methods.clone(), stop_handle.clone());
+        let service = tower::service_fn(move |mut request: HttpRequest<_>| {
+            request.extensions_mut().insert(remote.ip());
+
+            let mut service = service.clone();
+            async move { service.call(request).await }
+        });
+
+        tokio::spawn(serve_with_graceful_shutdown(
+            socket,
+            service,
+            stop_handle.clone().shutdown(),
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ipv4_addresses_get_the_scheme_and_default_port() {
+        assert_eq!(
+            normalize_address("127.0.0.1:9000"),
+            Ok("http://127.0.0.1:9000".to_string())
+        );
+        assert_eq!(
+            normalize_address(" http://10.0.0.1:9000/ "),
+            Ok("http://10.0.0.1:9000".to_string())
+        );
+        assert_eq!(
+            normalize_address("10.0.0.1"),
+            Ok(format!("http://10.0.0.1:{DEFAULT_PORT}"))
+        );
+    }
+
+    #[test]
+    fn ipv6_addresses_are_bracketed() {
+        assert_eq!(
+            normalize_address("[::1]:9000"),
+            Ok("http://[::1]:9000".to_string())
+        );
+        assert_eq!(
+            normalize_address("[::1]"),
+            Ok(format!("http://[::1]:{DEFAULT_PORT}"))
+        );
+        assert_eq!(
+            normalize_address("2001:DB8:0:0::1"),
+            Ok(format!("http://[2001:db8::1]:{DEFAULT_PORT}"))
+        );
+        assert_eq!(
+            normalize_address("[::1"),
+            Err(AddressError::InvalidHost("[::1".to_string()))
+        );
+        assert_eq!(
+            normalize_address("[::1]9000"),
+            Err(AddressError::InvalidPort("9000".to_string()))
+        );
+    }
+
+    #[test]
+    fn hostnames_are_lowercased() {
+        assert_eq!(
+            normalize_address("Node-1.Example.com:9000"),
+            Ok("http://node-1.example.com:9000".to_string())
+        );
+        assert_eq!(
+            normalize_address("localhost"),
+            Ok(format!("http://localhost:{DEFAULT_PORT}"))
+        );
+        assert_eq!(
+            normalize_address("-node.example.com"),
+            Err(AddressError::InvalidHost("-node.example.com".to_string()))
+        );
+        assert_eq!(
+            normalize_address("node..example.com"),
+            Err(AddressError::InvalidHost("node..example.com".to_string()))
+        );
+        assert_eq!(
+            normalize_address("node_1:9000"),
+            Err(AddressError::InvalidHost("node_1".to_string()))
+        );
+    }
+
+    #[test]
+    fn unusable_addresses_are_rejected() {
+        assert_eq!(normalize_address(""), Err(AddressError::Empty));
+        assert_eq!(normalize_address("http://"), Err(AddressError::Empty));
+        assert_eq!(
+            normalize_address("https://127.0.0.1:9000"),
+            Err(AddressError::UnsupportedScheme("https".to_string()))
+        );
+        assert_eq!(
+            normalize_address("127.0.0.1:0"),
+            Err(AddressError::InvalidPort("0".to_string()))
+        );
+        assert_eq!(
+            normalize_address("127.0.0.1:65536"),
+            Err(AddressError::InvalidPort("65536".to_string()))
+        );
+        assert_eq!(
+            normalize_address("127.0.0.1:port"),
+            Err(AddressError::InvalidPort("port".to_string()))
+        );
+    }
 }
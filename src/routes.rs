@@ -1,5 +1,30 @@
-use crate::modules::node;
-use crate::modules::node::{get_nodes, start_node};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use jsonrpsee_server::ServerHandle;
+
+use crate::database::{AccountDB, BaseDB, BlockchainDB};
+use crate::modules::account::Account;
+use crate::modules::amount::Amount;
+use crate::modules::ban::{self, Ban};
+use crate::modules::blockchain::Block;
+use crate::modules::chain::next_height;
+use crate::modules::mempool::{mempool, minimum_fee, MIN_RELAY_FEE_RATE};
+use crate::modules::merkle::{merkle_proof, verify_proof, MerkleProof};
+use crate::modules::migration::migrate_databases;
+use crate::modules::miner::current_timestamp;
+use crate::modules::node::{get_peers, start_node, wait_for_shutdown};
+use crate::modules::script::Script;
+use crate::modules::transactions::{OutPoint, Transaction, Vin, Vout};
+use crate::modules::utxo::{UtxoEntry, UtxoSet};
+use crate::modules::{miner, node, subsidy};
+use crate::p2p::{node_ip, normalize_address, BroadCast, PeerResult, RPCClient};
+
+/// Node `tx transfer` submits transactions to, unless another one is given with `--node`
+const LOCAL_NODE: &str = "127.0.0.1";
 
 pub struct AccountRoute {}
 
@@ -13,39 +38,144 @@ pub struct TransactionRoute {}
 
 impl NodeRoute {
     #[allow(unused_variables)]
-    pub fn add(args: Vec<String>) {
+    pub async fn add(args: Vec<String>) {
         let node: String = args[3].clone();
 
         // add the node locally
-        node::write_node(node);
+        if let Err(error) = node::write_node(node.clone()) {
+            eprintln!("bitcoin-rs: Could not add node {node}: {error}");
+            return;
+        }
 
-        // broadcast to all local nodes
+        // broadcast to all local nodes, which spread it through the network
+        if let Ok(address) = normalize_address(&node) {
+            print_results(&BroadCast::add_node(address, node::MAX_ADDR_HOPS).await);
+        }
     }
 
-    pub fn list() {
+    pub async fn list() {
         println!("Querying for local nodes...");
 
-        // query all local nodes to vector
-        let nodes: Vec<String> = get_nodes();
+        // contact every known node at once to refresh what is known about it
+        for result in BroadCast::ping(vec![]).await {
+            node::update_peer(&result.node, result.result.ok().map(|_| result.elapsed));
+        }
 
         // iterate and write all local nodes to STDOUT
-        for node in nodes {
-            println!("{node}");
+        let now: u64 = current_timestamp();
+        for peer in get_peers() {
+            let last_seen: String = match peer.last_seen {
+                Some(time) => format!("last seen {}s ago", now.saturating_sub(time)),
+                None => "never seen".to_string(),
+            };
+            let latency: String = match peer.latency {
+                Some(latency) => format!("{latency}ms"),
+                None => "-".to_string(),
+            };
+
+            let banned: &str = if ban::is_node_banned(&peer.address) {
+                "  banned"
+            } else {
+                ""
+            };
+
+            println!(
+                "{}  {last_seen}  failures: {}  latency: {latency}{banned}",
+                peer.address, peer.failures
+            );
         }
     }
 
     #[allow(unused_variables)]
-    pub async fn start(args: Vec<String>) {
+    pub async fn start(mut args: Vec<String>) {
+        // peers are told to reach the node at the address given with `--advertise`
+        let Ok(advertise) = take_option(&mut args, "--advertise") else {
+            eprintln!("bitcoin-rs: --advertise requires an address.");
+            return;
+        };
+
+        // coinbase transactions are checked against the subsidy of the configured interval
+        if !take_halving_interval(&mut args) {
+            return;
+        }
+
+        // misbehaving peers are banned for the configured number of seconds
+        if !take_ban_time(&mut args) {
+            return;
+        }
+
         // collect address from arguments
         let addr: &str = &args[3];
 
+        if !migrate_local_databases() {
+            return;
+        }
+
         println!("Binding node at {addr}");
 
         // bind the node to the specified address and port
-        start_node(addr)
-            .await
-            // instead of `expect`, according to clippy
-            .unwrap_or_else(|_| panic!("Could not bind to {addr}"));
+        let handle: ServerHandle = match start_node(addr, advertise.as_deref()).await {
+            Ok((_, _, handle)) => handle,
+            Err(error) => {
+                eprintln!("bitcoin-rs: Could not bind to {addr}: {error}");
+                return;
+            }
+        };
+
+        // serve until the server shuts down
+        wait_for_shutdown(handle).await;
+    }
+
+    pub fn bans() {
+        // write all banned nodes to STDOUT
+        let now: u64 = current_timestamp();
+        for ban in ban::get_bans() {
+            println!(
+                "{}  {}s left  reason: {}",
+                ban.ip,
+                ban.until.saturating_sub(now),
+                ban.reason
+            );
+        }
+    }
+
+    pub fn ban(args: Vec<String>) {
+        // collect address and optional duration from arguments
+        let Some(node) = args.get(3) else {
+            eprintln!("bitcoin-rs: Node ban requires an address.");
+            return;
+        };
+        let seconds: u64 = match args.get(4).map(|seconds| seconds.parse::<u64>()) {
+            Some(Ok(seconds)) => seconds,
+            Some(Err(_)) => {
+                eprintln!("bitcoin-rs: \"{}\" is not a number of seconds.", args[4]);
+                return;
+            }
+            None => ban::DEFAULT_BAN_TIME,
+        };
+
+        // bans apply to the IP address of the node
+        match node_ip(node) {
+            Ok(ip) => {
+                let Ban { ip, until, .. } = ban::ban(ip, seconds, "manual");
+                println!("Banned {ip} until {until}");
+            }
+            Err(error) => eprintln!("bitcoin-rs: Could not ban node {node}: {error}"),
+        }
+    }
+
+    pub fn unban(args: Vec<String>) {
+        // collect address from arguments
+        let Some(node) = args.get(3) else {
+            eprintln!("bitcoin-rs: Node unban requires an address.");
+            return;
+        };
+
+        match node_ip(node).map(ban::unban) {
+            Ok(true) => println!("Unbanned {node}"),
+            Ok(false) => println!("Node {node} is not banned"),
+            Err(error) => eprintln!("bitcoin-rs: Could not unban node {node}: {error}"),
+        }
     }
 }
 
@@ -53,20 +183,39 @@ impl AccountRoute {
     #[allow(unused_variables)]
     pub fn create(args: Vec<String>) {
         // generate public, private keys and address
+        let account: Account = Account::generate();
+
+        // store the account locally, making it the current account
+        AccountDB::new()
+            .insert(account.clone())
+            .expect("Couldn't write to Account database");
 
         // display public private keys and address
+        println!("Private key: {}", account.privkey);
+        println!("Public key: {}", account.pubkey);
+        println!("Address: {}", account.address);
     }
 
     pub fn get() {
         // get all accounts from local database
+        let accounts: Vec<Account> = AccountDB::new().find_all();
 
         // display all accounts
+        for account in accounts {
+            println!("{}", account.address);
+        }
     }
 
     pub fn current() {
         // get current account from local database
-
-        // display current account
+        match AccountDB::new().find_one() {
+            // display current account
+            Some(account) => {
+                println!("Public key: {}", account.pubkey);
+                println!("Address: {}", account.address);
+            }
+            None => eprintln!("Account: No account found. Create one with `account create`."),
+        }
     }
 }
 
@@ -74,6 +223,39 @@ impl BlockchainRoute {
     pub fn list() {
         // use a loop to list all blocks in the local database
     }
+
+    pub fn proof(args: Vec<String>) {
+        // collect transaction hash from arguments
+        let Some(txid) = args.get(3) else {
+            eprintln!("bitcoin-rs: Blockchain proof requires a transaction hash.");
+            return;
+        };
+
+        // locate the block that includes the transaction
+        let Some(block) = BlockchainDB::new().find_by_transaction(txid) else {
+            eprintln!("Blockchain: Transaction {txid} is not in any block.");
+            return;
+        };
+
+        // build the inclusion proof and check it against the block's merkle root
+        let proof: MerkleProof = merkle_proof(&block.tx, txid).expect("Transaction is in block");
+        let valid: bool = verify_proof(&proof, &block.merkle_root);
+
+        print_proof(&block, &proof, valid);
+    }
+}
+
+// display a merkle proof and the block header it leads to
+fn print_proof(block: &Block, proof: &MerkleProof, valid: bool) {
+    println!("Block: {} (index {})", block.hash, block.index);
+    println!("Merkle root: {}", block.merkle_root);
+    println!("Transaction: {} (position {})", proof.txid, proof.index);
+
+    for sibling in &proof.siblings {
+        println!("  {sibling}");
+    }
+
+    println!("Valid: {valid}");
 }
 
 impl TransactionRoute {
@@ -81,23 +263,335 @@ impl TransactionRoute {
         // list all transactions from the local database
     }
 
-    #[allow(unused_variables)]
-    pub fn transfer(args: Vec<String>) {
+    pub fn transfer(mut args: Vec<String>) {
+        // the transaction is submitted to the node at the address given with `--node`
+        let node: String = match take_option(&mut args, "--node") {
+            Ok(node) => node.unwrap_or_else(|| LOCAL_NODE.to_string()),
+            Err(()) => {
+                eprintln!("bitcoin-rs: --node requires an address.");
+                return;
+            }
+        };
+        let client: RPCClient = match RPCClient::new(node.clone()) {
+            Ok(client) => client,
+            Err(error) => {
+                eprintln!("Transactions: Invalid node address {node}: {error}");
+                return;
+            }
+        };
+
+        // collect receiver and amount from arguments
+        let (Some(receiver), Some(amount)) = (args.get(3), args.get(4)) else {
+            eprintln!("bitcoin-rs: Transfer requires a receiver and an amount.");
+            return;
+        };
+        let Some(script_pubkey) = Script::for_address(receiver) else {
+            eprintln!("Transactions: \"{receiver}\" is not a valid address.");
+            return;
+        };
+        let amount: Amount = match amount.parse() {
+            Ok(amount) => amount,
+            Err(error) => {
+                eprintln!("Transactions: Invalid amount: {error}.");
+                return;
+            }
+        };
+
+        // the fee rate in satoshis per 1000 bytes defaults to the minimum relay fee rate
+        let fee_rate: u64 = match args.get(5).map(|rate| rate.parse::<u64>()) {
+            Some(Ok(rate)) => rate,
+            Some(Err(_)) => {
+                eprintln!("Transactions: \"{}\" is not a valid fee rate.", args[5]);
+                return;
+            }
+            None => MIN_RELAY_FEE_RATE,
+        };
+
+        // transfers are sent from the current account
+        let Some(account) = AccountDB::new().find_one() else {
+            eprintln!("Transactions: No account found. Create one with `account create`.");
+            return;
+        };
+
+        // outputs the node's pending transactions spend can't be spent again
+        let spent: HashSet<OutPoint> = match client.get_mempool() {
+            Ok(pending) => pending
+                .iter()
+                .flat_map(|txn| txn.vin.iter().map(Vin::outpoint))
+                .collect(),
+            Err(error) => {
+                eprintln!("Transactions: Could not reach the node at {node}: {error}");
+                return;
+            }
+        };
+
         // perform a transfer from Node A to Node B
+        let Some(txn) = build_transfer(&account, &script_pubkey, amount, fee_rate, &spent) else {
+            eprintln!("Transactions: Insufficient funds in {}.", account.address);
+            return;
+        };
 
-        // display the unblock spread
+        // the node validates the transaction, keeps it in its mempool and relays it
+        if let Err(error) = client.new_untransaction(txn.clone()) {
+            eprintln!(
+                "Transactions: {node} rejected transaction {}: {error}",
+                txn.hash
+            );
+            return;
+        }
+        println!("Sent to {node}");
 
         // display the transaction details
+        println!("Transaction: {}", txn.hash);
+        for vout in &txn.vout {
+            let receiver: String = vout
+                .script_pubkey
+                .address()
+                .unwrap_or_else(|| vout.script_pubkey.to_string());
+            println!("  {} -> {receiver}", vout.amount);
+        }
+        println!("Fee: {} sat ({} bytes)", txn.fee().to_sat(), txn.size());
+    }
+}
+
+/// Migrate the local databases written by earlier versions, so starting a node doesn't
+/// overwrite them. Fails if a database can't be migrated.
+fn migrate_local_databases() -> bool {
+    match migrate_databases() {
+        Ok(migrated) => {
+            for path in migrated {
+                println!("Migrated {path} from an earlier version");
+            }
+            true
+        }
+        Err(error) => {
+            eprintln!("bitcoin-rs: Couldn't migrate the local databases: {error}");
+            false
+        }
+    }
+}
+
+/// Remove the option `name` and the value following it from `args`, returning the value.
+/// Fails if the option is given without a value.
+fn take_option(args: &mut Vec<String>, name: &str) -> Result<Option<String>, ()> {
+    let Some(position) = args.iter().position(|arg| arg == name) else {
+        return Ok(None);
+    };
+
+    if position + 1 >= args.len() {
+        return Err(());
     }
+
+    let value: String = args.remove(position + 1);
+    args.remove(position);
+    Ok(Some(value))
+}
+
+/// Set the number of blocks after which the subsidy halves from the `--halving-interval`
+/// option. Fails if the option isn't a positive number of blocks.
+fn take_halving_interval(args: &mut Vec<String>) -> bool {
+    let blocks: String = match take_option(args, "--halving-interval") {
+        Ok(Some(blocks)) => blocks,
+        Ok(None) => return true,
+        Err(()) => {
+            eprintln!("bitcoin-rs: --halving-interval requires a number of blocks.");
+            return false;
+        }
+    };
+
+    match blocks.parse::<u32>() {
+        Ok(blocks) if blocks > 0 => {
+            subsidy::set_halving_interval(blocks);
+            true
+        }
+        _ => {
+            eprintln!("bitcoin-rs: \"{blocks}\" is not a positive number of blocks.");
+            false
+        }
+    }
+}
+
+/// Set the time misbehaving peers are banned for from the `--ban-time` option. Fails if the
+/// option isn't a number of seconds.
+fn take_ban_time(args: &mut Vec<String>) -> bool {
+    let seconds: String = match take_option(args, "--ban-time") {
+        Ok(Some(seconds)) => seconds,
+        Ok(None) => return true,
+        Err(()) => {
+            eprintln!("bitcoin-rs: --ban-time requires a number of seconds.");
+            return false;
+        }
+    };
+
+    match seconds.parse::<u64>() {
+        Ok(seconds) => {
+            ban::set_ban_time(seconds);
+            true
+        }
+        Err(_) => {
+            eprintln!("bitcoin-rs: \"{seconds}\" is not a number of seconds.");
+            false
+        }
+    }
+}
+
+// report the outcome of a broadcast for every peer
+fn print_results<T>(results: &[PeerResult<T>]) {
+    for peer in results {
+        match &peer.result {
+            Ok(_) => println!("Sent to {} in {}ms", peer.node, peer.elapsed.as_millis()),
+            Err(error) => println!("Could not send to {}: {error}", peer.node),
+        }
+    }
+}
+
+// build and sign a transaction paying `amount` from `account` to `script_pubkey` with a fee of
+// `fee_rate` satoshis per 1000 bytes, returning `None` if the account can't cover the amount and fee.
+// Outputs in `spent` are left alone.
+fn build_transfer(
+    account: &Account,
+    script_pubkey: &Script,
+    amount: Amount,
+    fee_rate: u64,
+    spent: &HashSet<OutPoint>,
+) -> Option<Transaction> {
+    // the fee depends on the size, which depends on the inputs needed to cover the fee
+    let mut fee: Amount = Amount::ZERO;
+    loop {
+        let txn: Transaction = build_transfer_with_fee(account, script_pubkey, amount, fee, spent)?;
+        let required: Amount = minimum_fee(txn.size(), fee_rate);
+
+        if txn.fee() >= required {
+            return Some(txn);
+        }
+        fee = required;
+    }
+}
+
+// build and sign a transaction paying `amount` from `account` to `script_pubkey` and `fee` to
+// the miner, returning `None` if the account can't cover both without spending outputs in `spent`
+fn build_transfer_with_fee(
+    account: &Account,
+    script_pubkey: &Script,
+    amount: Amount,
+    fee: Amount,
+    spent: &HashSet<OutPoint>,
+) -> Option<Transaction> {
+    let target: Amount = amount.checked_add(fee)?;
+
+    let utxos: UtxoSet = UtxoSet::load();
+    let height: u32 = next_height();
+
+    // the same outputs are picked every time the transfer is rebuilt
+    let mut entries: Vec<&UtxoEntry> = utxos.find_by_address(&account.address);
+    entries.sort_by_key(|entry| (&entry.outpoint.txid, entry.outpoint.vout));
+
+    // collect unspent outputs of the account until they cover the amount and fee, skipping
+    // outputs that pending transactions already spend and coinbase outputs that can't be spent yet
+    let mut vin: Vec<Vin> = Vec::new();
+    let mut total: Amount = Amount::ZERO;
+    for entry in entries {
+        if total >= target {
+            break;
+        }
+        if spent.contains(&entry.outpoint) || !entry.is_mature(height) {
+            continue;
+        }
+
+        vin.push(Vin::new(
+            entry.outpoint.txid.clone(),
+            entry.outpoint.vout,
+            entry.output.amount,
+        ));
+        total = total.checked_add(entry.output.amount)?;
+    }
+
+    let change: Amount = total.checked_sub(target)?;
+
+    // pay the receiver and return the change to the account, leaving the fee to the miner
+    let mut vout: Vec<Vout> = vec![Vout::new(script_pubkey.clone(), amount)];
+    if change > Amount::ZERO {
+        vout.push(Vout::new(Script::for_address(&account.address)?, change));
+    }
+
+    let mut txn: Transaction = Transaction::new(current_timestamp(), vin, vout);
+    txn.sign(account).expect("Couldn't sign transaction");
+
+    Some(txn)
 }
 
 impl MinerRoute {
-    #[allow(unused_variables)]
-    pub fn start(args: Vec<String>) {
+    pub async fn start(mut args: Vec<String>) {
+        // peers are told to reach the node at the address given with `--advertise`
+        let Ok(advertise) = take_option(&mut args, "--advertise") else {
+            eprintln!("bitcoin-rs: --advertise requires an address.");
+            return;
+        };
+
+        // coinbase transactions are checked against the subsidy of the configured interval
+        if !take_halving_interval(&mut args) {
+            return;
+        }
+
+        // misbehaving peers are banned for the configured number of seconds
+        if !take_ban_time(&mut args) {
+            return;
+        }
+
         // check if there is a current account
         // throw error "to create account" if no account exists
+        let Some(account) = AccountDB::new().find_one() else {
+            eprintln!("Miner: No account found. Create one with `account create`.");
+            return;
+        };
+
+        // blocks pay the P2PKH address of the account
+        let Some(script_pubkey) = Script::for_address(&account.address) else {
+            eprintln!("Miner: \"{}\" is not a valid address.", account.address);
+            return;
+        };
+
+        // collect address from arguments
+        let Some(addr) = args.get(3) else {
+            eprintln!("bitcoin-rs: Miner requires an address to start a node.");
+            return;
+        };
+
+        if !migrate_local_databases() {
+            return;
+        }
+
+        println!("Binding node at {addr}");
+
+        // start a node with the current account, catching up with the network before mining
+        let (advertised, handle) = match start_node(addr, advertise.as_deref()).await {
+            Ok((_, advertised, handle)) => (advertised, handle),
+            Err(error) => {
+                eprintln!("bitcoin-rs: Could not bind to {addr}: {error}");
+                return;
+            }
+        };
+
+        // mine blocks paying the current account until the node shuts down
+        // (mining is CPU bound, so it runs off the async runtime)
+        let stopping: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+        let stop: Arc<AtomicBool> = stopping.clone();
+        let mining = tokio::task::spawn_blocking(move || {
+            while !stop.load(Ordering::Relaxed) {
+                match miner::mine(&script_pubkey, advertised.as_deref()) {
+                    Some(block) => println!("Mined block {} with hash {}", block.index, block.hash),
+                    // the chain moved on, give it a moment before trying again
+                    None => thread::sleep(Duration::from_secs(1)),
+                }
+            }
+        });
+
+        // serve until the server shuts down
+        wait_for_shutdown(handle).await;
 
-        // start a node with the current account
-        // in an infinite loop, mine transactions
+        // let the block being mined finish, then persist the mempool without its transactions
+        stopping.store(true, Ordering::Relaxed);
+        let _ = mining.await;
+        mempool().save();
     }
 }